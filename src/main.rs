@@ -1,9 +1,12 @@
 //! Safe-sync server
 
 extern crate wavesexchange_log as log;
-extern crate wavesexchange_warp as wx_warp;
 
-use std::sync::Arc;
+use std::{
+    io::Write,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use tokio::{
     signal::unix::{signal, SignalKind},
@@ -15,23 +18,94 @@ mod server;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    env_logger::init();
+    let started_at = SystemTime::now();
 
     // Load configs
     let config = server::config::load()?;
 
+    init_logger(config.log_format);
+
     // Create the web server
-    use server::builder::ServerBuilder;
+    use server::{builder::ServerBuilder, TlsFiles};
     let server = ServerBuilder::new()
+        .service_config(Some(config.clone()))
+        .started_at(started_at)
+        .bind_addr(config.bind_addr)
+        .dual_stack(config.dual_stack)
+        .listen_backlog(config.listen_backlog)
         .port(config.port)
         .metrics_port(config.metrics_port)
+        .tls(
+            config
+                .tls_cert_path
+                .clone()
+                .zip(config.tls_key_path.clone())
+                .map(|(cert_path, key_path)| TlsFiles { cert_path, key_path }),
+        )
+        .admin_token(config.admin_token)
+        .admin_port(config.admin_port)
+        .admin_show_pending_contents(config.admin_show_pending_contents)
+        .flush_before_kill(config.flush_before_kill)
+        .max_prepairing_messages(config.max_prepairing_messages)
+        .max_prepairing_bytes(config.max_prepairing_bytes)
+        .max_connections(config.max_connections)
+        .max_handshake_connections(config.max_handshake_connections)
+        .max_connections_per_ip(config.max_connections_per_ip)
+        .trust_forwarded_for(config.trust_forwarded_for)
+        .subprotocol_policy(config.subprotocol_policy)
+        .max_open_mailboxes(config.max_open_mailboxes)
+        .max_mailbox_capacity(config.max_mailbox_capacity)
+        .denied_mailbox_ids(config.denied_mailbox_ids)
+        .metrics_tenants(config.metrics_tenants)
+        .close_policy(config.close_policy)
+        .closing_message_policy(config.closing_message_policy)
+        .peer_ip_policy(config.peer_ip_policy)
+        .require_connect_token(config.require_connect_token)
+        .mailbox_id_format(config.mailbox_id_format)
+        .id_prefix(config.id_prefix.clone())
+        .id_pool_size(config.id_pool_size)
+        .strict_protocol(config.strict_protocol)
+        .relay_ping_frames(config.relay_ping_frames)
+        .max_creates_per_sec(config.max_creates_per_sec)
+        .namespace_limits(config.namespace_limits.clone())
+        .echo_server_time(config.echo_server_time)
+        .time_sync_interval((config.time_sync_interval_sec > 0).then(|| Duration::from_secs(config.time_sync_interval_sec)))
+        .drain_timeout(Duration::from_secs(config.drain_timeout_sec))
+        .shutdown_notice_batch_size(config.shutdown_notice_batch_size)
+        .shutdown_notice_pause(Duration::from_millis(config.shutdown_notice_pause_ms))
+        .shutdown_reconnect_after(Duration::from_secs(config.shutdown_reconnect_after_sec))
+        .shutdown_kill_batch_size(config.shutdown_kill_batch_size)
+        .shutdown_kill_pause(Duration::from_millis(config.shutdown_kill_pause_ms))
+        .close_timeout(Duration::from_secs(config.close_timeout_sec))
+        .client_idle_timeout((config.client_idle_timeout_sec > 0).then(|| Duration::from_secs(config.client_idle_timeout_sec)))
+        .client_pong_timeout(Duration::from_secs(config.client_pong_timeout_sec))
+        .max_outstanding_pings(config.max_outstanding_pings)
+        .reservation_timeout(Duration::from_secs(config.reservation_timeout_sec))
+        .restored_mailbox_ttl(Duration::from_secs(config.restored_mailbox_ttl_sec))
+        .mailbox_timeout((config.mailbox_timeout_sec > 0).then(|| Duration::from_secs(config.mailbox_timeout_sec)))
+        .unpaired_timeout((config.unpaired_timeout_sec > 0).then(|| Duration::from_secs(config.unpaired_timeout_sec)))
+        .reconnect_grace((config.reconnect_grace_sec > 0).then(|| Duration::from_secs(config.reconnect_grace_sec)))
+        .max_pending_bytes(config.max_pending_bytes)
+        .peer_slow_threshold(config.peer_slow_threshold)
+        .nack_undelivered(config.nack_undelivered)
+        .max_outbound_bytes(config.max_outbound_bytes)
+        .outbound_buffer(config.outbound_buffer)
+        .max_text_bytes(config.max_text_bytes)
+        .max_binary_bytes(config.max_binary_bytes)
+        .outbound_overflow(config.outbound_overflow)
         .build()
         .new_server();
     let server = Arc::new(server);
 
+    // Restore messages saved on previous shutdown
+    let pending_messages_file = config.pending_messages_file;
+    if let Some(path) = &pending_messages_file {
+        server.load_pending_messages(path)?;
+    }
+
     // Run the web server
     let (shutdown_signal_tx, mut shutdown_signal_rx) = mpsc::channel(1);
-    let (server_task, server_stop_tx) = server.clone().start(shutdown_signal_tx);
+    let (server_task, server_stop_tx) = server.clone().start(shutdown_signal_tx)?;
     let server_handle = tokio::spawn(server_task);
 
     // Graceful shutdown handling
@@ -39,6 +113,13 @@ async fn main() -> Result<(), anyhow::Error> {
     let mut shutdown_start_tx = Some(shutdown_start_tx);
     let mut graceful_shutdown_handle = tokio::spawn(async move {
         if shutdown_start_rx.await.is_ok() {
+            server.begin_shutdown();
+            if let Some(path) = &pending_messages_file {
+                if let Err(err) = server.save_pending_messages(path) {
+                    log::error!("Failed to save pending messages: {}", err);
+                }
+            }
+            server.broadcast_shutdown_notice().await;
             log::debug!("Graceful shutdown started: disconnecting all clients");
             server.disconnect_all_clients().await;
         }
@@ -83,3 +164,53 @@ async fn main() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+/// Initialize the logger used by dependencies (the service itself logs via `wavesexchange_log`)
+fn init_logger(format: server::config::LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == server::config::LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = json_log_line(
+                &buf.timestamp().to_string(),
+                record.level().as_str(),
+                record.target(),
+                &record.args().to_string(),
+            );
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+/// Log line in the JSON format, a single line whatever the message
+fn json_log_line(ts: &str, level: &str, target: &str, msg: &str) -> String {
+    let line = serde_json::json!({
+        "ts": ts,
+        "level": level,
+        "target": target,
+        "msg": msg,
+    });
+    line.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_log_lines_are_valid_json() {
+        let msg = "Connection \"closed\"\n\tby {peer}";
+        let line = json_log_line("2024-01-01T00:00:00Z", "INFO", "hyper::server", msg);
+        assert!(!line.contains('\n'));
+        let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            parsed,
+            serde_json::json!({
+                "ts": "2024-01-01T00:00:00Z",
+                "level": "INFO",
+                "target": "hyper::server",
+                "msg": msg,
+            })
+        );
+    }
+}
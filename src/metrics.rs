@@ -1,11 +1,191 @@
 use lazy_static::lazy_static;
-use prometheus::{Counter, IntGauge};
+use prometheus::{
+    core::{Collector, Desc},
+    exponential_buckets,
+    proto::MetricFamily,
+    Counter, CounterVec, Gauge, Histogram, HistogramOpts, HistogramVec, IntGauge, IntGaugeVec, Opts,
+};
 
 lazy_static! {
     pub static ref ACTIVE_CLIENTS: IntGauge =
         IntGauge::new("Active_Clients_Count", "Number of connected clients").expect("can't create Active_Clients_Count metric");
+    pub static ref CLIENTS_MAP_SIZE: IntGauge = IntGauge::new(
+        "Clients_Map_Size",
+        "Number of clients tracked by the server, should match Active_Clients_Count"
+    )
+    .expect("can't create Clients_Map_Size metric");
     pub static ref CLIENT_CONNECT: Counter =
         Counter::new("Client_Connected", "Client connect events").expect("can't create Client_Connected metric");
     pub static ref CLIENT_DISCONNECT: Counter =
         Counter::new("Client_Disconnected", "Client disconnect events").expect("can't create Client_Disconnected metric");
+    pub static ref CLIENT_HANDSHAKE_ABORTED: Counter =
+        Counter::new("Client_Handshake_Aborted", "Connections closed by client before handshake")
+            .expect("can't create Client_Handshake_Aborted metric");
+    pub static ref CLIENT_PROTOCOL_ERRORS: Counter =
+        Counter::new("Client_Protocol_Errors", "Connections closed because of a protocol violation")
+            .expect("can't create Client_Protocol_Errors metric");
+    pub static ref CLIENT_IDLE_TIMEOUTS: Counter = Counter::new(
+        "Client_Idle_Timeouts",
+        "Connections closed because the client did not answer a ping after being idle"
+    )
+    .expect("can't create Client_Idle_Timeouts metric");
+    pub static ref SLOW_CONSUMER_DISCONNECTS: Counter = Counter::new(
+        "Slow_Consumer_Disconnects",
+        "Connections closed because the client was too slow to receive the messages relayed to it"
+    )
+    .expect("can't create Slow_Consumer_Disconnects metric");
+    pub static ref ACTIVE_MAILBOXES: IntGauge =
+        IntGauge::new("Active_Mailboxes_Count", "Number of open mailboxes").expect("can't create Active_Mailboxes_Count metric");
+    pub static ref MAILBOX_CREATED: Counter =
+        Counter::new("Mailbox_Created", "Mailbox create events").expect("can't create Mailbox_Created metric");
+    pub static ref MAILBOX_DESTROYED: Counter =
+        Counter::new("Mailbox_Destroyed", "Mailbox destroy events").expect("can't create Mailbox_Destroyed metric");
+    pub static ref MESSAGES_DROPPED_CLOSING: Counter = Counter::new(
+        "Messages_Dropped_Closing",
+        "Messages dropped because they were sent to a mailbox which is being closed"
+    )
+    .expect("can't create Messages_Dropped_Closing metric");
+    pub static ref MESSAGES_RELAYED: Counter = Counter::new(
+        "Messages_Relayed",
+        "Messages delivered right away to a connected peer (a message to a group mailbox counts once per receiver)"
+    )
+    .expect("can't create Messages_Relayed metric");
+    pub static ref BYTES_RELAYED: Counter =
+        Counter::new("Bytes_Relayed", "Total size of the messages counted in Messages_Relayed").expect("can't create Bytes_Relayed metric");
+    pub static ref MESSAGES_ENQUEUED: Counter = Counter::new(
+        "Messages_Enqueued",
+        "Messages enqueued in a mailbox because the peer is not connected yet (or messages are held in it)"
+    )
+    .expect("can't create Messages_Enqueued metric");
+    pub static ref BYTES_ENQUEUED: Counter = Counter::new("Bytes_Enqueued", "Total size of the messages counted in Messages_Enqueued")
+        .expect("can't create Bytes_Enqueued metric");
+    pub static ref AVG_PEERS_PER_MAILBOX: Gauge = Gauge::new("Avg_Peers_Per_Mailbox", "Average number of peers connected to a mailbox")
+        .expect("can't create Avg_Peers_Per_Mailbox metric");
+    pub static ref RELAY_LATENCY_SECONDS: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "Relay_Latency_Seconds",
+            "Time from receiving a message to handing it over to the receiving client"
+        )
+        .buckets(exponential_buckets(0.00001, 4.0, 10).expect("relay latency buckets"))
+    )
+    .expect("can't create Relay_Latency_Seconds metric");
+    pub static ref CONNECTION_DURATION_SECONDS: Histogram = Histogram::with_opts(
+        HistogramOpts::new("Connection_Duration_Seconds", "Time from websocket connection to disconnection").buckets(vec![
+            1.0,
+            5.0,
+            15.0,
+            60.0,
+            300.0,
+            900.0,
+            1800.0,
+            3600.0,
+            3.0 * 3600.0,
+            6.0 * 3600.0,
+            24.0 * 3600.0
+        ])
+    )
+    .expect("can't create Connection_Duration_Seconds metric");
+    pub static ref TENANT_ACTIVE_CLIENTS: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("Tenant_Active_Clients", "Number of clients connected to mailboxes, by tenant"),
+        &["tenant"]
+    )
+    .expect("can't create Tenant_Active_Clients metric");
+    pub static ref TENANT_CLIENT_CONNECT: CounterVec = CounterVec::new(
+        Opts::new("Tenant_Client_Connected", "Client create/connect to mailbox events, by tenant"),
+        &["tenant"]
+    )
+    .expect("can't create Tenant_Client_Connected metric");
+    pub static ref HTTP_REQUESTS: CounterVec = CounterVec::new(
+        Opts::new(
+            "Http_Requests",
+            "HTTP requests to the main port (websocket upgrades included), by response status"
+        ),
+        &["status"]
+    )
+    .expect("can't create Http_Requests metric");
+    pub static ref HTTP_RESPONSE_DURATION_SECONDS: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "Http_Response_Duration_Seconds",
+            "Time from receiving an HTTP request to the main port to sending the response, by response status"
+        )
+        .buckets(exponential_buckets(0.0001, 4.0, 10).expect("response duration buckets")),
+        &["status"]
+    )
+    .expect("can't create Http_Response_Duration_Seconds metric");
+    pub static ref SHUTTING_DOWN: IntGauge = IntGauge::new("Shutting_Down", "1 while the server is shutting down gracefully, 0 otherwise")
+        .expect("can't create Shutting_Down metric");
+}
+
+/// All metrics of the service. Every metric defined above must be listed here to be exported.
+pub fn all() -> Vec<&'static dyn Collector> {
+    vec![
+        &*ACTIVE_CLIENTS,
+        &*CLIENTS_MAP_SIZE,
+        &*CLIENT_CONNECT,
+        &*CLIENT_DISCONNECT,
+        &*CLIENT_HANDSHAKE_ABORTED,
+        &*CLIENT_PROTOCOL_ERRORS,
+        &*CLIENT_IDLE_TIMEOUTS,
+        &*SLOW_CONSUMER_DISCONNECTS,
+        &*ACTIVE_MAILBOXES,
+        &*MAILBOX_CREATED,
+        &*MAILBOX_DESTROYED,
+        &*MESSAGES_DROPPED_CLOSING,
+        &*MESSAGES_RELAYED,
+        &*BYTES_RELAYED,
+        &*MESSAGES_ENQUEUED,
+        &*BYTES_ENQUEUED,
+        &*AVG_PEERS_PER_MAILBOX,
+        &*RELAY_LATENCY_SECONDS,
+        &*CONNECTION_DURATION_SECONDS,
+        &*TENANT_ACTIVE_CLIENTS,
+        &*TENANT_CLIENT_CONNECT,
+        &*HTTP_REQUESTS,
+        &*HTTP_RESPONSE_DURATION_SECONDS,
+        &*SHUTTING_DOWN,
+    ]
+}
+
+/// Collector exporting all metrics of the service at once, so that they are registered in a single call
+#[derive(Clone, Copy)]
+pub struct AllMetrics;
+
+impl Collector for AllMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        all().into_iter().flat_map(|metric| metric.desc()).collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        all().into_iter().flat_map(|metric| metric.collect()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::Registry;
+
+    #[test]
+    fn every_metric_is_exported() {
+        let registry = Registry::new();
+        registry.register(Box::new(AllMetrics)).expect("register all metrics");
+        let exported = AllMetrics.desc().into_iter().map(|desc| desc.fq_name.clone()).collect::<Vec<_>>();
+
+        // every metric defined in this module names itself in its creation error message
+        let source = include_str!("metrics.rs");
+        let defined = source
+            .lines()
+            .filter(|line| line.trim_start().starts_with("pub static ref "))
+            .count();
+        let names = source
+            .split("can't create ")
+            .skip(1)
+            .filter_map(|rest| rest.split(" metric\")").next())
+            .filter(|name| !name.contains(char::is_whitespace))
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), defined);
+        for name in names {
+            assert!(exported.iter().any(|exported| exported == name), "{} is not exported", name);
+        }
+    }
 }
@@ -0,0 +1,397 @@
+//! Safe-sync admin HTTP API, served on its own port.
+//!
+//! All admin routes require the `Authorization: Bearer <token>` header with the configured admin token.
+//! If no admin token is configured, admin routes are disabled.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use warp::{http::StatusCode, reject::Reject, Filter, Rejection, Reply};
+
+use super::{
+    config::ServiceConfig,
+    websocket::{
+        client::{ClientId, Clients, CloseReason},
+        mailbox::MailboxManager,
+    },
+};
+
+/// Admin routes
+pub(super) fn routes(
+    admin_token: Option<String>,
+    service_config: Option<Arc<ServiceConfig>>,
+    started_at: SystemTime,
+    show_pending_contents: bool,
+    mailbox_manager: MailboxManager,
+    clients: Clients,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let with_mailbox_manager = warp::any().map(move || mailbox_manager.clone());
+    let with_clients = warp::any().map(move || clients.clone());
+
+    let with_auth = with_admin_auth(admin_token);
+
+    let get_config = warp::path!("admin" / "config")
+        .and(warp::get())
+        .and(with_auth.clone())
+        .map(move || match &service_config {
+            Some(config) => warp::reply::json(&**config).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        });
+
+    let get_status = warp::path!("admin" / "status")
+        .and(warp::get())
+        .and(with_auth.clone())
+        .map(move || status(started_at));
+
+    let evict_mailboxes = warp::path!("admin" / "mailboxes" / "evict")
+        .and(warp::post())
+        .and(with_auth.clone())
+        .and(warp::query::<EvictQuery>())
+        .and(with_mailbox_manager.clone())
+        .and(with_clients.clone())
+        .map(evict_mailboxes);
+
+    let list_mailboxes = warp::path!("admin" / "mailboxes")
+        .and(warp::get())
+        .and(with_auth.clone())
+        .and(with_mailbox_manager.clone())
+        .map(|mailbox_manager: MailboxManager| warp::reply::json(&mailbox_manager.snapshot()));
+
+    let get_mailbox = warp::path!("admin" / "mailboxes" / u32)
+        .and(warp::get())
+        .and(with_auth.clone())
+        .and(with_mailbox_manager.clone())
+        .map(|id, mailbox_manager: MailboxManager| match mailbox_manager.details(id) {
+            Some(details) => warp::reply::json(&details).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        });
+
+    let get_pending_messages = warp::path!("admin" / "mailboxes" / u32 / "pending")
+        .and(warp::get())
+        .and(with_auth.clone())
+        .and(with_mailbox_manager.clone())
+        .map(
+            move |id, mailbox_manager: MailboxManager| match mailbox_manager.pending_messages_snapshot(id, show_pending_contents) {
+                Some(peers) => warp::reply::json(&peers).into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            },
+        );
+
+    let disconnect_client = warp::path!("admin" / "clients" / u64 / "disconnect")
+        .and(warp::post())
+        .and(with_auth.clone())
+        .and(with_clients)
+        .map(disconnect_client);
+
+    let list_denied_mailboxes = warp::path!("admin" / "denied-mailboxes")
+        .and(warp::get())
+        .and(with_auth.clone())
+        .and(with_mailbox_manager.clone())
+        .map(|mailbox_manager: MailboxManager| warp::reply::json(&mailbox_manager.denied_ids()));
+
+    let deny_mailbox = warp::path!("admin" / "denied-mailboxes" / u32)
+        .and(warp::put())
+        .and(with_auth.clone())
+        .and(with_mailbox_manager.clone())
+        .map(|id, mailbox_manager: MailboxManager| {
+            mailbox_manager.deny_id(id);
+            StatusCode::OK
+        });
+
+    let allow_mailbox = warp::path!("admin" / "denied-mailboxes" / u32)
+        .and(warp::delete())
+        .and(with_auth)
+        .and(with_mailbox_manager)
+        .map(|id, mailbox_manager: MailboxManager| {
+            if mailbox_manager.allow_id(id) {
+                StatusCode::OK
+            } else {
+                StatusCode::NOT_FOUND
+            }
+        });
+
+    get_config
+        .or(get_status)
+        .or(list_mailboxes)
+        .or(get_mailbox)
+        .or(evict_mailboxes)
+        .or(get_pending_messages)
+        .or(disconnect_client)
+        .or(list_denied_mailboxes)
+        .or(deny_mailbox)
+        .or(allow_mailbox)
+        .recover(handle_rejection)
+}
+
+/// Admin authentication filter
+fn with_admin_auth(admin_token: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let admin_token = admin_token.map(Arc::<str>::from);
+    warp::header::optional::<String>("authorization")
+        .and_then(move |auth_header: Option<String>| {
+            let admin_token = admin_token.clone();
+            async move {
+                let admin_token = match admin_token {
+                    Some(token) => token,
+                    None => return Err(warp::reject::not_found()), // admin API disabled
+                };
+                match auth_header.as_deref().and_then(|h| h.strip_prefix("Bearer ")) {
+                    Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => Ok(()),
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Compare the secrets in a time which does not depend on where they differ,
+/// so that the token cannot be guessed byte by byte from the response times (only its length can be told)
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(StatusCode::UNAUTHORIZED)
+    } else {
+        Err(rejection)
+    }
+}
+
+#[derive(Serialize)]
+struct StatusReply {
+    /// Unix timestamp (in seconds) of the service start
+    started_at: u64,
+    uptime_seconds: u64,
+}
+
+/// Service start time and uptime
+fn status(started_at: SystemTime) -> impl Reply {
+    warp::reply::json(&StatusReply {
+        started_at: started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        uptime_seconds: started_at.elapsed().unwrap_or_default().as_secs(),
+    })
+}
+
+#[derive(Deserialize)]
+struct EvictQuery {
+    older_than_sec: u64,
+}
+
+#[derive(Serialize)]
+struct EvictReply {
+    closed: usize,
+}
+
+/// Force-close all mailboxes older than the given age, killing their connected clients
+fn evict_mailboxes(query: EvictQuery, mailbox_manager: MailboxManager, clients: Clients) -> impl Reply {
+    let max_age = Duration::from_secs(query.older_than_sec);
+    let to_close = mailbox_manager.close_mailboxes_older_than(max_age);
+    log::info!("Evicting {} mailboxes older than {:?}", to_close.len(), max_age);
+    for (mailbox_id, peers) in &to_close {
+        for &client_id in peers {
+            if let Some(client) = clients.find(client_id) {
+                log::trace!("forcibly killing {:?} because {:?} is being evicted", client_id, mailbox_id);
+                client.kill(CloseReason::MailboxExpired);
+            }
+        }
+    }
+    warp::reply::json(&EvictReply { closed: to_close.len() })
+}
+
+/// Gracefully disconnect a single client
+fn disconnect_client(client_id: u64, clients: Clients) -> impl Reply {
+    let client_id = ClientId::from_raw(client_id);
+    match clients.find(client_id) {
+        Some(client) => {
+            log::info!("Disconnecting {:?} by admin request", client_id);
+            client.kill(CloseReason::AdminDisconnect);
+            StatusCode::OK
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{builder::ServerBuilder, Server};
+
+    const TOKEN: &str = "secret";
+
+    fn admin_routes(server: &Server) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        routes(
+            Some(TOKEN.to_owned()),
+            None,
+            SystemTime::now(),
+            false,
+            server.mailbox_manager.clone(),
+            server.clients.clone(),
+        )
+    }
+
+    #[tokio::test]
+    async fn evict_closes_mailboxes_without_clients() {
+        let server = ServerBuilder::new().port(0).metrics_port(0).build().new_server();
+        let mailbox_manager = &server.mailbox_manager;
+        // a mailbox with its creator attached, and one restored with a message for a peer which has not connected yet
+        let (attached_id, _) = mailbox_manager.create_mailbox(false, false, None, None).unwrap();
+        let _ = mailbox_manager
+            .attach_client(attached_id, ClientId::from_raw(1), None, false)
+            .unwrap();
+        let restored = serde_json::json!([{ "id": 1000001, "messages": [{ "text": "hello" }] }]);
+        mailbox_manager.import_pending_messages(serde_json::from_value(restored).unwrap());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let reply = warp::test::request()
+            .method("POST")
+            .path("/admin/mailboxes/evict?older_than_sec=0")
+            .header("authorization", format!("Bearer {}", TOKEN))
+            .reply(&admin_routes(&server))
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        let reply: serde_json::Value = serde_json::from_slice(reply.body()).unwrap();
+        assert_eq!(reply, serde_json::json!({ "closed": 2 }));
+
+        // the mailbox with a client is closing until the client is gone, the other one is destroyed right away
+        let snapshot = serde_json::to_value(mailbox_manager.snapshot()).unwrap();
+        let snapshot = snapshot.as_array().unwrap();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0]["id"], attached_id.raw());
+        assert_eq!(snapshot[0]["is_closing"], true);
+        assert!(mailbox_manager.export_pending_messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn pending_messages_are_reported() {
+        let server = ServerBuilder::new().port(0).metrics_port(0).build().new_server();
+        let mailbox_manager = &server.mailbox_manager;
+        let (id, _) = mailbox_manager.create_mailbox(false, false, None, None).unwrap();
+        let _ = mailbox_manager.attach_client(id, ClientId::from_raw(1), None, false).unwrap();
+        for msg in [warp::ws::Message::text("hello"), warp::ws::Message::binary(vec![1, 2, 3])] {
+            let _ = mailbox_manager.send_to_mailbox(id, ClientId::from_raw(1), msg);
+        }
+
+        for show_contents in [false, true] {
+            let routes = routes(
+                Some(TOKEN.to_owned()),
+                None,
+                SystemTime::now(),
+                show_contents,
+                server.mailbox_manager.clone(),
+                server.clients.clone(),
+            );
+            let reply = warp::test::request()
+                .path(&format!("/admin/mailboxes/{}/pending", id.raw()))
+                .header("authorization", format!("Bearer {}", TOKEN))
+                .reply(&routes)
+                .await;
+            assert_eq!(reply.status(), StatusCode::OK);
+            let reply: serde_json::Value = serde_json::from_slice(reply.body()).unwrap();
+            // the messages are pending for the free slot of the joining peer
+            let mut expected = serde_json::json!([
+                { "client_id": 1, "count": 0, "total_bytes": 0, "sizes": [] },
+                { "client_id": null, "count": 2, "total_bytes": 8, "sizes": [5, 3] },
+            ]);
+            if show_contents {
+                expected[0]["messages"] = serde_json::json!([]);
+                expected[1]["messages"] = serde_json::json!([{ "text": "hello" }, { "binary": [1, 2, 3] }]);
+            }
+            assert_eq!(reply, expected);
+        }
+
+        let reply = warp::test::request()
+            .path("/admin/mailboxes/42/pending")
+            .header("authorization", format!("Bearer {}", TOKEN))
+            .reply(&admin_routes(&server))
+            .await;
+        assert_eq!(reply.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn deny_list_is_adjustable() {
+        let server = ServerBuilder::new().port(0).metrics_port(0).build().new_server();
+        let request = |method: &str, path: &str| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("authorization", format!("Bearer {}", TOKEN))
+        };
+        let reply = request("PUT", "/admin/denied-mailboxes/42").reply(&admin_routes(&server)).await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        let reply = request("GET", "/admin/denied-mailboxes").reply(&admin_routes(&server)).await;
+        assert_eq!(reply.body().as_ref(), b"[42]");
+        let reply = request("DELETE", "/admin/denied-mailboxes/42").reply(&admin_routes(&server)).await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        let reply = request("DELETE", "/admin/denied-mailboxes/42").reply(&admin_routes(&server)).await;
+        assert_eq!(reply.status(), StatusCode::NOT_FOUND);
+        assert!(server.mailbox_manager.denied_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn uptime_increases() {
+        let server = ServerBuilder::new().port(0).metrics_port(0).build().new_server();
+        let started_at = SystemTime::now() - Duration::from_secs(100);
+        let routes = routes(
+            Some(TOKEN.to_owned()),
+            None,
+            started_at,
+            false,
+            server.mailbox_manager.clone(),
+            server.clients.clone(),
+        );
+        let status = || async {
+            let reply = warp::test::request()
+                .path("/admin/status")
+                .header("authorization", format!("Bearer {}", TOKEN))
+                .reply(&routes)
+                .await;
+            assert_eq!(reply.status(), StatusCode::OK);
+            serde_json::from_slice::<serde_json::Value>(reply.body()).unwrap()
+        };
+
+        let first = status().await;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let second = status().await;
+        let started_at = started_at.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(first["started_at"], started_at);
+        assert_eq!(second["started_at"], started_at);
+        assert!(first["uptime_seconds"].as_u64().unwrap() >= 100);
+        assert!(second["uptime_seconds"].as_u64().unwrap() > first["uptime_seconds"].as_u64().unwrap());
+    }
+
+    #[tokio::test]
+    async fn wrong_token_is_unauthorized() {
+        let server = ServerBuilder::new().port(0).metrics_port(0).build().new_server();
+        for header in ["Bearer wrong", "Bearer secreT", "Bearer secret2", "Bearer ", "secret"] {
+            let reply = warp::test::request()
+                .path("/admin/status")
+                .header("authorization", header)
+                .reply(&admin_routes(&server))
+                .await;
+            assert_eq!(reply.status(), StatusCode::UNAUTHORIZED, "{}", header);
+        }
+        let reply = warp::test::request()
+            .path("/admin/status")
+            .header("authorization", format!("Bearer {}", TOKEN))
+            .reply(&admin_routes(&server))
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -1,28 +1,359 @@
 //! Safe-sync Web server instance builder.
 
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
 use builder_pattern::Builder;
+use tokio::sync::Semaphore;
 
 use super::{
-    websocket::{client::Clients, mailbox::MailboxManager},
-    Server,
+    config::{
+        ClosePolicy, ClosingMessagePolicy, IdFormat, NamespaceLimits, OutboundOverflowAction, PeerIpPolicy, ServiceConfig,
+        SubprotocolPolicy,
+    },
+    listener::ListenerSettings,
+    websocket::{
+        client::Clients,
+        connection::ConnectionSettings,
+        ip_limit::IpConnectionLimiter,
+        mailbox::{MailboxManager, MailboxSettings},
+        rate_limit::RateLimiter,
+        transform::{MessageTransformer, PassThrough},
+    },
+    Batching, Server, TlsFiles,
 };
 
 #[derive(Builder)]
 pub struct ServerBuilder {
+    #[public]
+    #[default(IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    bind_addr: IpAddr,
+
+    #[public]
+    #[default(false)]
+    dual_stack: bool,
+
+    #[public]
+    #[default(1024)]
+    listen_backlog: u32,
+
     #[public]
     port: u16,
 
     #[public]
     metrics_port: u16,
+
+    #[public]
+    #[default(None)]
+    tls: Option<TlsFiles>,
+
+    #[public]
+    #[default(None)]
+    admin_token: Option<String>,
+
+    #[public]
+    #[default(9091)]
+    admin_port: u16,
+
+    #[public]
+    #[default(false)]
+    admin_show_pending_contents: bool,
+
+    #[public]
+    #[default(true)]
+    flush_before_kill: bool,
+
+    #[public]
+    #[default(1000)]
+    max_prepairing_messages: usize,
+
+    #[public]
+    #[default(0)]
+    max_prepairing_bytes: usize,
+
+    #[public]
+    #[default(Arc::new(PassThrough))]
+    transformer: Arc<dyn MessageTransformer>,
+
+    #[public]
+    #[default(10000)]
+    max_connections: usize,
+
+    #[public]
+    #[default(0)]
+    max_handshake_connections: usize,
+
+    #[public]
+    #[default(0)]
+    max_connections_per_ip: usize,
+
+    #[public]
+    #[default(false)]
+    trust_forwarded_for: bool,
+
+    #[public]
+    #[default(SubprotocolPolicy::Lenient)]
+    subprotocol_policy: SubprotocolPolicy,
+
+    #[public]
+    #[default(0)]
+    max_open_mailboxes: usize,
+
+    #[public]
+    #[default(2)]
+    max_mailbox_capacity: usize,
+
+    #[public]
+    #[default(Vec::new())]
+    denied_mailbox_ids: Vec<u32>,
+
+    #[public]
+    #[default(Vec::new())]
+    metrics_tenants: Vec<String>,
+
+    #[public]
+    #[default(ClosePolicy::AnyPeer)]
+    close_policy: ClosePolicy,
+
+    #[public]
+    #[default(ClosingMessagePolicy::Drop)]
+    closing_message_policy: ClosingMessagePolicy,
+
+    #[public]
+    #[default(PeerIpPolicy::Any)]
+    peer_ip_policy: PeerIpPolicy,
+
+    #[public]
+    #[default(false)]
+    require_connect_token: bool,
+
+    #[public]
+    #[default(IdFormat::Numeric)]
+    mailbox_id_format: IdFormat,
+
+    #[public]
+    #[default(None)]
+    id_prefix: Option<String>,
+
+    #[public]
+    #[default(0)]
+    id_pool_size: usize,
+
+    #[public]
+    #[default(false)]
+    strict_protocol: bool,
+
+    #[public]
+    #[default(false)]
+    relay_ping_frames: bool,
+
+    #[public]
+    #[default(0)]
+    max_creates_per_sec: u32,
+
+    #[public]
+    #[default(HashMap::new())]
+    namespace_limits: HashMap<String, NamespaceLimits>,
+
+    #[public]
+    #[default(false)]
+    echo_server_time: bool,
+
+    #[public]
+    #[default(None)]
+    time_sync_interval: Option<Duration>,
+
+    #[public]
+    #[default(Duration::from_secs(10))]
+    drain_timeout: Duration,
+
+    #[public]
+    #[default(0)]
+    shutdown_notice_batch_size: usize,
+
+    #[public]
+    #[default(Duration::ZERO)]
+    shutdown_notice_pause: Duration,
+
+    #[public]
+    #[default(Duration::ZERO)]
+    shutdown_reconnect_after: Duration,
+
+    #[public]
+    #[default(1)]
+    shutdown_kill_batch_size: usize,
+
+    #[public]
+    #[default(Duration::from_millis(1))]
+    shutdown_kill_pause: Duration,
+
+    #[public]
+    #[default(Some(Duration::from_secs(60)))]
+    mailbox_timeout: Option<Duration>,
+
+    #[public]
+    #[default(None)]
+    unpaired_timeout: Option<Duration>,
+
+    #[public]
+    #[default(None)]
+    reconnect_grace: Option<Duration>,
+
+    #[public]
+    #[default(Duration::from_secs(5))]
+    close_timeout: Duration,
+
+    #[public]
+    #[default(None)]
+    client_idle_timeout: Option<Duration>,
+
+    #[public]
+    #[default(Duration::from_secs(10))]
+    client_pong_timeout: Duration,
+
+    #[public]
+    #[default(0)]
+    max_outstanding_pings: usize,
+
+    #[public]
+    #[default(Duration::from_secs(30))]
+    reservation_timeout: Duration,
+
+    #[public]
+    #[default(Duration::from_secs(3600))]
+    restored_mailbox_ttl: Duration,
+
+    #[public]
+    #[default(0)]
+    max_pending_bytes: usize,
+
+    #[public]
+    #[default(0)]
+    peer_slow_threshold: usize,
+
+    #[public]
+    #[default(false)]
+    nack_undelivered: bool,
+
+    #[public]
+    #[default(0)]
+    max_outbound_bytes: usize,
+
+    #[public]
+    #[default(4096)]
+    outbound_buffer: usize,
+
+    #[public]
+    #[default(1024 * 1024)]
+    max_text_bytes: usize,
+
+    #[public]
+    #[default(1024 * 1024)]
+    max_binary_bytes: usize,
+
+    #[public]
+    #[default(OutboundOverflowAction::Disconnect)]
+    outbound_overflow: OutboundOverflowAction,
+
+    #[public]
+    #[default(None)]
+    service_config: Option<ServiceConfig>,
+
+    #[public]
+    #[default(SystemTime::now())]
+    started_at: SystemTime,
 }
 
 impl ServerBuilder {
     pub fn new_server(self) -> Server {
+        let namespace_create_limiters = self
+            .namespace_limits
+            .iter()
+            .filter(|(_, limits)| limits.max_creates_per_sec > 0)
+            .map(|(ns, limits)| (ns.clone(), RateLimiter::new(limits.max_creates_per_sec)))
+            .collect::<HashMap<_, _>>();
+        let mailbox_manager = MailboxManager::new(MailboxSettings {
+            max_prepairing_messages: self.max_prepairing_messages,
+            max_prepairing_bytes: self.max_prepairing_bytes,
+            close_policy: self.close_policy,
+            id_pool_size: self.id_pool_size,
+            reservation_timeout: self.reservation_timeout,
+            max_pending_bytes: self.max_pending_bytes,
+            max_open_mailboxes: self.max_open_mailboxes,
+            max_capacity: self.max_mailbox_capacity,
+            peer_ip_policy: self.peer_ip_policy,
+            require_connect_token: self.require_connect_token,
+            mailbox_timeout: self.mailbox_timeout,
+            unpaired_timeout: self.unpaired_timeout.or(self.mailbox_timeout),
+            reconnect_grace: self.reconnect_grace,
+            restored_mailbox_ttl: self.restored_mailbox_ttl,
+            namespace_limits: self.namespace_limits,
+        });
+        for id in self.denied_mailbox_ids {
+            mailbox_manager.deny_id(id);
+        }
         Server {
+            listener_settings: ListenerSettings {
+                bind_addr: self.bind_addr,
+                dual_stack: self.dual_stack,
+                backlog: self.listen_backlog,
+            },
             port: self.port,
             metrics_port: self.metrics_port,
-            mailbox_manager: MailboxManager::default(),
+            tls: self.tls,
+            admin_token: self.admin_token,
+            admin_port: self.admin_port,
+            admin_show_pending_contents: self.admin_show_pending_contents,
+            service_config: self.service_config.map(Arc::new),
+            started_at: self.started_at,
+            drain_timeout: self.drain_timeout,
+            shutdown_notice_batching: Batching {
+                size: self.shutdown_notice_batch_size,
+                pause: self.shutdown_notice_pause,
+            },
+            shutdown_reconnect_after: self.shutdown_reconnect_after,
+            shutdown_kill_batching: Batching {
+                size: self.shutdown_kill_batch_size,
+                pause: self.shutdown_kill_pause,
+            },
+            connection_settings: ConnectionSettings {
+                flush_before_kill: self.flush_before_kill,
+                transformer: self.transformer,
+                metrics_tenants: Arc::new(self.metrics_tenants.into_iter().collect::<HashSet<_>>()),
+                id_format: self.mailbox_id_format,
+                closing_message_policy: self.closing_message_policy,
+                id_prefix: self.id_prefix.map(Arc::from),
+                strict_protocol: self.strict_protocol,
+                relay_ping_frames: self.relay_ping_frames,
+                echo_server_time: self.echo_server_time,
+                time_sync_interval: self.time_sync_interval,
+                close_timeout: self.close_timeout,
+                drain_timeout: self.drain_timeout,
+                client_idle_timeout: self.client_idle_timeout,
+                client_pong_timeout: self.client_pong_timeout,
+                max_outstanding_pings: self.max_outstanding_pings,
+                peer_slow_threshold: self.peer_slow_threshold,
+                nack_undelivered: self.nack_undelivered,
+                max_outbound_bytes: self.max_outbound_bytes,
+                outbound_buffer: self.outbound_buffer,
+                max_text_bytes: self.max_text_bytes,
+                max_binary_bytes: self.max_binary_bytes,
+                outbound_overflow: self.outbound_overflow,
+                create_limiter: (self.max_creates_per_sec > 0).then(|| Arc::new(RateLimiter::new(self.max_creates_per_sec))),
+                namespace_create_limiters: Arc::new(namespace_create_limiters),
+            },
+            connection_limit: Arc::new(Semaphore::new(self.max_connections)),
+            handshake_limit: (self.max_handshake_connections > 0).then(|| Arc::new(Semaphore::new(self.max_handshake_connections))),
+            ip_limit: (self.max_connections_per_ip > 0).then(|| Arc::new(IpConnectionLimiter::new(self.max_connections_per_ip))),
+            trust_forwarded_for: self.trust_forwarded_for,
+            subprotocol_policy: self.subprotocol_policy,
+            mailbox_manager,
             clients: Clients::default(),
+            shutting_down: Arc::default(),
         }
     }
 }
@@ -1,19 +1,300 @@
 //! Safe-sync server configs.
 
-use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+};
 
-/// Safe-sync server application config
-#[derive(Clone)]
+use serde::{Deserialize, Serialize, Serializer};
+
+/// Safe-sync server application config (serialized with secrets redacted)
+#[derive(Clone, Serialize)]
 pub struct ServiceConfig {
+    /// Address the main, metrics and admin ports are listened on
+    pub bind_addr: IpAddr,
+
+    /// Accept IPv4 connections on the IPv6 `bind_addr` as well (IPv6 only otherwise, whatever the host default is)
+    pub dual_stack: bool,
+
+    /// Max number of established connections waiting to be accepted by each listener
+    pub listen_backlog: u32,
+
     /// Server port
     pub port: u16,
 
     /// Metrics port
     pub metrics_port: u16,
+
+    /// Certificate chain (PEM) of the TLS listener of the main port (plain HTTP if not set, e.g. behind a TLS-terminating proxy)
+    pub tls_cert_path: Option<PathBuf>,
+
+    /// Private key (PEM) of the TLS listener of the main port
+    pub tls_key_path: Option<PathBuf>,
+
+    /// Token required to access the admin API (admin API is disabled if not set)
+    #[serde(serialize_with = "serialize_redacted")]
+    pub admin_token: Option<String>,
+
+    /// Port of the admin API (plain HTTP, only listened on if the admin API is enabled)
+    pub admin_port: u16,
+
+    /// Include the contents of pending messages (not only their sizes) in the admin API replies
+    pub admin_show_pending_contents: bool,
+
+    /// Log output format
+    pub log_format: LogFormat,
+
+    /// Deliver messages already queued for a client before disconnecting it
+    /// (e.g. when the other peer of its mailbox leaves)
+    pub flush_before_kill: bool,
+
+    /// Max number of messages enqueued in a mailbox before the second peer connects
+    pub max_prepairing_messages: usize,
+
+    /// Max total size (in bytes) of messages enqueued in a mailbox before the second peer connects (0 means unlimited)
+    pub max_prepairing_bytes: usize,
+
+    /// File to save messages pending for not yet connected peers on graceful shutdown
+    /// and to restore them from on startup (not persisted if not set)
+    pub pending_messages_file: Option<PathBuf>,
+
+    /// Max time (in seconds) a restored mailbox waits for a client to connect before it is closed with its messages,
+    /// counted from the creation of the mailbox (before the restart)
+    pub restored_mailbox_ttl_sec: u64,
+
+    /// Max number of simultaneously handled websocket connections
+    pub max_connections: usize,
+
+    /// Max number of websocket connections without a mailbox yet, excess ones are rejected (0 means unlimited)
+    pub max_handshake_connections: usize,
+
+    /// Max number of simultaneous websocket connections from a single IP address, excess ones are rejected (0 means unlimited)
+    pub max_connections_per_ip: usize,
+
+    /// Take the client IP address from the `X-Forwarded-For` header set by a reverse proxy
+    pub trust_forwarded_for: bool,
+
+    /// What happens to a websocket connection offering none of the supported subprotocols
+    pub subprotocol_policy: SubprotocolPolicy,
+
+    /// Max number of open mailboxes, creating more fails until some are closed (0 means unlimited)
+    pub max_open_mailboxes: usize,
+
+    /// Max number of peers a client can request when creating a group mailbox (2 disables group mailboxes)
+    pub max_mailbox_capacity: usize,
+
+    /// Mailbox IDs which are never issued and cannot be connected to
+    pub denied_mailbox_ids: Vec<u32>,
+
+    /// Tenants reported in metrics labels as-is (any other tenant is reported as "other")
+    pub metrics_tenants: Vec<String>,
+
+    /// Which peer leaving a mailbox ends the session
+    pub close_policy: ClosePolicy,
+
+    /// What happens to messages sent to a mailbox which is being closed
+    pub closing_message_policy: ClosingMessagePolicy,
+
+    /// Which IP addresses the joining peer of a mailbox may connect from, compared to its creator
+    pub peer_ip_policy: PeerIpPolicy,
+
+    /// Issue a secret token for every new mailbox, which peers must present to connect to it
+    pub require_connect_token: bool,
+
+    /// How mailbox IDs are presented to clients
+    pub mailbox_id_format: IdFormat,
+
+    /// Environment prefix of the mailbox IDs presented to clients (if any), e.g. "stg" for "stg-12345"
+    pub id_prefix: Option<String>,
+
+    /// Number of mailbox IDs generated in advance (0 disables the pool)
+    pub id_pool_size: usize,
+
+    /// Close the connection on protocol violations instead of replying with a non-fatal error
+    pub strict_protocol: bool,
+
+    /// Relay websocket ping and pong frames to the other peer of the mailbox
+    pub relay_ping_frames: bool,
+
+    /// Max number of mailboxes created per second server-wide (0 means unlimited)
+    pub max_creates_per_sec: u32,
+
+    /// Limits of the listed namespaces, applied to their mailboxes on top of the server-wide ones
+    pub namespace_limits: HashMap<String, NamespaceLimits>,
+
+    /// Max time (in seconds) graceful shutdown waits for disconnected clients to finish, e.g. to flush their queues
+    pub drain_timeout_sec: u64,
+
+    /// Number of clients sent the shutdown notice at once (0 means all of them)
+    pub shutdown_notice_batch_size: usize,
+
+    /// Pause (in milliseconds) after each batch of shutdown notices
+    pub shutdown_notice_pause_ms: u64,
+
+    /// Time (in seconds) clients are advised by the shutdown notice to wait before reconnecting
+    pub shutdown_reconnect_after_sec: u64,
+
+    /// Number of clients disconnected at once on graceful shutdown (0 means all of them)
+    pub shutdown_kill_batch_size: usize,
+
+    /// Pause (in milliseconds) after each batch of clients disconnected on graceful shutdown
+    pub shutdown_kill_pause_ms: u64,
+
+    /// Max time (in seconds) to wait for the client to complete the websocket close handshake
+    pub close_timeout_sec: u64,
+
+    /// Time (in seconds) without any frames from a client after which it is pinged (0 disables the idle timeout)
+    pub client_idle_timeout_sec: u64,
+
+    /// Max time (in seconds) to wait for any frame from an idle client after pinging it, it is disconnected afterwards
+    pub client_pong_timeout_sec: u64,
+
+    /// Max number of idle pings a client can leave unanswered at once, it is disconnected when another one is due
+    /// (0 means unlimited)
+    pub max_outstanding_pings: usize,
+
+    /// How long (in seconds) a reserved mailbox slot is held unless confirmed
+    pub reservation_timeout_sec: u64,
+
+    /// Max time (in seconds) a mailbox with both peers connected can stay idle, it is closed afterwards (60 by default, 0 means unlimited)
+    pub mailbox_timeout_sec: u64,
+
+    /// Max time (in seconds) a mailbox with a single peer connected can stay idle (0 means the same as `mailbox_timeout_sec`)
+    pub unpaired_timeout_sec: u64,
+
+    /// How long (in seconds) the mailbox slot of a client which has lost its connection is held for it to reconnect (0 disables reconnection)
+    pub reconnect_grace_sec: u64,
+
+    /// Max total size (in bytes) of pending messages in all mailboxes, the oldest ones are evicted above it (0 means unlimited)
+    pub max_pending_bytes: usize,
+
+    /// Number of messages queued for a receiver above which the sender gets a 'peer_slow' advisory (0 disables it)
+    pub peer_slow_threshold: usize,
+
+    /// Reply with 'nack' to the sender of a message which could not be delivered to the other peer
+    pub nack_undelivered: bool,
+
+    /// Max total size (in bytes) of messages waiting to be sent to a client (0 means unlimited)
+    pub max_outbound_bytes: usize,
+
+    /// Max number of messages waiting to be sent to a client
+    pub outbound_buffer: usize,
+
+    /// Max size (in bytes) of a single text message sent by a client, a larger one closes the connection (0 means unlimited)
+    pub max_text_bytes: usize,
+
+    /// Max size (in bytes) of a single binary message sent by a client, a larger one closes the connection (0 means unlimited)
+    pub max_binary_bytes: usize,
+
+    /// What happens to a message which its receiver is too slow to take (over the size limit or with a full buffer)
+    pub outbound_overflow: OutboundOverflowAction,
+
+    /// Include the server time in the handshake replies
+    pub echo_server_time: bool,
+
+    /// Interval (in seconds) of the 'time_sync' messages sent to every client (0 disables them)
+    pub time_sync_interval_sec: u64,
+}
+
+/// Limits of the mailboxes in a single namespace, so that one application cannot exhaust another's capacity
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize)]
+pub struct NamespaceLimits {
+    /// Max number of open mailboxes in the namespace (0 means only the server-wide limit applies)
+    pub max_open_mailboxes: usize,
+    /// Max idle time (in seconds) of a mailbox in the namespace, replaces `mailbox_timeout_sec` (0 means the server-wide one)
+    pub mailbox_timeout_sec: u64,
+    /// Max number of mailboxes created per second in the namespace (0 means only the server-wide limit applies)
+    pub max_creates_per_sec: u32,
+}
+
+/// Which peer leaving a mailbox ends the session
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClosePolicy {
+    /// Any peer leaving destroys the mailbox and disconnects the other peer
+    AnyPeer,
+    /// Only the creator leaving destroys the mailbox, the joiner leaving frees its slot for a new joiner
+    Creator,
+}
+
+/// What happens to messages sent to a mailbox which is being closed (its other peer has left)
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClosingMessagePolicy {
+    /// The message is silently dropped (counted in the metrics)
+    Drop,
+    /// The message is dropped and the sender receives a 'closing' notice
+    Reject,
+}
+
+/// Which IP addresses the joining peer of a mailbox may connect from, compared to the creator of the mailbox
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerIpPolicy {
+    /// Any IP address
+    Any,
+    /// The same subnet as the creator (/24 for IPv4, /64 for IPv6)
+    SameSubnet,
+    /// Any IP address other than the creator's
+    DifferentIp,
+}
+
+/// What happens to a websocket connection offering none of the supported subprotocols in `Sec-WebSocket-Protocol`
+/// (or not sending the header at all)
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubprotocolPolicy {
+    /// The connection is accepted and speaks the oldest protocol version
+    Lenient,
+    /// The connection is rejected
+    Strict,
+}
+
+/// What happens to a message which would take the data waiting to be sent to its receiver over the limit
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutboundOverflowAction {
+    /// The message is dropped and the receiver is disconnected
+    Disconnect,
+    /// The message is dropped and the sender receives an error
+    Reject,
+}
+
+/// How mailbox IDs are presented to clients
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdFormat {
+    /// JSON number, e.g. `1000001`
+    Numeric,
+    /// Short case-insensitive alphanumeric string, e.g. `"00YH21"`
+    Alphanumeric,
+}
+
+/// Log output format
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable plain text
+    Plain,
+    /// One JSON object per line, for structured log ingestion
+    Json,
 }
 
 #[derive(Deserialize)]
 struct RawConfig {
+    /// Listening address of all the ports
+    #[serde(default = "default_bind_addr")]
+    bind_addr: IpAddr,
+
+    /// Accept both IPv6 and IPv4 connections on an IPv6 listening address
+    #[serde(default)]
+    dual_stack: bool,
+
+    /// TCP listen backlog of the listeners
+    #[serde(default = "default_listen_backlog")]
+    listen_backlog: u32,
+
     /// Server port
     #[serde(default = "default_port")]
     port: u16,
@@ -21,6 +302,229 @@ struct RawConfig {
     /// Metrics port
     #[serde(default = "default_metrics_port")]
     metrics_port: u16,
+
+    /// TLS certificate chain file of the main port
+    tls_cert_path: Option<PathBuf>,
+
+    /// TLS private key file of the main port
+    tls_key_path: Option<PathBuf>,
+
+    /// Token required to access the admin API
+    admin_token: Option<String>,
+
+    /// Admin API port
+    #[serde(default = "default_admin_port")]
+    admin_port: u16,
+
+    /// Include the contents of pending messages in the admin API replies
+    #[serde(default)]
+    admin_show_pending_contents: bool,
+
+    /// Log output format (the same variable is used by `wavesexchange_log`)
+    #[serde(rename = "rust_log_format", default = "default_log_format")]
+    log_format: LogFormat,
+
+    /// Deliver queued messages before disconnecting a client
+    #[serde(default = "default_flush_before_kill")]
+    flush_before_kill: bool,
+
+    /// Max number of messages enqueued before the second peer connects
+    #[serde(default = "default_max_prepairing_messages")]
+    max_prepairing_messages: usize,
+
+    /// Max total size of messages enqueued before the second peer connects
+    #[serde(default)]
+    max_prepairing_bytes: usize,
+
+    /// File to persist pending messages across restarts
+    pending_messages_file: Option<PathBuf>,
+
+    /// Max time a restored mailbox waits for a client to connect
+    #[serde(default = "default_restored_mailbox_ttl_sec")]
+    restored_mailbox_ttl_sec: u64,
+
+    /// Max number of simultaneous websocket connections
+    #[serde(default = "default_max_connections")]
+    max_connections: usize,
+
+    /// Max number of websocket connections without a mailbox yet
+    #[serde(default)]
+    max_handshake_connections: usize,
+
+    /// Max number of simultaneous websocket connections from a single IP address
+    #[serde(default)]
+    max_connections_per_ip: usize,
+
+    /// Take the client IP address from the `X-Forwarded-For` header
+    #[serde(default)]
+    trust_forwarded_for: bool,
+
+    /// What happens to a websocket connection offering none of the supported subprotocols
+    #[serde(default = "default_subprotocol_policy")]
+    subprotocol_policy: SubprotocolPolicy,
+
+    /// Max number of open mailboxes
+    #[serde(default)]
+    max_open_mailboxes: usize,
+
+    /// Max number of peers of a group mailbox
+    #[serde(default = "default_max_mailbox_capacity")]
+    max_mailbox_capacity: usize,
+
+    /// Comma-separated list of denied mailbox IDs
+    #[serde(default)]
+    denied_mailbox_ids: Vec<u32>,
+
+    /// Comma-separated list of tenants reported in metrics
+    #[serde(default)]
+    metrics_tenants: Vec<String>,
+
+    /// Which peer leaving a mailbox ends the session
+    #[serde(default = "default_close_policy")]
+    close_policy: ClosePolicy,
+
+    /// What happens to messages sent to a mailbox which is being closed
+    #[serde(default = "default_closing_message_policy")]
+    closing_message_policy: ClosingMessagePolicy,
+
+    /// Which IP addresses the joining peer of a mailbox may connect from
+    #[serde(default = "default_peer_ip_policy")]
+    peer_ip_policy: PeerIpPolicy,
+
+    /// Issue and require connect tokens
+    #[serde(default)]
+    require_connect_token: bool,
+
+    /// How mailbox IDs are presented to clients
+    #[serde(default = "default_mailbox_id_format")]
+    mailbox_id_format: IdFormat,
+
+    /// Environment prefix of the mailbox IDs presented to clients
+    id_prefix: Option<String>,
+
+    /// Number of mailbox IDs generated in advance
+    #[serde(default)]
+    id_pool_size: usize,
+
+    /// Close the connection on protocol violations
+    #[serde(default)]
+    strict_protocol: bool,
+
+    /// Relay websocket ping and pong frames to the other peer of the mailbox
+    #[serde(default)]
+    relay_ping_frames: bool,
+
+    /// Max number of mailboxes created per second server-wide
+    #[serde(default)]
+    max_creates_per_sec: u32,
+
+    /// Comma-separated list of `namespace:max_open_mailboxes:mailbox_timeout_sec:max_creates_per_sec` entries
+    #[serde(default)]
+    namespace_limits: Vec<String>,
+
+    /// Max time graceful shutdown waits for disconnected clients to finish
+    #[serde(default = "default_drain_timeout_sec")]
+    drain_timeout_sec: u64,
+
+    /// Number of clients sent the shutdown notice at once
+    #[serde(default)]
+    shutdown_notice_batch_size: usize,
+
+    /// Pause after each batch of shutdown notices
+    #[serde(default)]
+    shutdown_notice_pause_ms: u64,
+
+    /// Time clients are advised by the shutdown notice to wait before reconnecting
+    #[serde(default)]
+    shutdown_reconnect_after_sec: u64,
+
+    /// Number of clients disconnected at once on graceful shutdown
+    #[serde(default = "default_shutdown_kill_batch_size")]
+    shutdown_kill_batch_size: usize,
+
+    /// Pause after each batch of clients disconnected on graceful shutdown
+    #[serde(default = "default_shutdown_kill_pause_ms")]
+    shutdown_kill_pause_ms: u64,
+
+    /// Max time to wait for the client to complete the websocket close handshake
+    #[serde(default = "default_close_timeout_sec")]
+    close_timeout_sec: u64,
+
+    /// Time without any frames from a client after which it is pinged
+    #[serde(default)]
+    client_idle_timeout_sec: u64,
+
+    /// Max time to wait for any frame from an idle client after pinging it
+    #[serde(default = "default_client_pong_timeout_sec")]
+    client_pong_timeout_sec: u64,
+
+    /// Max number of idle pings a client can leave unanswered at once
+    #[serde(default)]
+    max_outstanding_pings: usize,
+
+    /// How long a reserved mailbox slot is held unless confirmed
+    #[serde(default = "default_reservation_timeout_sec")]
+    reservation_timeout_sec: u64,
+
+    /// Max idle time of a mailbox with both peers connected
+    #[serde(default = "default_mailbox_timeout_sec")]
+    mailbox_timeout_sec: u64,
+
+    /// Max idle time of a mailbox with a single peer connected
+    #[serde(default)]
+    unpaired_timeout_sec: u64,
+
+    /// How long the mailbox slot of a client which has lost its connection is held for it to reconnect
+    #[serde(default)]
+    reconnect_grace_sec: u64,
+
+    /// Max total size of pending messages in all mailboxes
+    #[serde(default)]
+    max_pending_bytes: usize,
+
+    /// Number of messages queued for a receiver above which the sender gets a 'peer_slow' advisory
+    #[serde(default)]
+    peer_slow_threshold: usize,
+
+    /// Reply with 'nack' to the sender of an undelivered message
+    #[serde(default)]
+    nack_undelivered: bool,
+
+    /// Max total size of messages waiting to be sent to a client
+    #[serde(default)]
+    max_outbound_bytes: usize,
+
+    /// Max number of messages waiting to be sent to a client
+    #[serde(default = "default_outbound_buffer")]
+    outbound_buffer: usize,
+
+    /// Max size of a single text message sent by a client
+    #[serde(default = "default_max_message_bytes")]
+    max_text_bytes: usize,
+
+    /// Max size of a single binary message sent by a client
+    #[serde(default = "default_max_message_bytes")]
+    max_binary_bytes: usize,
+
+    /// What happens to a message which its receiver is too slow to take
+    #[serde(default = "default_outbound_overflow")]
+    outbound_overflow: OutboundOverflowAction,
+
+    /// Include the server time in the handshake replies
+    #[serde(default)]
+    echo_server_time: bool,
+
+    /// Interval of the 'time_sync' messages sent to every client
+    #[serde(default)]
+    time_sync_interval_sec: u64,
+}
+
+fn default_bind_addr() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
+fn default_listen_backlog() -> u32 {
+    1024
 }
 
 fn default_port() -> u16 {
@@ -28,16 +532,373 @@ fn default_port() -> u16 {
 }
 
 fn default_metrics_port() -> u16 {
-    8080
+    9090
+}
+
+fn default_admin_port() -> u16 {
+    9091
+}
+
+fn default_log_format() -> LogFormat {
+    LogFormat::Plain
+}
+
+fn default_flush_before_kill() -> bool {
+    true
+}
+
+fn default_max_prepairing_messages() -> usize {
+    1000
+}
+
+fn default_max_connections() -> usize {
+    10000
+}
+
+fn default_max_mailbox_capacity() -> usize {
+    2
+}
+
+fn default_max_message_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_close_policy() -> ClosePolicy {
+    ClosePolicy::AnyPeer
+}
+
+fn default_closing_message_policy() -> ClosingMessagePolicy {
+    ClosingMessagePolicy::Drop
+}
+
+fn default_subprotocol_policy() -> SubprotocolPolicy {
+    SubprotocolPolicy::Lenient
+}
+
+fn default_peer_ip_policy() -> PeerIpPolicy {
+    PeerIpPolicy::Any
+}
+
+fn default_outbound_buffer() -> usize {
+    4096
+}
+
+fn default_outbound_overflow() -> OutboundOverflowAction {
+    OutboundOverflowAction::Disconnect
+}
+
+fn default_mailbox_id_format() -> IdFormat {
+    IdFormat::Numeric
+}
+
+fn default_drain_timeout_sec() -> u64 {
+    10
+}
+
+fn default_shutdown_kill_batch_size() -> usize {
+    1
+}
+
+fn default_shutdown_kill_pause_ms() -> u64 {
+    1
+}
+
+fn default_close_timeout_sec() -> u64 {
+    5
+}
+
+fn default_client_pong_timeout_sec() -> u64 {
+    10
+}
+
+fn default_reservation_timeout_sec() -> u64 {
+    30
+}
+
+fn default_mailbox_timeout_sec() -> u64 {
+    60
+}
+
+fn default_restored_mailbox_ttl_sec() -> u64 {
+    3600
+}
+
+/// Parse a `namespace:max_open_mailboxes:mailbox_timeout_sec:max_creates_per_sec` entry,
+/// omitted trailing limits are 0 (not limited by the namespace)
+fn parse_namespace_limits(entry: &str) -> Result<(String, NamespaceLimits), anyhow::Error> {
+    let mut parts = entry.split(':');
+    let namespace = parts
+        .next()
+        .filter(|ns| !ns.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("empty namespace in '{}'", entry))?;
+    let mut limits = parts.map(|limit| {
+        limit
+            .parse::<u64>()
+            .map_err(|err| anyhow::anyhow!("invalid limit '{}' in '{}': {}", limit, entry, err))
+    });
+    let mut next_limit = || limits.next().transpose().map(Option::unwrap_or_default);
+    let namespace_limits = NamespaceLimits {
+        max_open_mailboxes: next_limit()? as usize,
+        mailbox_timeout_sec: next_limit()?,
+        max_creates_per_sec: u32::try_from(next_limit()?)?,
+    };
+    if limits.next().is_some() {
+        anyhow::bail!("too many limits in '{}'", entry);
+    }
+    Ok((namespace.to_owned(), namespace_limits))
+}
+
+/// Serialize a secret as a placeholder, so that only its presence is revealed
+fn serialize_redacted<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    secret.as_ref().map(|_| "<redacted>").serialize(serializer)
+}
+
+impl ServiceConfig {
+    /// Reject values which envy accepts but which would leave the server unable to serve clients,
+    /// naming the environment variable at fault
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        anyhow::ensure!(
+            !self.dual_stack || self.bind_addr.is_ipv6(),
+            "DUAL_STACK requires an IPv6 BIND_ADDR (got {}), e.g. ::",
+            self.bind_addr
+        );
+        anyhow::ensure!(
+            self.listen_backlog > 0,
+            "LISTEN_BACKLOG must be at least 1, otherwise connections are refused while the server is busy"
+        );
+        anyhow::ensure!(self.port > 0, "PORT must not be 0");
+        anyhow::ensure!(self.metrics_port > 0, "METRICS_PORT must not be 0");
+        anyhow::ensure!(
+            self.metrics_port != self.port,
+            "METRICS_PORT must differ from PORT ({}), both servers cannot listen on the same port",
+            self.port
+        );
+        anyhow::ensure!(
+            self.admin_token.is_none() || (self.admin_port > 0 && self.admin_port != self.port && self.admin_port != self.metrics_port),
+            "ADMIN_PORT must not be 0 and must differ from PORT and METRICS_PORT, the admin API is not exposed on the public ports"
+        );
+        anyhow::ensure!(
+            self.tls_cert_path.is_some() == self.tls_key_path.is_some(),
+            "TLS_CERT_PATH and TLS_KEY_PATH must be set together"
+        );
+        anyhow::ensure!(
+            self.max_connections > 0,
+            "MAX_CONNECTIONS must be at least 1, otherwise every connection is rejected"
+        );
+        anyhow::ensure!(
+            self.max_mailbox_capacity >= 2,
+            "MAX_MAILBOX_CAPACITY must be at least 2 (got {}), a mailbox always has room for two peers",
+            self.max_mailbox_capacity
+        );
+        anyhow::ensure!(
+            self.restored_mailbox_ttl_sec > 0,
+            "RESTORED_MAILBOX_TTL_SEC must not be 0, otherwise restored mailboxes expire before anyone can connect to them"
+        );
+        anyhow::ensure!(
+            self.reservation_timeout_sec > 0,
+            "RESERVATION_TIMEOUT_SEC must not be 0, otherwise reserved slots expire before they can be confirmed"
+        );
+        anyhow::ensure!(
+            self.client_idle_timeout_sec == 0 || self.client_pong_timeout_sec > 0,
+            "CLIENT_PONG_TIMEOUT_SEC must not be 0 when CLIENT_IDLE_TIMEOUT_SEC is set, otherwise every idle client is disconnected"
+        );
+        anyhow::ensure!(
+            self.outbound_buffer > self.max_prepairing_messages,
+            "OUTBOUND_BUFFER must be greater than MAX_PREPAIRING_MESSAGES (got {} and {}), \
+             otherwise the messages enqueued for a peer cannot all be delivered when it connects",
+            self.outbound_buffer,
+            self.max_prepairing_messages
+        );
+        if let Some(&id) = self.denied_mailbox_ids.iter().find(|&&id| id >= 1 << 30) {
+            anyhow::bail!("DENIED_MAILBOX_IDS contains {}, which is not a valid (30-bit) mailbox id", id);
+        }
+        Ok(())
+    }
 }
 
 pub fn load() -> Result<ServiceConfig, anyhow::Error> {
-    let raw_config = envy::from_env::<RawConfig>()?;
+    from_raw(envy::from_env::<RawConfig>()?)
+}
+
+fn from_raw(raw_config: RawConfig) -> Result<ServiceConfig, anyhow::Error> {
+    let namespace_limits = raw_config
+        .namespace_limits
+        .iter()
+        .map(|entry| parse_namespace_limits(entry))
+        .collect::<Result<HashMap<_, _>, _>>()?;
 
     let config = ServiceConfig {
+        bind_addr: raw_config.bind_addr,
+        dual_stack: raw_config.dual_stack,
+        listen_backlog: raw_config.listen_backlog,
         port: raw_config.port,
         metrics_port: raw_config.metrics_port,
+        tls_cert_path: raw_config.tls_cert_path,
+        tls_key_path: raw_config.tls_key_path,
+        admin_token: raw_config.admin_token.filter(|token| !token.is_empty()),
+        admin_port: raw_config.admin_port,
+        admin_show_pending_contents: raw_config.admin_show_pending_contents,
+        log_format: raw_config.log_format,
+        flush_before_kill: raw_config.flush_before_kill,
+        max_prepairing_messages: raw_config.max_prepairing_messages,
+        max_prepairing_bytes: raw_config.max_prepairing_bytes,
+        pending_messages_file: raw_config.pending_messages_file,
+        restored_mailbox_ttl_sec: raw_config.restored_mailbox_ttl_sec,
+        max_connections: raw_config.max_connections,
+        max_handshake_connections: raw_config.max_handshake_connections,
+        max_connections_per_ip: raw_config.max_connections_per_ip,
+        trust_forwarded_for: raw_config.trust_forwarded_for,
+        subprotocol_policy: raw_config.subprotocol_policy,
+        max_open_mailboxes: raw_config.max_open_mailboxes,
+        max_mailbox_capacity: raw_config.max_mailbox_capacity,
+        denied_mailbox_ids: raw_config.denied_mailbox_ids,
+        metrics_tenants: raw_config.metrics_tenants,
+        close_policy: raw_config.close_policy,
+        closing_message_policy: raw_config.closing_message_policy,
+        peer_ip_policy: raw_config.peer_ip_policy,
+        require_connect_token: raw_config.require_connect_token,
+        mailbox_id_format: raw_config.mailbox_id_format,
+        id_prefix: raw_config.id_prefix.filter(|prefix| !prefix.is_empty()),
+        id_pool_size: raw_config.id_pool_size,
+        strict_protocol: raw_config.strict_protocol,
+        relay_ping_frames: raw_config.relay_ping_frames,
+        max_creates_per_sec: raw_config.max_creates_per_sec,
+        namespace_limits,
+        drain_timeout_sec: raw_config.drain_timeout_sec,
+        shutdown_notice_batch_size: raw_config.shutdown_notice_batch_size,
+        shutdown_notice_pause_ms: raw_config.shutdown_notice_pause_ms,
+        shutdown_reconnect_after_sec: raw_config.shutdown_reconnect_after_sec,
+        shutdown_kill_batch_size: raw_config.shutdown_kill_batch_size,
+        shutdown_kill_pause_ms: raw_config.shutdown_kill_pause_ms,
+        close_timeout_sec: raw_config.close_timeout_sec,
+        client_idle_timeout_sec: raw_config.client_idle_timeout_sec,
+        client_pong_timeout_sec: raw_config.client_pong_timeout_sec,
+        max_outstanding_pings: raw_config.max_outstanding_pings,
+        reservation_timeout_sec: raw_config.reservation_timeout_sec,
+        mailbox_timeout_sec: raw_config.mailbox_timeout_sec,
+        unpaired_timeout_sec: raw_config.unpaired_timeout_sec,
+        reconnect_grace_sec: raw_config.reconnect_grace_sec,
+        max_pending_bytes: raw_config.max_pending_bytes,
+        peer_slow_threshold: raw_config.peer_slow_threshold,
+        nack_undelivered: raw_config.nack_undelivered,
+        max_outbound_bytes: raw_config.max_outbound_bytes,
+        outbound_buffer: raw_config.outbound_buffer,
+        max_text_bytes: raw_config.max_text_bytes,
+        max_binary_bytes: raw_config.max_binary_bytes,
+        outbound_overflow: raw_config.outbound_overflow,
+        echo_server_time: raw_config.echo_server_time,
+        time_sync_interval_sec: raw_config.time_sync_interval_sec,
     };
 
+    config.validate()?;
+
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Vars<'a> = &'a [(&'a str, &'a str)];
+
+    /// Load the config from the given environment variables instead of the process environment
+    fn load_from(vars: Vars) -> Result<ServiceConfig, anyhow::Error> {
+        from_raw(envy::from_iter(
+            vars.iter().map(|&(key, value)| (key.to_owned(), value.to_owned())),
+        )?)
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = load_from(&[]).unwrap();
+        assert_eq!(config.bind_addr, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(config.listen_backlog, 1024);
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.metrics_port, 9090);
+        assert_eq!(config.admin_port, 9091);
+        assert_eq!(config.mailbox_timeout_sec, 60);
+        assert_eq!(config.restored_mailbox_ttl_sec, 3600);
+    }
+
+    #[test]
+    fn serialized_with_secrets_redacted() {
+        let config = load_from(&[("PORT", "9000"), ("ADMIN_TOKEN", "secret")]).unwrap();
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["port"], 9000);
+        assert_eq!(json["admin_token"], "<redacted>");
+        assert!(!json.to_string().contains("secret"));
+        let json = serde_json::to_value(load_from(&[]).unwrap()).unwrap();
+        assert_eq!(json["admin_token"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn validation_rules() {
+        let cases: &[(Vars, Option<&str>)] = &[
+            (&[("PORT", "0")], Some("PORT must not be 0")),
+            (&[("METRICS_PORT", "0")], Some("METRICS_PORT must not be 0")),
+            (
+                &[("PORT", "9000"), ("METRICS_PORT", "9000")],
+                Some("METRICS_PORT must differ from PORT"),
+            ),
+            (&[("PORT", "9000"), ("METRICS_PORT", "9001")], None),
+            // the admin port only matters when the admin API is enabled
+            (&[("ADMIN_PORT", "0")], None),
+            (&[("ADMIN_PORT", "8080")], None),
+            (&[("ADMIN_TOKEN", "secret"), ("ADMIN_PORT", "0")], Some("ADMIN_PORT must not be 0")),
+            (
+                &[("ADMIN_TOKEN", "secret"), ("ADMIN_PORT", "8080")],
+                Some("ADMIN_PORT must not be 0"),
+            ),
+            (
+                &[("ADMIN_TOKEN", "secret"), ("ADMIN_PORT", "9090")],
+                Some("ADMIN_PORT must not be 0"),
+            ),
+            (&[("ADMIN_TOKEN", "secret"), ("ADMIN_PORT", "9092")], None),
+            (&[("BIND_ADDR", "::")], None),
+            (&[("BIND_ADDR", "127.0.0.1")], None),
+            (&[("BIND_ADDR", "localhost")], Some("invalid IP address syntax")),
+            (&[("DUAL_STACK", "true")], Some("DUAL_STACK requires an IPv6 BIND_ADDR")),
+            (&[("BIND_ADDR", "::"), ("DUAL_STACK", "true")], None),
+            (&[("LISTEN_BACKLOG", "0")], Some("LISTEN_BACKLOG must be at least 1")),
+            (&[("LISTEN_BACKLOG", "65535")], None),
+            // an empty token disables the admin API as well
+            (&[("ADMIN_TOKEN", ""), ("ADMIN_PORT", "8080")], None),
+            (
+                &[("TLS_CERT_PATH", "cert.pem")],
+                Some("TLS_CERT_PATH and TLS_KEY_PATH must be set together"),
+            ),
+            (
+                &[("TLS_KEY_PATH", "key.pem")],
+                Some("TLS_CERT_PATH and TLS_KEY_PATH must be set together"),
+            ),
+            (&[("TLS_CERT_PATH", "cert.pem"), ("TLS_KEY_PATH", "key.pem")], None),
+            (&[("MAX_CONNECTIONS", "0")], Some("MAX_CONNECTIONS must be at least 1")),
+            (&[("MAX_MAILBOX_CAPACITY", "1")], Some("MAX_MAILBOX_CAPACITY must be at least 2")),
+            (&[("MAX_MAILBOX_CAPACITY", "2")], None),
+            (&[("RESTORED_MAILBOX_TTL_SEC", "0")], Some("RESTORED_MAILBOX_TTL_SEC must not be 0")),
+            (&[("RESERVATION_TIMEOUT_SEC", "0")], Some("RESERVATION_TIMEOUT_SEC must not be 0")),
+            (&[("CLIENT_PONG_TIMEOUT_SEC", "0")], None),
+            (
+                &[("CLIENT_IDLE_TIMEOUT_SEC", "30"), ("CLIENT_PONG_TIMEOUT_SEC", "0")],
+                Some("CLIENT_PONG_TIMEOUT_SEC must not be 0"),
+            ),
+            (
+                &[("OUTBOUND_BUFFER", "1000")],
+                Some("OUTBOUND_BUFFER must be greater than MAX_PREPAIRING_MESSAGES"),
+            ),
+            (&[("OUTBOUND_BUFFER", "1001")], None),
+            (
+                &[("DENIED_MAILBOX_IDS", "1,1073741824")],
+                Some("DENIED_MAILBOX_IDS contains 1073741824"),
+            ),
+            (&[("DENIED_MAILBOX_IDS", "1,1073741823")], None),
+        ];
+        for (vars, expected_error) in cases {
+            match (load_from(vars), expected_error) {
+                (Ok(_), None) => {}
+                (Err(err), Some(expected)) => assert!(err.to_string().starts_with(expected), "{:?}: {}", vars, err),
+                (result, _) => panic!("{:?}: unexpected {:?}", vars, result.map(|_| ())),
+            }
+        }
+    }
+}
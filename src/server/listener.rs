@@ -0,0 +1,308 @@
+//! Listening sockets of the server.
+//!
+//! The sockets are built and served by the service itself rather than by warp, so that their socket options
+//! can be configured. The address of the client is passed to the routes with `remote_addr()`.
+
+use std::{
+    convert::Infallible,
+    error::Error as StdError,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{future, stream::FuturesUnordered, StreamExt};
+use hyper::{
+    server::{
+        accept::Accept,
+        conn::{AddrIncoming, AddrStream},
+    },
+    service::{make_service_fn, service_fn, Service},
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    time::Timeout,
+};
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
+use warp::{Filter, Rejection, Reply};
+
+/// Max time for a client to complete the TLS handshake, its connection is dropped afterwards
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+tokio::task_local! {
+    /// Address of the client whose request is being handled
+    static REMOTE_ADDR: SocketAddr;
+}
+
+/// Socket options of the listeners
+#[derive(Clone, Copy, Debug)]
+pub struct ListenerSettings {
+    /// Address all the listeners are bound to
+    pub bind_addr: IpAddr,
+    /// Accept IPv4 connections on an IPv6 bind address as well (`IPV6_V6ONLY` off)
+    pub dual_stack: bool,
+    /// Max number of established connections waiting to be accepted, capped by the host (`net.core.somaxconn`)
+    pub backlog: u32,
+}
+
+impl ListenerSettings {
+    /// Open the listening socket of the port
+    pub fn bind(&self, port: u16) -> Result<AddrIncoming, anyhow::Error> {
+        let addr = SocketAddr::new(self.bind_addr, port);
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if addr.is_ipv6() {
+            // set either way, so that the default of the host (`net.ipv6.bindv6only`) does not matter
+            socket.set_only_v6(!self.dual_stack)?;
+        }
+        // a restarted server can bind the port while the connections of the previous one are still in TIME_WAIT
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog.try_into().unwrap_or(i32::MAX))?;
+        socket.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(socket.into())?;
+        let mut incoming = AddrIncoming::from_listener(listener)?;
+        incoming.set_nodelay(true);
+        Ok(incoming)
+    }
+}
+
+/// TLS settings of a listener from the certificate chain and the private key (PEM)
+pub fn tls_config(cert: &[u8], key: &[u8]) -> Result<Arc<ServerConfig>, anyhow::Error> {
+    let certs = rustls_pemfile::certs(&mut &*cert).map_err(|err| anyhow::anyhow!("can't parse the TLS certificate: {}", err))?;
+    anyhow::ensure!(!certs.is_empty(), "no certificate found in the TLS certificate file");
+    let key = rustls_pemfile::read_all(&mut &*key)
+        .map_err(|err| anyhow::anyhow!("can't parse the TLS key: {}", err))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::PKCS8Key(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("no private key found in the TLS key file"))?;
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs.into_iter().map(Certificate).collect(), PrivateKey(key))
+        .map_err(|err| anyhow::anyhow!("invalid TLS certificate or key: {}", err))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Serve the routes on the listener until the shutdown signal, over TLS if its settings are given.
+/// Like warp's servers, it does not wait for the connections upgraded to websockets, which the server closes itself.
+pub async fn serve<F, R>(incoming: AddrIncoming, routes: F, tls: Option<Arc<ServerConfig>>, shutdown: impl Future<Output = ()>)
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let result = match tls {
+        None => serve_connections(incoming, routes, shutdown).await,
+        Some(config) => {
+            let incoming = TlsIncoming {
+                incoming,
+                acceptor: TlsAcceptor::from(config),
+                handshakes: FuturesUnordered::new(),
+            };
+            serve_connections(incoming, routes, shutdown).await
+        }
+    };
+    if let Err(err) = result {
+        log::error!("Server error: {}", err);
+    }
+}
+
+async fn serve_connections<I, F, R>(incoming: I, routes: F, shutdown: impl Future<Output = ()>) -> Result<(), hyper::Error>
+where
+    I: Accept,
+    I::Conn: Connection + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn StdError + Send + Sync>>,
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |conn: &I::Conn| {
+        let remote_addr = conn.remote_addr();
+        let service = service.clone();
+        future::ok::<_, Infallible>(service_fn(move |request| {
+            let mut service = service.clone();
+            REMOTE_ADDR.scope(remote_addr, service.call(request))
+        }))
+    });
+    hyper::Server::builder(incoming)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+/// Address of the client, `None` outside of the routes served by `serve`
+pub fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::any().map(|| REMOTE_ADDR.try_with(|addr| *addr).ok())
+}
+
+/// Access log of the routes served by `serve` (warp's own log only knows the clients of the listeners it binds itself)
+pub fn access(info: warp::log::Info) {
+    let remote_addr = REMOTE_ADDR.try_with(|addr| addr.to_string()).unwrap_or_else(|_| "-".to_owned());
+    log::info!(
+        "{} \"{} {} {:?}\" {} {:?}",
+        remote_addr,
+        info.method(),
+        info.path(),
+        info.version(),
+        info.status().as_u16(),
+        info.elapsed();
+        "user_agent" => info.user_agent().unwrap_or("-")
+    );
+}
+
+/// Accepted connection of a listener
+trait Connection {
+    /// Address of the client, IPv4 clients of a dual-stack listener have their IPv4 address
+    /// rather than the IPv4-mapped IPv6 one, so that the IP limits and policies treat them as IPv4 clients
+    fn remote_addr(&self) -> SocketAddr;
+}
+
+impl Connection for AddrStream {
+    fn remote_addr(&self) -> SocketAddr {
+        let addr = AddrStream::remote_addr(self);
+        match addr {
+            SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+                Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+                None => addr,
+            },
+            SocketAddr::V4(_) => addr,
+        }
+    }
+}
+
+impl Connection for TlsStream<AddrStream> {
+    fn remote_addr(&self) -> SocketAddr {
+        Connection::remote_addr(self.get_ref().0)
+    }
+}
+
+/// Connections of a listener once their TLS handshakes are done, several handshakes can be in progress at once
+struct TlsIncoming {
+    incoming: AddrIncoming,
+    acceptor: TlsAcceptor,
+    handshakes: FuturesUnordered<Timeout<tokio_rustls::Accept<AddrStream>>>,
+}
+
+impl Accept for TlsIncoming {
+    type Conn = TlsStream<AddrStream>;
+    type Error = io::Error;
+
+    fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        while let Poll::Ready(accepted) = Pin::new(&mut this.incoming).poll_accept(cx) {
+            match accepted {
+                Some(Ok(stream)) => this
+                    .handshakes
+                    .push(tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, this.acceptor.accept(stream))),
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => return Poll::Ready(None),
+            }
+        }
+        loop {
+            match this.handshakes.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(Ok(stream)))) => return Poll::Ready(Some(Ok(stream))),
+                // a failed handshake only drops its own connection
+                Poll::Ready(Some(Ok(Err(err)))) => log::debug!("TLS handshake failed: {}", err),
+                Poll::Ready(Some(Err(_))) => log::debug!("TLS handshake timed out"),
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    /// Serve a route replying with the client address as seen by the routes, returns the listening address
+    fn serve_remote_addr(settings: ListenerSettings) -> SocketAddr {
+        let incoming = settings.bind(0).unwrap();
+        let addr = incoming.local_addr();
+        let route = warp::path::end()
+            .and(remote_addr())
+            .map(|addr: Option<SocketAddr>| format!("{:?}", addr));
+        tokio::spawn(serve(incoming, route, None, future::pending()));
+        addr
+    }
+
+    /// Body of the response to a plain HTTP request sent to the address
+    async fn get(addr: SocketAddr) -> io::Result<String> {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response.split("\r\n\r\n").nth(1).unwrap_or_default().to_owned())
+    }
+
+    #[tokio::test]
+    async fn dual_stack_accepts_ipv4_clients() {
+        let settings = ListenerSettings {
+            bind_addr: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            dual_stack: true,
+            backlog: 16,
+        };
+        let port = serve_remote_addr(settings).port();
+        // the IPv4 client connects to the IPv6 socket as `::ffff:127.0.0.1`, but the routes see its IPv4 address
+        let body = get((Ipv4Addr::LOCALHOST, port).into()).await.unwrap();
+        assert!(body.starts_with("Some(127.0.0.1:"), "{}", body);
+        let body = get((Ipv6Addr::LOCALHOST, port).into()).await.unwrap();
+        assert!(body.starts_with("Some([::1]:"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn ipv6_listener_is_ipv6_only_without_dual_stack() {
+        let settings = ListenerSettings {
+            bind_addr: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            dual_stack: false,
+            backlog: 16,
+        };
+        let port = serve_remote_addr(settings).port();
+        assert!(get((Ipv6Addr::LOCALHOST, port).into()).await.is_ok());
+        let err = get((Ipv4Addr::LOCALHOST, port).into()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn backlog_is_applied() {
+        const BACKLOG: u32 = 2;
+        let settings = ListenerSettings {
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            dual_stack: false,
+            backlog: BACKLOG,
+        };
+        // nothing accepts the connections, so they stay in the queue of the listener
+        let incoming = settings.bind(0).unwrap();
+        let addr = incoming.local_addr();
+        let connect = || tokio::time::timeout(Duration::from_millis(300), TcpStream::connect(addr));
+        let mut queued = Vec::new();
+        // Linux completes the handshakes of one connection more than the backlog
+        for _ in 0..=BACKLOG {
+            queued.push(connect().await.expect("connection not queued").unwrap());
+        }
+        // the handshake of the next one is left unanswered while the queue is full
+        assert!(connect().await.is_err());
+    }
+}
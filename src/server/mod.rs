@@ -1,25 +1,137 @@
 //! Safe-sync Web server.
 
-use std::sync::Arc;
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
-use futures::Future;
-use tokio::sync::{mpsc, oneshot};
-use warp::{ws, Filter};
-use wx_warp::{log::access, MetricsWarpBuilder};
+use futures::{future, Future, FutureExt};
+use prometheus::{core::Collector, Encoder, TextEncoder};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use warp::{
+    http::{
+        header::{CONTENT_TYPE, SEC_WEBSOCKET_PROTOCOL},
+        HeaderValue, StatusCode,
+    },
+    ws, Filter, Rejection, Reply,
+};
 
-use self::websocket::{client::Clients, mailbox::MailboxManager};
-use crate::metrics::{ACTIVE_CLIENTS, CLIENT_CONNECT, CLIENT_DISCONNECT};
+use self::{
+    config::{ServiceConfig, SubprotocolPolicy},
+    listener::{access, ListenerSettings},
+    websocket::{
+        client::{Client, Clients, CloseReason, SendError},
+        connection::{ConnectionSettings, UpgradeInfo},
+        ip_limit::IpConnectionLimiter,
+        mailbox::{MailboxManager, PendingMessages},
+        subprotocol::Negotiation,
+    },
+};
+use crate::metrics::{AllMetrics, AVG_PEERS_PER_MAILBOX, CLIENTS_MAP_SIZE, HTTP_REQUESTS, HTTP_RESPONSE_DURATION_SECONDS, SHUTTING_DOWN};
 
+mod admin;
 pub mod builder;
 pub mod config;
+pub mod listener;
 mod websocket;
 
+/// How often the mailbox statistics metrics are recomputed
+const MAILBOX_STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often graceful shutdown checks whether all clients have finished
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Max time to wait for room in the full outgoing channel of a client to queue the shutdown notice
+const SHUTDOWN_NOTICE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often the pool of pre-generated mailbox IDs is refilled
+const ID_POOL_REFILL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Lower bound of how often mailboxes are checked against the mailbox timeouts and the reconnect grace period
+const MIN_MAILBOX_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Certificate chain and private key (PEM files) of the TLS listener of the main port
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsFiles {
+    /// Contents of the certificate chain and private key files, with an error naming the file which cannot be read
+    fn read(&self) -> Result<(Vec<u8>, Vec<u8>), anyhow::Error> {
+        let read = |path: &Path, what: &str| {
+            fs::read(path).map_err(|err| anyhow::anyhow!("can't read the TLS {} file {}: {}", what, path.display(), err))
+        };
+        Ok((read(&self.cert_path, "certificate")?, read(&self.key_path, "key")?))
+    }
+}
+
 /// The web server
 pub struct Server {
+    /// Address and socket options of all the listeners (main, metrics and admin ports)
+    listener_settings: ListenerSettings,
     port: u16,
     metrics_port: u16,
+    /// Serve the main port over TLS with these files (plain HTTP if not set)
+    tls: Option<TlsFiles>,
+    admin_token: Option<String>,
+    /// Port of the admin API, kept off the public port (only listened on if the admin token is set)
+    admin_port: u16,
+    /// Include the contents of pending messages in the admin API replies (only their sizes otherwise)
+    admin_show_pending_contents: bool,
+    service_config: Option<Arc<ServiceConfig>>,
+    /// When the service process was started
+    started_at: SystemTime,
+    /// Max time `disconnect_all_clients` waits for the disconnected clients to finish
+    drain_timeout: Duration,
+    /// Pacing of the shutdown notices sent by `broadcast_shutdown_notice`
+    shutdown_notice_batching: Batching,
+    /// Time clients are advised to wait before reconnecting, sent in the shutdown notice
+    shutdown_reconnect_after: Duration,
+    /// Pacing of the clients killed by `disconnect_all_clients`
+    shutdown_kill_batching: Batching,
+    connection_settings: ConnectionSettings,
+    /// Limits the number of simultaneously handled websocket connections
+    connection_limit: Arc<Semaphore>,
+    /// Limits the number of websocket connections without a mailbox yet (if set)
+    handshake_limit: Option<Arc<Semaphore>>,
+    /// Limits the number of simultaneous websocket connections from each IP address (if set)
+    ip_limit: Option<Arc<IpConnectionLimiter>>,
+    /// Take the client IP address from the `X-Forwarded-For` header instead of the socket address
+    trust_forwarded_for: bool,
+    /// What happens to a connection offering none of the supported subprotocols
+    subprotocol_policy: SubprotocolPolicy,
     mailbox_manager: MailboxManager,
     clients: Clients,
+    /// Set once graceful shutdown has begun, the readiness probe fails from then on
+    shutting_down: Arc<AtomicBool>,
+}
+
+/// Processing clients in batches of `size` (all at once if 0), with a pause after each batch
+#[derive(Clone, Copy, Debug)]
+pub struct Batching {
+    pub size: usize,
+    pub pause: Duration,
+}
+
+impl Batching {
+    /// Apply the action to each client, batch by batch (the actions of a batch run concurrently)
+    async fn for_each<F: Future<Output = ()>>(self, clients: Vec<Client>, action: impl Fn(Client) -> F) {
+        let batch_size = if self.size > 0 { self.size } else { clients.len().max(1) };
+        for batch in clients.chunks(batch_size) {
+            future::join_all(batch.iter().cloned().map(&action)).await;
+            if !self.pause.is_zero() {
+                tokio::time::sleep(self.pause).await;
+            }
+        }
+    }
 }
 
 impl Server
@@ -27,54 +139,1881 @@ where
     Self: Send + Sync + 'static,
 {
     /// Start the web server.
-    /// Returns the future that runs the web server and a sender that can be used to stop the server.
+    /// Returns the future that runs the web server and a sender that can be used to stop the server,
+    /// or an error if a listener cannot be set up.
     /// The shutdown signal is propagated to each connection handler to terminate them all.
-    pub fn start(self: Arc<Self>, shutdown_signal: mpsc::Sender<()>) -> (impl Future<Output = ()>, oneshot::Sender<()>) {
+    pub fn start(
+        self: Arc<Self>,
+        shutdown_signal: mpsc::Sender<()>,
+    ) -> Result<(impl Future<Output = ()>, oneshot::Sender<()>), anyhow::Error> {
+        let listener_settings = self.listener_settings;
         let port = self.port;
         let metrics_port = self.metrics_port;
-        let with_self = { warp::any().map(move || self.clone()) };
-        let with_shutdown_signal = { warp::any().map(move || shutdown_signal.clone()) };
+        // the admin API is only listened on if it is enabled
+        let admin_port = self.admin_token.as_ref().map(|_| self.admin_port);
+        let tls = match self.tls.as_ref().map(TlsFiles::read).transpose()? {
+            Some((cert, key)) => Some(listener::tls_config(&cert, &key)?),
+            None => None,
+        };
+        let admin = admin::routes(
+            self.admin_token.clone(),
+            self.service_config.clone(),
+            self.started_at,
+            self.admin_show_pending_contents,
+            self.mailbox_manager.clone(),
+            self.clients.clone(),
+        );
+        let mailbox_stats = Self::collect_mailbox_stats(self.mailbox_manager.clone(), self.clients.clone());
+        let id_pool = Self::refill_id_pool(self.mailbox_manager.clone());
+        let mailbox_expiry = Self::close_expired_mailboxes(self.mailbox_manager.clone(), self.clients.clone());
+        let reconnect_expiry =
+            Self::expire_detached_peers(self.mailbox_manager.clone(), self.clients.clone(), self.connection_settings.clone());
+        let shutting_down = self.shutting_down.clone();
+        let ws = self.ws_route(shutdown_signal);
+
+        // liveness: the server loop is running and answering requests
+        let health = warp::path!("health").and(warp::get()).map(|| StatusCode::OK);
+
+        // readiness: the server is accepting connections and is not shutting down,
+        // so that load balancers stop routing new clients to it during the drain
+        let ready = warp::path!("ready").and(warp::get()).map(move || {
+            if shutting_down.load(Ordering::Relaxed) {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::OK
+            }
+        });
 
-        let ws = warp::path("ws")
+        // the probes are frequent, so they are kept out of the access log (but not out of the request metrics)
+        let routes = health
+            .or(ready)
+            .or(ws.with(warp::log::custom(access)))
+            .with(warp::log::custom(record_request_metrics));
+
+        // Signal to stop the servers
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let stop_signal = async {
+            let _ = stop_rx.await;
+            log::trace!("server shutdown signal received");
+        }
+        .shared();
+
+        // every listener is bound to the same address, `BIND_ADDR`, with the same socket options
+        let bind = |port: u16, what: &str| {
+            let incoming = listener_settings
+                .bind(port)
+                .map_err(|err| anyhow::anyhow!("can't start the {} on port {}: {}", what, port, err))?;
+            log::info!("Serving the {} on {}", what, incoming.local_addr());
+            Ok::<_, anyhow::Error>(incoming)
+        };
+
+        // the admin API has its own plain HTTP port, so that it is never exposed along with the websocket service
+        let admin_server = match admin_port {
+            Some(admin_port) => {
+                let incoming = bind(admin_port, "admin API")?;
+                Some(listener::serve(
+                    incoming,
+                    admin.with(warp::log::custom(access)),
+                    None,
+                    stop_signal.clone(),
+                ))
+            }
+            None => None,
+        };
+
+        let service = if tls.is_some() {
+            "websocket service (TLS)"
+        } else {
+            "websocket service"
+        };
+        let incoming = bind(port, service)?;
+        let main_server = listener::serve(incoming, routes, tls, stop_signal.clone());
+
+        // the metrics port stays plain HTTP
+        let incoming = bind(metrics_port, "metrics")?;
+        let metrics_server = listener::serve(incoming, metrics_route(), None, stop_signal);
+
+        let servers = future::join3(main_server, metrics_server, async move {
+            if let Some(admin_server) = admin_server {
+                admin_server.await;
+            }
+        });
+
+        // Background tasks run as long as the servers are running
+        let servers = async move {
+            tokio::select! {
+                _ = servers => {}
+                _ = mailbox_stats => {}
+                _ = id_pool => {}
+                _ = mailbox_expiry => {}
+                _ = reconnect_expiry => {}
+            }
+        };
+
+        Ok((servers, stop_tx))
+    }
+
+    /// Websocket route: upgrades the connection and serves it until it ends or the shutdown signal is received
+    fn ws_route(self: Arc<Self>, shutdown_signal: mpsc::Sender<()>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        let with_self = warp::any().map(move || self.clone());
+        let with_shutdown_signal = warp::any().map(move || shutdown_signal.clone());
+
+        warp::path("ws")
             .and(warp::path::end())
             .and(warp::ws())
+            .and(listener::remote_addr())
+            .and(warp::header::optional::<String>("x-forwarded-for"))
+            .and(warp::header::optional::<String>("sec-websocket-protocol"))
             .and(with_self)
             .and(with_shutdown_signal)
-            .map(|ws: ws::Ws, server: Arc<Self>, shutdown_signal| {
-                let mailbox_manager = server.mailbox_manager.clone();
-                let clients = server.clients.clone();
-                ws.on_upgrade(move |socket| websocket::connection::handle_connection(socket, mailbox_manager, clients, shutdown_signal))
-            })
-            .with(warp::log::custom(access));
+            .map(
+                |ws: ws::Ws,
+                 remote_addr: Option<SocketAddr>,
+                 forwarded_for: Option<String>,
+                 subprotocols: Option<String>,
+                 server: Arc<Self>,
+                 shutdown_signal| {
+                    let negotiation = Negotiation::new(subprotocols.as_deref(), server.subprotocol_policy);
+                    let protocol_version = match negotiation.version() {
+                        Some(version) => version,
+                        None => {
+                            log::debug!("Connection rejected: no supported subprotocol in {:?}", subprotocols);
+                            return StatusCode::BAD_REQUEST.into_response();
+                        }
+                    };
+                    let remote_ip = if server.trust_forwarded_for {
+                        forwarded_for.as_deref().and_then(forwarded_client_ip)
+                    } else {
+                        remote_addr.map(|addr| addr.ip())
+                    };
+                    // connections from an unknown address are only subject to the other limits
+                    let ip_guard = match (&server.ip_limit, remote_ip) {
+                        (Some(ip_limit), Some(ip)) => match ip_limit.try_acquire(ip) {
+                            Some(guard) => Some(guard),
+                            None => {
+                                log::debug!("Connection rejected: too many connections from {}", ip);
+                                return StatusCode::TOO_MANY_REQUESTS.into_response();
+                            }
+                        },
+                        _ => None,
+                    };
+                    let permit = match server.connection_limit.clone().try_acquire_owned() {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            log::debug!("Connection rejected: too many connections");
+                            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                        }
+                    };
+                    let handshake_permit = match server.handshake_limit.clone().map(Semaphore::try_acquire_owned).transpose() {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            log::debug!("Connection rejected: too many connections in the handshake state");
+                            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                        }
+                    };
+                    let mailbox_manager = server.mailbox_manager.clone();
+                    let clients = server.clients.clone();
+                    let settings = server.connection_settings.clone();
+                    let upgrade_info = UpgradeInfo {
+                        remote_ip,
+                        protocol_version,
+                    };
+                    let upgrade = ws.on_upgrade(move |socket| async move {
+                        websocket::connection::handle_connection(
+                            socket,
+                            upgrade_info,
+                            handshake_permit,
+                            mailbox_manager,
+                            clients,
+                            settings,
+                            shutdown_signal,
+                        )
+                        .await;
+                        drop(permit);
+                        drop(ip_guard);
+                    });
+                    let mut response = upgrade.into_response();
+                    // the selected subprotocol is echoed back, a client offering none of them is not sent any
+                    if let Negotiation::Selected(version) = negotiation {
+                        response
+                            .headers_mut()
+                            .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(version.name()));
+                    }
+                    response
+                },
+            )
+    }
 
-        // Signal to stop the server
-        let (stop_tx, stop_rx) = oneshot::channel();
+    /// Periodically update the metrics derived from the state of all mailboxes and clients
+    async fn collect_mailbox_stats(mailbox_manager: MailboxManager, clients: Clients) {
+        let mut interval = tokio::time::interval(MAILBOX_STATS_INTERVAL);
+        loop {
+            interval.tick().await;
+            Self::update_mailbox_stats(&mailbox_manager, &clients);
+        }
+    }
 
-        let servers = MetricsWarpBuilder::new()
-            .with_main_routes(ws)
-            .with_main_routes_port(port)
-            .with_metrics_port(metrics_port)
-            .with_metric(&*ACTIVE_CLIENTS)
-            .with_metric(&*CLIENT_CONNECT)
-            .with_metric(&*CLIENT_DISCONNECT)
-            .with_graceful_shutdown(async {
-                let _ = stop_rx.await;
-                log::trace!("server shutdown signal received");
-            })
-            .run_async();
+    /// Recompute the metrics derived from the state of all mailboxes and clients
+    fn update_mailbox_stats(mailbox_manager: &MailboxManager, clients: &Clients) {
+        AVG_PEERS_PER_MAILBOX.set(mailbox_manager.average_peers());
+        CLIENTS_MAP_SIZE.set(clients.count() as i64);
+    }
 
-        (servers, stop_tx)
+    /// Periodically close mailboxes idle for longer than the timeout for their number of peers, disconnecting their clients
+    async fn close_expired_mailboxes(mailbox_manager: MailboxManager, clients: Clients) {
+        let shortest_timeout = match mailbox_manager.configured_timeouts().into_iter().min() {
+            Some(timeout) => timeout,
+            None => return futures::future::pending().await,
+        };
+        // often enough to close mailboxes soon after they expire, without spinning on short timeouts
+        let mut interval = tokio::time::interval((shortest_timeout / 4).max(MIN_MAILBOX_EXPIRY_INTERVAL));
+        loop {
+            interval.tick().await;
+            for (mailbox_id, peers) in mailbox_manager.close_expired_mailboxes() {
+                log::debug!("{:?} has expired, disconnecting its {} peers", mailbox_id, peers.len());
+                for client in peers.into_iter().filter_map(|client_id| clients.find(client_id)) {
+                    client.kill(CloseReason::MailboxExpired);
+                }
+            }
+        }
     }
 
-    /// Gracefully kill all connected websocket clients
+    /// Periodically free the mailbox slots held for clients which have not reconnected in time, notifying the other peers
+    async fn expire_detached_peers(mailbox_manager: MailboxManager, clients: Clients, settings: ConnectionSettings) {
+        let grace = match mailbox_manager.reconnect_grace() {
+            Some(grace) => grace,
+            None => return futures::future::pending().await,
+        };
+        let mut interval = tokio::time::interval((grace / 4).max(MIN_MAILBOX_EXPIRY_INTERVAL));
+        loop {
+            interval.tick().await;
+            for (mailbox_id, departure) in mailbox_manager.expire_detached_peers() {
+                websocket::connection::handle_departure(mailbox_id, departure, &clients, &settings);
+            }
+        }
+    }
+
+    /// Periodically top up the pool of pre-generated mailbox IDs
+    async fn refill_id_pool(mailbox_manager: MailboxManager) {
+        let mut interval = tokio::time::interval(ID_POOL_REFILL_INTERVAL);
+        loop {
+            interval.tick().await;
+            mailbox_manager.refill_id_pool();
+        }
+    }
+
+    /// Mark the server as shutting down, making the readiness probe fail and setting the `Shutting_Down` gauge
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        SHUTTING_DOWN.set(1);
+    }
+
+    /// Save messages pending for peers which are not connected yet to a file, so that they can be restored after restart
+    pub fn save_pending_messages(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let pending = self.mailbox_manager.export_pending_messages();
+        log::info!("Saving pending messages of {} mailboxes to {}", pending.len(), path.display());
+        let json = serde_json::to_vec(&pending)?;
+        fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// Restore messages saved by `save_pending_messages` (if the file exists) and remove the file
+    pub fn load_pending_messages(&self, path: &Path) -> Result<(), anyhow::Error> {
+        if !path.exists() {
+            return Ok(());
+        }
+        let json = fs::read(path)?;
+        let pending: Vec<PendingMessages> = serde_json::from_slice(&json)?;
+        log::info!("Restoring pending messages of {} mailboxes from {}", pending.len(), path.display());
+        self.mailbox_manager.import_pending_messages(pending);
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    /// Notify all connected websocket clients that the server is shutting down.
+    /// The notice is queued before the clients are killed, so it is delivered by the drain of their queues.
+    pub async fn broadcast_shutdown_notice(&self) {
+        let notice = websocket::connection::shutdown_notice(self.drain_timeout, self.shutdown_reconnect_after);
+        let clients = self.clients.all();
+        log::info!("Notifying {} connected clients about shutdown", clients.len());
+        let send_notice = |client: Client| {
+            let notice = notice.clone();
+            async move {
+                match client.send_message_waiting(notice, SHUTDOWN_NOTICE_TIMEOUT).await {
+                    Ok(()) => {}
+                    Err(SendError::Full) => log::debug!("Send shutdown notice to {:?} failed - too slow to receive it", client.id),
+                    Err(SendError::Closed) => log::debug!("Send shutdown notice to {:?} failed - disconnected early?", client.id),
+                }
+            }
+        };
+        self.shutdown_notice_batching.for_each(clients, send_notice).await;
+    }
+
+    /// Gracefully kill all connected websocket clients and wait (up to the drain timeout) for them to finish
     pub async fn disconnect_all_clients(&self) {
         let clients_to_kill = self.clients.all();
         let client_count = clients_to_kill.len();
         log::info!("About to kill {} connected clients", client_count);
-        for client in clients_to_kill {
+        let kill = |client: Client| async move {
             log::trace!("Gracefully killing {:?}", client.id);
-            client.kill();
-            tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+            client.drain_and_kill(CloseReason::ServerShutdown);
+        };
+        self.shutdown_kill_batching.for_each(clients_to_kill, kill).await;
+
+        // killed clients may still be flushing their queues, wait for them but no longer than the drain timeout
+        let deadline = tokio::time::Instant::now() + self.drain_timeout;
+        while self.clients.count() > 0 {
+            if tokio::time::Instant::now() >= deadline {
+                log::warn!(
+                    "Drain timeout of {:?} has passed, abandoning {} clients",
+                    self.drain_timeout,
+                    self.clients.count()
+                );
+                break;
+            }
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Metrics of the service in the Prometheus text format, served on the metrics port
+fn metrics_route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("metrics").and(warp::get()).map(|| {
+        let encoder = TextEncoder::new();
+        let mut body = Vec::new();
+        // labeled metrics have no samples until their first label values are used, and the encoder rejects them
+        let metrics = AllMetrics.collect().into_iter().filter(|family| !family.get_metric().is_empty());
+        match encoder.encode(&metrics.collect::<Vec<_>>(), &mut body) {
+            Ok(()) => warp::reply::with_header(body, CONTENT_TYPE, encoder.format_type()).into_response(),
+            Err(err) => {
+                log::error!("Failed to encode metrics: {}", err);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    })
+}
+
+/// Count the request to the main port and observe its response time, by response status
+fn record_request_metrics(info: warp::log::Info) {
+    let status = info.status();
+    HTTP_REQUESTS.with_label_values(&[status.as_str()]).inc();
+    HTTP_RESPONSE_DURATION_SECONDS
+        .with_label_values(&[status.as_str()])
+        .observe(info.elapsed().as_secs_f64());
+}
+
+/// Client IP address from the `X-Forwarded-For` header: the last address in the list, which is added
+/// by the reverse proxy itself (the addresses before it are sent by the client and cannot be trusted)
+fn forwarded_client_ip(forwarded_for: &str) -> Option<IpAddr> {
+    forwarded_for.rsplit(',').next()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Instant};
+
+    use futures::{SinkExt, StreamExt};
+    use prometheus::core::Metric;
+    use serde_json::{json, Value};
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+    use tokio_tungstenite::{
+        tungstenite::{self, client::IntoClientRequest, Message},
+        MaybeTlsStream, WebSocketStream,
+    };
+
+    use super::{
+        builder::ServerBuilder,
+        websocket::{
+            client::ClientId,
+            transform::testing::{RejectContaining, Uppercase},
+        },
+        *,
+    };
+    use crate::metrics::{
+        CLIENT_HANDSHAKE_ABORTED, CONNECTION_DURATION_SECONDS, MESSAGES_DROPPED_CLOSING, RELAY_LATENCY_SECONDS, TENANT_ACTIVE_CLIENTS,
+        TENANT_CLIENT_CONNECT,
+    };
+
+    type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    const ADMIN_TOKEN: &str = "secret";
+
+    /// Serve the websocket route of the server on an ephemeral local port.
+    /// Connections are terminated as on server shutdown once the returned receiver is dropped.
+    fn serve(server: Server) -> (SocketAddr, Arc<Server>, mpsc::Receiver<()>) {
+        let server = Arc::new(server);
+        let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+        let settings = ListenerSettings {
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            dual_stack: false,
+            backlog: 128,
+        };
+        let incoming = settings.bind(0).unwrap();
+        let addr = incoming.local_addr();
+        tokio::spawn(listener::serve(
+            incoming,
+            server.clone().ws_route(shutdown_tx),
+            None,
+            future::pending(),
+        ));
+        (addr, server, shutdown_rx)
+    }
+
+    async fn connect(addr: SocketAddr) -> Socket {
+        connect_with(addr, None).await.unwrap().0
+    }
+
+    /// Connect offering the given subprotocols, returns the socket and the subprotocol selected by the server
+    async fn connect_with(
+        addr: SocketAddr,
+        subprotocols: Option<&str>,
+    ) -> Result<(Socket, Option<String>), tokio_tungstenite::tungstenite::Error> {
+        let mut request = format!("ws://{}/ws", addr).into_client_request()?;
+        if let Some(subprotocols) = subprotocols {
+            request
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_str(subprotocols).unwrap());
+        }
+        let (socket, response) = tokio_tungstenite::connect_async(request).await?;
+        let selected = response
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .map(|name| name.to_str().unwrap().to_owned());
+        Ok((socket, selected))
+    }
+
+    /// Create a mailbox and connect a second client to it, both speaking the given protocol version
+    async fn pair(addr: SocketAddr, subprotocol: Option<&str>) -> (Socket, Socket) {
+        let (mut creator, _) = connect_with(addr, subprotocol).await.unwrap();
+        let created = request(&mut creator, json!({ "req": "create" })).await;
+        let (mut joiner, _) = connect_with(addr, subprotocol).await.unwrap();
+        let connected = request(&mut joiner, json!({ "req": "connect", "id": created["id"] })).await;
+        assert_eq!(connected["resp"], "connected");
+        (creator, joiner)
+    }
+
+    /// The next message received, skipping the notices of the server about the other peers
+    async fn next_message(socket: &mut Socket) -> Message {
+        loop {
+            match socket.next().await {
+                Some(Ok(Message::Text(text))) if text.contains("\"peer_") => continue,
+                Some(Ok(msg)) => return msg,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    /// Send the JSON request and wait for the reply
+    async fn request(socket: &mut Socket, request: Value) -> Value {
+        socket.send(Message::Text(request.to_string())).await.unwrap();
+        match socket.next().await {
+            Some(Ok(Message::Text(reply))) => serde_json::from_str(&reply).unwrap(),
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+
+    /// Wait for the notice from the server, skipping the messages before it
+    async fn expect_notice(socket: &mut Socket, resp: &str) {
+        loop {
+            match socket.next().await {
+                Some(Ok(Message::Text(text))) if serde_json::from_str::<Value>(&text).unwrap()["resp"] == resp => return,
+                Some(Ok(_)) => continue,
+                other => panic!("unexpected {:?} instead of {}", other, resp),
+            }
+        }
+    }
+
+    /// Wait until the connections closed by the clients are torn down by the server
+    async fn wait_for_clients(server: &Server, count: usize) {
+        for _ in 0..100 {
+            if server.clients.count() == count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} clients connected instead of {}", server.clients.count(), count);
+    }
+
+    /// Opcodes and payloads of all the frames sent by the server until it closes the connection,
+    /// read from the TCP stream itself (the websocket library would answer pings and close frames while reading them)
+    async fn raw_frames(socket: &mut Socket) -> Vec<(u8, Vec<u8>)> {
+        let mut received = Vec::new();
+        match socket.get_mut() {
+            MaybeTlsStream::Plain(stream) => stream.read_to_end(&mut received).await.unwrap(),
+            _ => unreachable!("plain connection"),
+        };
+        // the frames sent by the server are unmasked: opcode, payload length (extended to 16 bits above 125), payload
+        let mut frames = Vec::new();
+        let mut rest = received.as_slice();
+        while let [first, len, tail @ ..] = rest {
+            let (len, tail) = match *len {
+                126 => (usize::from(u16::from_be_bytes([tail[0], tail[1]])), &tail[2..]),
+                len => (usize::from(len), tail),
+            };
+            frames.push((first & 0x0F, tail[..len].to_vec()));
+            rest = &tail[len..];
+        }
+        frames
+    }
+
+    /// Close codes of the close frames among the raw frames
+    fn close_codes(frames: &[(u8, Vec<u8>)]) -> Vec<u16> {
+        let close_frames = frames.iter().filter(|(opcode, _)| *opcode == 0x8);
+        close_frames
+            .map(|(_, payload)| u16::from_be_bytes([payload[0], payload[1]]))
+            .collect()
+    }
+
+    /// Code and reason of the close frame sent by the server, skipping the messages before it
+    async fn close_frame(socket: &mut Socket) -> (u16, String) {
+        while let Some(msg) = socket.next().await {
+            if let Message::Close(Some(frame)) = msg.unwrap() {
+                return (frame.code.into(), frame.reason.into_owned());
+            }
+        }
+        panic!("connection closed without a close frame");
+    }
+
+    /// Send the request to the admin API of the server
+    async fn admin_request(server: &Server, method: &str, path: &str) -> StatusCode {
+        let admin = admin::routes(
+            Some(ADMIN_TOKEN.to_owned()),
+            None,
+            SystemTime::now(),
+            false,
+            server.mailbox_manager.clone(),
+            server.clients.clone(),
+        );
+        let reply = warp::test::request()
+            .method(method)
+            .path(path)
+            .header("authorization", format!("Bearer {}", ADMIN_TOKEN))
+            .reply(&admin)
+            .await;
+        reply.status()
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_before_every_labeled_metric_is_used() {
+        let reply = warp::test::request().path("/metrics").reply(&metrics_route()).await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        assert!(String::from_utf8_lossy(reply.body()).contains("Active_Clients_Count"));
+    }
+
+    #[tokio::test]
+    async fn subprotocol_header() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let cases = [
+            (None, None, "safesync.v1"),
+            (Some("safesync.v3"), None, "safesync.v1"),
+            (Some("safesync.v1"), Some("safesync.v1"), "safesync.v1"),
+            (Some("safesync.v3, safesync.v2, safesync.v1"), Some("safesync.v2"), "safesync.v2"),
+        ];
+        for (offered, echoed, spoken) in cases {
+            let (mut socket, selected) = connect_with(addr, offered).await.unwrap();
+            assert_eq!(selected.as_deref(), echoed, "{:?}", offered);
+            let whoami = request(&mut socket, json!({ "req": "whoami" })).await;
+            assert_eq!(whoami["protocol"], spoken, "{:?}", offered);
+        }
+    }
+
+    #[tokio::test]
+    async fn whoami_reflects_the_mailbox() {
+        let (addr, server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let (mut creator, _) = connect_with(addr, Some("safesync.v2")).await.unwrap();
+        let whoami = request(&mut creator, json!({ "req": "whoami" })).await;
+        assert_eq!(whoami["mailbox_id"], Value::Null);
+        let client_id = ClientId::from_raw(whoami["client_id"].as_u64().unwrap());
+        assert!(server.clients.find(client_id).is_some());
+
+        let created = request(&mut creator, json!({ "req": "create" })).await;
+        let whoami = request(&mut creator, json!({ "req": "whoami" })).await;
+        assert_eq!(whoami["client_id"], client_id.raw());
+        assert_eq!(whoami["mailbox_id"], created["id"]);
+        let (mut joiner, _) = connect_with(addr, Some("safesync.v2")).await.unwrap();
+        request(&mut joiner, json!({ "req": "connect", "id": created["id"] })).await;
+        let whoami = request(&mut joiner, json!({ "req": "whoami" })).await;
+        assert_ne!(whoami["client_id"], client_id.raw());
+        assert_eq!(whoami["mailbox_id"], created["id"]);
+    }
+
+    #[tokio::test]
+    async fn strict_subprotocol_policy() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .subprotocol_policy(SubprotocolPolicy::Strict)
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        for offered in [None, Some("safesync.v3")] {
+            match connect_with(addr, offered).await {
+                Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                    assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{:?}", offered)
+                }
+                other => panic!("{:?}: unexpected {:?}", offered, other.map(|(_, selected)| selected)),
+            }
+        }
+        let (_, selected) = connect_with(addr, Some("safesync.v3, safesync.v1")).await.unwrap();
+        assert_eq!(selected.as_deref(), Some("safesync.v1"));
+    }
+
+    #[tokio::test]
+    async fn connections_are_limited() {
+        let server = ServerBuilder::new().port(0).metrics_port(0).max_connections(2).build().new_server();
+        let (addr, server, _shutdown) = serve(server);
+        let first = connect(addr).await;
+        let _second = connect(addr).await;
+        match connect_with(addr, None).await {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE),
+            other => panic!("connection accepted: {:?}", other.map(|(_, subprotocol)| subprotocol)),
+        }
+
+        // the slot is released once the connection is torn down
+        drop(first);
+        wait_for_clients(&server, 1).await;
+        let _ = connect(addr).await;
+    }
+
+    #[tokio::test]
+    async fn handshake_connections_are_limited() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .max_handshake_connections(2)
+            .build()
+            .new_server();
+        let (addr, server, _shutdown) = serve(server);
+        let rejected = |result: Result<_, tungstenite::Error>| match result {
+            Err(tungstenite::Error::Http(response)) => response.status() == StatusCode::SERVICE_UNAVAILABLE,
+            _ => false,
+        };
+        let mut creator = connect(addr).await;
+        let idle = connect(addr).await;
+        assert!(rejected(connect_with(addr, None).await));
+
+        // a connection leaves the handshake state once it has a mailbox, or when it is closed
+        let created = request(&mut creator, json!({ "req": "create" })).await;
+        let mut joiner = connect(addr).await;
+        assert!(rejected(connect_with(addr, None).await));
+        drop(idle);
+        wait_for_clients(&server, 2).await;
+        let mut other = connect(addr).await;
+        assert!(rejected(connect_with(addr, None).await));
+        let connected = request(&mut joiner, json!({ "req": "connect", "id": created["id"] })).await;
+        assert_eq!(connected["resp"], "connected");
+        let _ = request(&mut other, json!({ "req": "create" })).await;
+        let _ = connect(addr).await;
+    }
+
+    #[tokio::test]
+    async fn connect_tokens() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .require_connect_token(true)
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let mut creator = connect(addr).await;
+        let created = request(&mut creator, json!({ "req": "create" })).await;
+        let token = created["token"].as_str().unwrap();
+        for wrong_token in [json!(null), json!("wrong")] {
+            let mut joiner = connect(addr).await;
+            let reply = request(&mut joiner, json!({ "req": "connect", "id": created["id"], "token": wrong_token })).await;
+            assert_eq!(reply["code"], "unauthorized");
+        }
+        let mut joiner = connect(addr).await;
+        let reply = request(&mut joiner, json!({ "req": "connect", "id": created["id"], "token": token })).await;
+        assert_eq!(reply["resp"], "connected");
+
+        // without REQUIRE_CONNECT_TOKEN no token is issued nor checked
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let mut creator = connect(addr).await;
+        let created = request(&mut creator, json!({ "req": "create" })).await;
+        assert_eq!(created.get("token"), None);
+        let mut joiner = connect(addr).await;
+        let reply = request(&mut joiner, json!({ "req": "connect", "id": created["id"] })).await;
+        assert_eq!(reply["resp"], "connected");
+    }
+
+    #[tokio::test]
+    async fn recoverable_errors_keep_connection_open() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let mut creator = connect(addr).await;
+        let created = request(&mut creator, json!({ "req": "create" })).await;
+        let mut joiner = connect(addr).await;
+        let _ = request(&mut joiner, json!({ "req": "connect", "id": created["id"] })).await;
+
+        let mut client = connect(addr).await;
+        let reply = request(&mut client, json!({ "req": "connect", "id": 12345 })).await;
+        assert_eq!(reply["code"], "not_found");
+        let reply = request(&mut client, json!({ "req": "connect", "id": created["id"] })).await;
+        assert_eq!(reply["code"], "busy");
+        let reply = request(&mut client, json!({ "req": "reserve", "id": created["id"] })).await;
+        assert_eq!(reply["code"], "busy");
+        let reply = request(&mut client, json!({ "req": "create" })).await;
+        assert_eq!(reply["resp"], "created");
+    }
+
+    #[tokio::test]
+    async fn binary_frames_are_relayed_only_after_handshake() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let mut creator = connect(addr).await;
+        creator.send(Message::Binary(br#"{"req":"create"}"#.to_vec())).await.unwrap();
+        let reply: Value = match creator.next().await {
+            Some(Ok(Message::Text(reply))) => serde_json::from_str(&reply).unwrap(),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert_eq!(reply["code"], "bad_request");
+        assert!(reply["message"].as_str().unwrap().contains("JSON text message"));
+
+        let created = request(&mut creator, json!({ "req": "create" })).await;
+        let mut joiner = connect(addr).await;
+        assert_eq!(
+            request(&mut joiner, json!({ "req": "connect", "id": created["id"] })).await["resp"],
+            "connected"
+        );
+        let payload = vec![0, 0xff, 0xfe, b'{', 0x80];
+        creator.send(Message::Binary(payload.clone())).await.unwrap();
+        assert_eq!(next_message(&mut joiner).await, Message::Binary(payload));
+    }
+
+    #[tokio::test]
+    async fn split_handshake_is_reported() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let mut client = connect(addr).await;
+
+        client.send(Message::Text(r#"{"req":"cre"#.to_owned())).await.unwrap();
+        let reply: Value = match client.next().await {
+            Some(Ok(Message::Text(reply))) => serde_json::from_str(&reply).unwrap(),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert_eq!(reply["code"], "bad_request");
+        assert!(reply["message"].as_str().unwrap().contains("single websocket message"));
+
+        client.send(Message::Text(r#"{"req":}"#.to_owned())).await.unwrap();
+        let reply: Value = match client.next().await {
+            Some(Ok(Message::Text(reply))) => serde_json::from_str(&reply).unwrap(),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert_eq!(reply["code"], "bad_request");
+        assert!(!reply["message"].as_str().unwrap().contains("single websocket message"));
+    }
+
+    #[tokio::test]
+    async fn clients_map_follows_connections() {
+        let (addr, server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let map_size = || {
+            Server::update_mailbox_stats(&server.mailbox_manager, &server.clients);
+            CLIENTS_MAP_SIZE.get()
+        };
+        // the Active_Clients_Count gauge is shared by the servers of all the tests, so the map of this server is checked
+        // against the connections of this test, whichever way they end
+        for _ in 0..3 {
+            let (mut creator, joiner) = pair(addr, Some("safesync.v2")).await;
+            let idle = connect(addr).await;
+            wait_for_clients(&server, 3).await;
+            assert_eq!(map_size(), 3);
+            drop(idle);
+            wait_for_clients(&server, 2).await;
+            // the joiner is disconnected by the server once the creator has left
+            creator.close(None).await.unwrap();
+            drop(joiner);
+            wait_for_clients(&server, 0).await;
+            assert_eq!(map_size(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_duration_is_observed() {
+        let (addr, server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        // count of the connections which lasted less than the smallest bucket bound (1 second)
+        let shortest = || CONNECTION_DURATION_SECONDS.metric().get_histogram().get_bucket()[0].get_cumulative_count();
+        let observed = shortest();
+        let socket = connect(addr).await;
+        wait_for_clients(&server, 1).await;
+        drop(socket);
+        wait_for_clients(&server, 0).await;
+        // other tests disconnect clients at the same time, so the count is only known to have grown
+        assert!(shortest() > observed);
+    }
+
+    #[tokio::test]
+    async fn close_before_handshake_is_counted() {
+        let (addr, server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let aborted = CLIENT_HANDSHAKE_ABORTED.get();
+        let mut socket = connect(addr).await;
+        wait_for_clients(&server, 1).await;
+        socket.close(None).await.unwrap();
+
+        wait_for_clients(&server, 0).await;
+        assert!(CLIENT_HANDSHAKE_ABORTED.get() > aborted);
+        assert_eq!(serde_json::to_value(server.mailbox_manager.snapshot()).unwrap(), json!([]));
+    }
+
+    #[tokio::test]
+    async fn clients_are_counted_by_tenant() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .metrics_tenants(vec!["tenant_a".to_owned(), "tenant_b".to_owned()])
+            .build()
+            .new_server();
+        let (addr, server, _shutdown) = serve(server);
+        let mut creator = connect(addr).await;
+        let created = request(&mut creator, json!({ "req": "create", "tenant": "tenant_a" })).await;
+        let mut joiner = connect(addr).await;
+        let connect_request = json!({ "req": "connect", "id": created["id"], "tenant": "tenant_b" });
+        assert_eq!(request(&mut joiner, connect_request).await["resp"], "connected");
+        for tenant in ["tenant_a", "tenant_b"] {
+            assert_eq!(TENANT_CLIENT_CONNECT.with_label_values(&[tenant]).get(), 1.0, "{}", tenant);
+            assert_eq!(TENANT_ACTIVE_CLIENTS.with_label_values(&[tenant]).get(), 1, "{}", tenant);
+        }
+
+        drop(creator);
+        drop(joiner);
+        wait_for_clients(&server, 0).await;
+        for tenant in ["tenant_a", "tenant_b"] {
+            assert_eq!(TENANT_CLIENT_CONNECT.with_label_values(&[tenant]).get(), 1.0, "{}", tenant);
+            assert_eq!(TENANT_ACTIVE_CLIENTS.with_label_values(&[tenant]).get(), 0, "{}", tenant);
+        }
+    }
+
+    #[tokio::test]
+    async fn id_formats_round_trip_through_connect() {
+        for id_format in [config::IdFormat::Numeric, config::IdFormat::Alphanumeric] {
+            let server = ServerBuilder::new()
+                .port(0)
+                .metrics_port(0)
+                .mailbox_id_format(id_format)
+                .build()
+                .new_server();
+            let (addr, _server, _shutdown) = serve(server);
+            let mut creator = connect(addr).await;
+            let created = request(&mut creator, json!({ "req": "create" })).await;
+            let id = &created["id"];
+            match id_format {
+                config::IdFormat::Numeric => assert!(id.is_u64(), "{}", id),
+                config::IdFormat::Alphanumeric => assert!(id.as_str().unwrap().chars().all(|c| c.is_ascii_alphanumeric()), "{}", id),
+            }
+            let mut joiner = connect(addr).await;
+            let connected = request(&mut joiner, json!({ "req": "connect", "id": id })).await;
+            assert_eq!(connected["resp"], "connected", "{}", id);
+        }
+    }
+
+    #[tokio::test]
+    async fn id_from_another_environment_is_rejected() {
+        let staging = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .id_prefix(Some("stg".to_owned()))
+            .build()
+            .new_server();
+        let (staging, _staging, _staging_shutdown) = serve(staging);
+        let (production, _production, _production_shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+
+        let mut creator = connect(staging).await;
+        let created = request(&mut creator, json!({ "req": "create" })).await;
+        let staging_id = created["id"].as_str().unwrap().to_owned();
+        assert!(staging_id.starts_with("stg-"), "{}", staging_id);
+        let mut creator = connect(production).await;
+        let created = request(&mut creator, json!({ "req": "create" })).await;
+        let production_id = created["id"].clone();
+
+        let mut client = connect(production).await;
+        let reply = request(&mut client, json!({ "req": "connect", "id": staging_id })).await;
+        assert_eq!(reply["code"], "bad_request");
+        assert!(reply["message"].as_str().unwrap().contains("another environment"));
+
+        let mut client = connect(staging).await;
+        for id in [production_id, json!(format!("prd-{}", &staging_id[4..]))] {
+            let reply = request(&mut client, json!({ "req": "connect", "id": id })).await;
+            assert_eq!(reply["code"], "bad_request", "{}", id);
+            assert!(reply["message"].as_str().unwrap().contains("another environment"));
         }
+        let reply = request(&mut client, json!({ "req": "connect", "id": staging_id })).await;
+        assert_eq!(reply["resp"], "connected");
+    }
+
+    #[tokio::test]
+    async fn usage_counts_relayed_bytes() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let (mut creator, mut joiner) = pair(addr, Some("safesync.v2")).await;
+        creator.send(Message::Text("hello".to_owned())).await.unwrap();
+        creator.send(Message::Binary(vec![0; 10])).await.unwrap();
+        assert_eq!(next_message(&mut joiner).await, Message::Text("hello".to_owned()));
+        assert_eq!(next_message(&mut joiner).await, Message::Binary(vec![0; 10]));
+        joiner.send(Message::Text("abc".to_owned())).await.unwrap();
+        assert_eq!(next_message(&mut creator).await, Message::Text("abc".to_owned()));
+
+        let usage = json!({ "req": "usage" });
+        assert_eq!(
+            request(&mut creator, usage.clone()).await,
+            json!({ "resp": "usage", "sent": 15, "received": 3 })
+        );
+        assert_eq!(
+            request(&mut joiner, usage).await,
+            json!({ "resp": "usage", "sent": 3, "received": 15 })
+        );
+    }
+
+    #[tokio::test]
+    async fn relay_latency_is_observed_for_relayed_messages() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let (mut creator, mut joiner) = pair(addr, Some("safesync.v2")).await;
+        let observed = RELAY_LATENCY_SECONDS.get_sample_count();
+        creator.send(Message::Text("hello".to_owned())).await.unwrap();
+        assert_eq!(next_message(&mut joiner).await, Message::Text("hello".to_owned()));
+        // other tests relay messages at the same time, so the count is only known to have grown
+        assert!(RELAY_LATENCY_SECONDS.get_sample_count() > observed);
+    }
+
+    #[tokio::test]
+    async fn relayed_messages_are_transformed() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .transformer(Arc::new(Uppercase))
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let (mut creator, mut joiner) = pair(addr, Some("safesync.v2")).await;
+        creator.send(Message::Text("hello".to_owned())).await.unwrap();
+        creator.send(Message::Binary(b"hello".to_vec())).await.unwrap();
+        assert_eq!(next_message(&mut joiner).await, Message::Text("HELLO".to_owned()));
+        assert_eq!(next_message(&mut joiner).await, Message::Binary(b"hello".to_vec()));
+        // the control messages are not relayed, so they are not transformed either
+        let usage = request(&mut joiner, json!({ "req": "usage" })).await;
+        assert_eq!(usage["resp"], "usage");
+    }
+
+    #[tokio::test]
+    async fn rejected_messages_are_not_relayed() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .transformer(Arc::new(RejectContaining("secret")))
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let (mut creator, mut joiner) = pair(addr, Some("safesync.v2")).await;
+        creator.send(Message::Text("my secret".to_owned())).await.unwrap();
+        let reply: Value = serde_json::from_str(next_message(&mut creator).await.to_text().unwrap()).unwrap();
+        assert_eq!(reply["resp"], "error");
+        assert_eq!(reply["code"], "message_rejected");
+        // the sender can go on, and the peer only gets the next message
+        creator.send(Message::Text("hello".to_owned())).await.unwrap();
+        assert_eq!(next_message(&mut joiner).await, Message::Text("hello".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn mailbox_creation_is_rate_limited() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .max_creates_per_sec(2)
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let mut creators = Vec::new();
+        for _ in 0..2 {
+            let mut creator = connect(addr).await;
+            assert_eq!(request(&mut creator, json!({ "req": "create" })).await["resp"], "created");
+            creators.push(creator);
+        }
+        let mut throttled = connect(addr).await;
+        let reply = request(&mut throttled, json!({ "req": "create" })).await;
+        assert_eq!(reply["code"], "rate_limited", "{}", reply);
+        // the client can retry once the limit allows it
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(request(&mut throttled, json!({ "req": "create" })).await["resp"], "created");
+    }
+
+    #[tokio::test]
+    async fn namespace_must_match() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let mut creator = connect(addr).await;
+        let created = request(&mut creator, json!({ "req": "create", "ns": "app_a" })).await;
+        let mut joiner = connect(addr).await;
+        for connect_request in [
+            json!({ "req": "connect", "id": created["id"], "ns": "app_b" }),
+            json!({ "req": "connect", "id": created["id"] }),
+        ] {
+            let reply = request(&mut joiner, connect_request).await;
+            assert_eq!(reply["code"], "not_found", "{}", reply);
+        }
+        let connected = request(&mut joiner, json!({ "req": "connect", "id": created["id"], "ns": "app_a" })).await;
+        assert_eq!(connected["resp"], "connected");
+    }
+
+    #[tokio::test]
+    async fn read_only_peer_cannot_send() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let mut creator = connect(addr).await;
+        let created = request(&mut creator, json!({ "req": "create" })).await;
+        let mut reader = connect(addr).await;
+        let connect_request = json!({ "req": "connect", "id": created["id"], "read_only": true });
+        assert_eq!(request(&mut reader, connect_request).await["resp"], "connected");
+
+        reader.send(Message::Text("from reader".to_owned())).await.unwrap();
+        let reply: Value = serde_json::from_str(next_message(&mut reader).await.to_text().unwrap()).unwrap();
+        assert_eq!(reply["code"], "message_rejected");
+        creator.send(Message::Text("from creator".to_owned())).await.unwrap();
+        assert_eq!(next_message(&mut reader).await, Message::Text("from creator".to_owned()));
+        // the rejected message was not relayed
+        let relayed = tokio::time::timeout(Duration::from_millis(200), next_message(&mut creator)).await;
+        assert!(relayed.is_err(), "relayed from a read-only peer: {:?}", relayed);
+    }
+
+    #[tokio::test]
+    async fn messages_to_closing_mailbox_are_dropped() {
+        for policy in [config::ClosingMessagePolicy::Drop, config::ClosingMessagePolicy::Reject] {
+            let server = ServerBuilder::new()
+                .port(0)
+                .metrics_port(0)
+                .closing_message_policy(policy)
+                .build()
+                .new_server();
+            let (addr, server, _shutdown) = serve(server);
+            let (mut creator, mut joiner) = pair(addr, Some("safesync.v2")).await;
+            // the mailbox is marked as closing, its clients are left connected
+            assert_eq!(server.mailbox_manager.close_mailboxes_older_than(Duration::ZERO).len(), 1);
+
+            let dropped_before = MESSAGES_DROPPED_CLOSING.get();
+            creator.send(Message::Text("too late".to_owned())).await.unwrap();
+            if policy == config::ClosingMessagePolicy::Reject {
+                let reply: Value = serde_json::from_str(next_message(&mut creator).await.to_text().unwrap()).unwrap();
+                assert_eq!(reply, json!({ "resp": "closing" }));
+            }
+            let relayed = tokio::time::timeout(Duration::from_millis(200), next_message(&mut joiner)).await;
+            assert!(relayed.is_err(), "relayed to a closing mailbox: {:?}", relayed);
+            assert!(MESSAGES_DROPPED_CLOSING.get() > dropped_before);
+            if policy == config::ClosingMessagePolicy::Drop {
+                let replied = tokio::time::timeout(Duration::from_millis(200), next_message(&mut creator)).await;
+                assert!(replied.is_err(), "replied with the drop policy: {:?}", replied);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn first_message_is_delivered_to_the_joiner() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        for (first, delivered) in [
+            (json!("hello"), "hello".to_owned()),
+            (json!({ "greeting": "hello" }), json!({ "greeting": "hello" }).to_string()),
+        ] {
+            let mut creator = connect(addr).await;
+            let created = request(&mut creator, json!({ "req": "create", "first": first })).await;
+            assert_eq!(created["resp"], "created");
+            let mut joiner = connect(addr).await;
+            let connected = request(&mut joiner, json!({ "req": "connect", "id": created["id"] })).await;
+            assert_eq!(connected["pending_count"], 1);
+            assert_eq!(next_message(&mut joiner).await, Message::Text(delivered));
+        }
+    }
+
+    #[tokio::test]
+    async fn oldest_messages_are_dropped_before_pairing() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .max_prepairing_messages(2)
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let (mut creator, _) = connect_with(addr, Some("safesync.v2")).await.unwrap();
+        let created = request(&mut creator, json!({ "req": "create" })).await;
+        for msg in ["a", "b", "c"] {
+            creator.send(Message::Text(msg.to_owned())).await.unwrap();
+        }
+        let notice = Message::Text(json!({ "resp": "buffer_full" }).to_string());
+        assert_eq!(next_message(&mut creator).await, notice);
+
+        let (mut joiner, _) = connect_with(addr, Some("safesync.v2")).await.unwrap();
+        let connected = request(&mut joiner, json!({ "req": "connect", "id": created["id"] })).await;
+        assert_eq!(connected["pending_count"], 2);
+        assert_eq!(next_message(&mut joiner).await, Message::Text("b".to_owned()));
+        assert_eq!(next_message(&mut joiner).await, Message::Text("c".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn control_messages_are_relayed_with_v1() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        for subprotocol in [None, Some("safesync.v1")] {
+            let (mut creator, mut joiner) = pair(addr, subprotocol).await;
+            for control in [json!({ "req": "usage" }), json!({ "req": "whoami" }), json!({ "req": "done" })] {
+                creator.send(Message::Text(control.to_string())).await.unwrap();
+                assert_eq!(next_message(&mut joiner).await, Message::Text(control.to_string()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_is_member_of_one_mailbox() {
+        let (addr, server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let mut other = connect(addr).await;
+        let other_mailbox = request(&mut other, json!({ "req": "create" })).await;
+        let (mut creator, mut joiner) = pair(addr, Some("safesync.v2")).await;
+
+        // once in a mailbox, the requests to join another one are just data for the other peer
+        for handshake in [json!({ "req": "connect", "id": other_mailbox["id"] }), json!({ "req": "create" })] {
+            creator.send(Message::Text(handshake.to_string())).await.unwrap();
+            assert_eq!(next_message(&mut joiner).await, Message::Text(handshake.to_string()));
+        }
+        let snapshot = serde_json::to_value(server.mailbox_manager.snapshot()).unwrap();
+        let mut peers = snapshot
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|mailbox| mailbox["peers"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        peers.sort_unstable();
+        assert_eq!(peers, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn done_closes_both_peers_normally() {
+        let (addr, server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let (mut creator, mut joiner) = pair(addr, Some("safesync.v2")).await;
+        creator.send(Message::Text("last".to_owned())).await.unwrap();
+        creator.send(Message::Text(json!({ "req": "done" }).to_string())).await.unwrap();
+
+        assert_eq!(next_message(&mut joiner).await, Message::Text("last".to_owned()));
+        let notice: Value = serde_json::from_str(next_message(&mut joiner).await.to_text().unwrap()).unwrap();
+        assert_eq!(notice, json!({ "resp": "transfer_complete" }));
+        assert_eq!(close_frame(&mut joiner).await, (1000, String::new()));
+        assert_eq!(close_frame(&mut creator).await, (1000, String::new()));
+
+        // the mailbox is destroyed before the clients are unregistered
+        wait_for_clients(&server, 0).await;
+        assert_eq!(serde_json::to_value(server.mailbox_manager.snapshot()).unwrap(), json!([]));
+    }
+
+    #[tokio::test]
+    async fn v1_creator_cannot_require_control_messages() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let mut creator = connect(addr).await;
+        for create in [
+            json!({ "req": "create", "require_ready": true }),
+            json!({ "req": "create", "require_approval": true }),
+        ] {
+            let reply = request(&mut creator, create).await;
+            assert_eq!(
+                (&reply["resp"], &reply["code"]),
+                (&json!("error"), &json!("bad_request")),
+                "{}",
+                reply
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_joiner_is_ready_on_joining() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let (mut creator, _) = connect_with(addr, Some("safesync.v2")).await.unwrap();
+        let created = request(&mut creator, json!({ "req": "create", "require_ready": true })).await;
+        creator.send(Message::Text("held".to_owned())).await.unwrap();
+        creator.send(Message::Text(json!({ "req": "ready" }).to_string())).await.unwrap();
+        let mut joiner = connect(addr).await;
+        let connected = request(&mut joiner, json!({ "req": "connect", "id": created["id"] })).await;
+        assert_eq!(connected["pending_count"], 0);
+
+        let ready = Message::Text(json!({ "resp": "ready" }).to_string());
+        assert_eq!(next_message(&mut creator).await, ready);
+        assert_eq!(next_message(&mut joiner).await, ready);
+        assert_eq!(next_message(&mut joiner).await, Message::Text("held".to_owned()));
+    }
+
+    /// Create a mailbox requiring approval and connect a second client to it
+    async fn pair_with_approval(addr: SocketAddr) -> (Socket, Socket) {
+        let (mut creator, _) = connect_with(addr, Some("safesync.v2")).await.unwrap();
+        let created = request(&mut creator, json!({ "req": "create", "require_approval": true })).await;
+        let (mut joiner, _) = connect_with(addr, Some("safesync.v2")).await.unwrap();
+        let connected = request(&mut joiner, json!({ "req": "connect", "id": created["id"] })).await;
+        assert_eq!(connected["resp"], "connected");
+        (creator, joiner)
+    }
+
+    #[tokio::test]
+    async fn messages_are_held_until_approval() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let (mut creator, mut joiner) = pair_with_approval(addr).await;
+        joiner.send(Message::Text("held".to_owned())).await.unwrap();
+        let withheld = tokio::time::timeout(Duration::from_millis(200), next_message(&mut creator)).await;
+        assert!(withheld.is_err(), "relayed before approval: {:?}", withheld);
+
+        creator.send(Message::Text(json!({ "req": "approve" }).to_string())).await.unwrap();
+        let approved = Message::Text(json!({ "resp": "approved" }).to_string());
+        assert_eq!(next_message(&mut creator).await, approved);
+        assert_eq!(next_message(&mut joiner).await, approved);
+        assert_eq!(next_message(&mut creator).await, Message::Text("held".to_owned()));
+
+        // once approved, the messages are relayed right away
+        joiner.send(Message::Text("relayed".to_owned())).await.unwrap();
+        assert_eq!(next_message(&mut creator).await, Message::Text("relayed".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn pending_count_does_not_take_the_messages() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let (mut creator, mut joiner) = pair_with_approval(addr).await;
+        for msg in ["a", "b", "c"] {
+            creator.send(Message::Text(msg.to_owned())).await.unwrap();
+        }
+        let pending_count = json!({ "resp": "pending_count", "count": 3 });
+        // the messages of the creator are enqueued by its own connection, wait for them
+        for _ in 0..100 {
+            if request(&mut joiner, json!({ "req": "pending_count" })).await == pending_count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(request(&mut joiner, json!({ "req": "pending_count" })).await, pending_count);
+
+        creator.send(Message::Text(json!({ "req": "approve" }).to_string())).await.unwrap();
+        assert_eq!(
+            next_message(&mut joiner).await,
+            Message::Text(json!({ "resp": "approved" }).to_string())
+        );
+        for msg in ["a", "b", "c"] {
+            assert_eq!(next_message(&mut joiner).await, Message::Text(msg.to_owned()));
+        }
+        assert_eq!(request(&mut joiner, json!({ "req": "pending_count" })).await["count"], 0);
+    }
+
+    #[tokio::test]
+    async fn reject_closes_both_peers() {
+        let (addr, server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let (mut creator, mut joiner) = pair_with_approval(addr).await;
+        joiner.send(Message::Text("held".to_owned())).await.unwrap();
+        creator.send(Message::Text(json!({ "req": "reject" }).to_string())).await.unwrap();
+
+        let rejected = Message::Text(json!({ "resp": "rejected" }).to_string());
+        assert_eq!(next_message(&mut creator).await, rejected);
+        assert_eq!(next_message(&mut joiner).await, rejected);
+        assert_eq!(close_frame(&mut creator).await.0, 4005);
+        assert_eq!(close_frame(&mut joiner).await.0, 4005);
+
+        wait_for_clients(&server, 0).await;
+        assert_eq!(serde_json::to_value(server.mailbox_manager.snapshot()).unwrap(), json!([]));
+    }
+
+    #[tokio::test]
+    async fn ping_frames_are_relayed_when_enabled() {
+        for relay in [false, true] {
+            let server = ServerBuilder::new()
+                .port(0)
+                .metrics_port(0)
+                .relay_ping_frames(relay)
+                .build()
+                .new_server();
+            let (addr, _server, _shutdown) = serve(server);
+            let (mut creator, mut joiner) = pair(addr, None).await;
+            creator.send(Message::Ping(b"rtt-1".to_vec())).await.unwrap();
+            // the server answers the ping itself in both modes
+            assert_eq!(next_message(&mut creator).await, Message::Pong(b"rtt-1".to_vec()));
+            let relayed = tokio::time::timeout(Duration::from_millis(200), next_message(&mut joiner)).await;
+            if relay {
+                assert_eq!(relayed.unwrap(), Message::Ping(b"rtt-1".to_vec()));
+            } else {
+                assert!(relayed.is_err(), "relayed while disabled: {:?}", relayed);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn relayed_ping_frames_are_numbered() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .relay_ping_frames(true)
+            .nack_undelivered(true)
+            .max_outbound_bytes(1)
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let (mut creator, _joiner) = pair(addr, None).await;
+        // the empty ping fits in the outbound limit of the joiner, the text message does not and is not delivered
+        creator.send(Message::Ping(Vec::new())).await.unwrap();
+        creator.send(Message::Text("hello".to_owned())).await.unwrap();
+        loop {
+            let reply: Value = match next_message(&mut creator).await {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                _ => continue,
+            };
+            if reply["resp"] == "nack" {
+                assert_eq!(reply["seq"], 2);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn both_peers_reconnect() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .reconnect_grace(Some(Duration::from_secs(10)))
+            .build()
+            .new_server();
+        let (addr, server, _shutdown) = serve(server);
+        let mut creator = connect(addr).await;
+        let created = request(&mut creator, json!({ "req": "create" })).await;
+        let mut joiner = connect(addr).await;
+        let connected = request(&mut joiner, json!({ "req": "connect", "id": created["id"] })).await;
+
+        // both connections are lost (dropped without the close handshake), the creator's after sending a message
+        drop(joiner);
+        expect_notice(&mut creator, "peer_reconnecting").await;
+        creator.send(Message::Text("sent alone".to_owned())).await.unwrap();
+        drop(creator);
+        wait_for_clients(&server, 0).await;
+
+        let reconnect = |reply: &Value| json!({ "req": "connect", "id": created["id"], "reconnect_token": reply["reconnect_token"] });
+        let mut joiner = connect(addr).await;
+        let reconnected = request(&mut joiner, reconnect(&connected)).await;
+        assert_eq!(
+            (&reconnected["resp"], &reconnected["pending_count"]),
+            (&json!("connected"), &json!(1))
+        );
+        assert_eq!(next_message(&mut joiner).await, Message::Text("sent alone".to_owned()));
+        let mut creator = connect(addr).await;
+        let reconnected = request(&mut creator, reconnect(&created)).await;
+        assert_eq!(
+            (&reconnected["resp"], &reconnected["pending_count"]),
+            (&json!("connected"), &json!(0))
+        );
+
+        // the session goes on in both directions
+        expect_notice(&mut joiner, "peer_reconnected").await;
+        joiner.send(Message::Text("back".to_owned())).await.unwrap();
+        assert_eq!(next_message(&mut creator).await, Message::Text("back".to_owned()));
+        creator.send(Message::Text("welcome back".to_owned())).await.unwrap();
+        assert_eq!(next_message(&mut joiner).await, Message::Text("welcome back".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn messages_stay_ordered_across_reconnect() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .reconnect_grace(Some(Duration::from_secs(10)))
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let mut sender = connect(addr).await;
+        let created = request(&mut sender, json!({ "req": "create" })).await;
+        let mut receiver = connect(addr).await;
+        let connected = request(&mut receiver, json!({ "req": "connect", "id": created["id"] })).await;
+        drop(receiver);
+        expect_notice(&mut sender, "peer_reconnecting").await;
+
+        let texts = (0..20).map(|i| i.to_string()).collect::<Vec<_>>();
+        let (while_lost, after) = texts.split_at(10);
+        for text in while_lost {
+            sender.send(Message::Text(text.clone())).await.unwrap();
+        }
+        // the later messages race with the reconnection, the receiver sends nothing
+        let mut receiver = connect(addr).await;
+        let reconnect = json!({ "req": "connect", "id": created["id"], "reconnect_token": connected["reconnect_token"] });
+        receiver.send(Message::Text(reconnect.to_string())).await.unwrap();
+        for text in after {
+            sender.send(Message::Text(text.clone())).await.unwrap();
+        }
+
+        let reply: Value = serde_json::from_str(next_message(&mut receiver).await.to_text().unwrap()).unwrap();
+        assert_eq!(reply["resp"], "connected");
+        let mut received = Vec::new();
+        while received.len() < texts.len() {
+            received.push(next_message(&mut receiver).await.into_text().unwrap());
+        }
+        assert_eq!(received, texts);
+    }
+
+    /// Relay large messages to a peer which does not read them, until the sender is told that one was not delivered
+    async fn overflow_outbound_buffer(sender: &mut Socket) -> Value {
+        let chunk = vec![0u8; 256 * 1024];
+        for _ in 0..400 {
+            sender.send(Message::Binary(chunk.clone())).await.unwrap();
+            match tokio::time::timeout(Duration::from_millis(1), sender.next()).await {
+                Ok(Some(Ok(Message::Text(reply)))) if !reply.contains("\"peer_") => return serde_json::from_str(&reply).unwrap(),
+                _ => continue,
+            }
+        }
+        panic!("outgoing buffer never full");
+    }
+
+    #[tokio::test]
+    async fn slow_peer_is_advised() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .peer_slow_threshold(4)
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        // the joiner is not reading, so the messages relayed to it queue up once the socket buffers are full
+        let (mut creator, _joiner) = pair(addr, Some("safesync.v2")).await;
+        let chunk = vec![0u8; 256 * 1024];
+        for _ in 0..400 {
+            creator.send(Message::Binary(chunk.clone())).await.unwrap();
+            match tokio::time::timeout(Duration::from_millis(1), creator.next()).await {
+                Ok(Some(Ok(Message::Text(text)))) if text.contains("\"peer_slow\"") => return,
+                Ok(Some(Ok(Message::Text(text)))) if text.contains("\"peer_connected\"") => continue,
+                Ok(other) => panic!("unexpected {:?}", other),
+                Err(_) => continue,
+            }
+        }
+        panic!("no peer_slow notice");
+    }
+
+    #[tokio::test]
+    async fn full_outbound_buffer() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .outbound_buffer(1)
+            .nack_undelivered(true)
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let (mut creator, mut joiner) = pair(addr, None).await;
+        assert_eq!(overflow_outbound_buffer(&mut creator).await["resp"], "nack");
+        // the slow consumer is disconnected once it takes the messages already sent to it
+        assert_eq!(close_frame(&mut joiner).await, (4006, "slow consumer".to_owned()));
+
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .outbound_buffer(1)
+            .outbound_overflow(config::OutboundOverflowAction::Reject)
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let (mut creator, _joiner) = pair(addr, None).await;
+        assert_eq!(overflow_outbound_buffer(&mut creator).await["code"], "message_rejected");
+    }
+
+    #[tokio::test]
+    async fn outbound_byte_limit() {
+        for action in [config::OutboundOverflowAction::Disconnect, config::OutboundOverflowAction::Reject] {
+            // the message count limit is far from being reached, the byte limit is crossed by a few large messages
+            let server = ServerBuilder::new()
+                .port(0)
+                .metrics_port(0)
+                .outbound_buffer(10_000)
+                .max_outbound_bytes(1024 * 1024)
+                .outbound_overflow(action)
+                .nack_undelivered(true)
+                .build()
+                .new_server();
+            let (addr, _server, _shutdown) = serve(server);
+            let (mut creator, mut joiner) = pair(addr, None).await;
+            let reply = overflow_outbound_buffer(&mut creator).await;
+            match action {
+                config::OutboundOverflowAction::Disconnect => {
+                    assert_eq!(reply["resp"], "nack");
+                    assert_eq!(close_frame(&mut joiner).await, (4006, "slow consumer".to_owned()));
+                }
+                config::OutboundOverflowAction::Reject => assert_eq!(reply["code"], "message_rejected"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn text_and_binary_size_limits() {
+        let text = Message::Text("x".repeat(200));
+        let binary = Message::Binary(vec![0; 200]);
+        // the 200 bytes messages are over one limit and under the other, the handshake requests are under both
+        for (max_text_bytes, max_binary_bytes, accepted, rejected) in [(100, 1000, binary.clone(), text.clone()), (1000, 100, text, binary)]
+        {
+            let server = ServerBuilder::new()
+                .port(0)
+                .metrics_port(0)
+                .max_text_bytes(max_text_bytes)
+                .max_binary_bytes(max_binary_bytes)
+                .build()
+                .new_server();
+            let (addr, _server, _shutdown) = serve(server);
+            let (mut creator, mut joiner) = pair(addr, None).await;
+            creator.send(accepted.clone()).await.unwrap();
+            assert_eq!(next_message(&mut joiner).await, accepted);
+            let kind = if rejected.is_binary() { "binary" } else { "text" };
+            creator.send(rejected).await.unwrap();
+            let reply: Value = serde_json::from_str(next_message(&mut creator).await.to_text().unwrap()).unwrap();
+            assert_eq!(reply["code"], "message_too_large");
+            assert_eq!(reply["message"], format!("{} message larger than 100 bytes", kind));
+        }
+    }
+
+    #[tokio::test]
+    async fn protocol_error_close_code() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .strict_protocol(true)
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        for violation in [
+            Message::Text("hello".to_owned()),
+            Message::Text(json!({ "req": "usage" }).to_string()),
+        ] {
+            let mut socket = connect(addr).await;
+            socket.send(violation).await.unwrap();
+            let frames = raw_frames(&mut socket).await;
+            let error: Value = serde_json::from_slice(&frames[0].1).unwrap();
+            assert_eq!(error["code"], "bad_request");
+            // the error reply is followed by a single close frame
+            assert_eq!(close_codes(&frames), [4000]);
+        }
+    }
+
+    #[tokio::test]
+    async fn counterpart_left_close_code() {
+        let (addr, _server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let (mut creator, mut joiner) = pair(addr, None).await;
+        creator.close(None).await.unwrap();
+        assert_eq!(close_frame(&mut joiner).await, (4001, "counterpart left".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn relayed_messages_are_flushed_before_counterpart_left() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .flush_before_kill(true)
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let (mut creator, _) = connect_with(addr, Some("safesync.v2")).await.unwrap();
+        let created = request(&mut creator, json!({ "req": "create" })).await;
+        // enqueued before the joiner connects, then relayed right before the creator leaves
+        creator.send(Message::Text("enqueued".to_owned())).await.unwrap();
+        let (mut joiner, _) = connect_with(addr, Some("safesync.v2")).await.unwrap();
+        let connected = request(&mut joiner, json!({ "req": "connect", "id": created["id"] })).await;
+        assert_eq!(connected["pending_count"], 1);
+        // more than the socket buffers hold while the joiner is not reading, so most of them are still queued on kill
+        let messages = (0..40u8).map(|i| vec![i; 256 * 1024]).collect::<Vec<_>>();
+        for msg in &messages {
+            creator.send(Message::Binary(msg.clone())).await.unwrap();
+        }
+        creator.close(None).await.unwrap();
+        // the joiner only starts reading once it has been killed
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(next_message(&mut joiner).await, Message::Text("enqueued".to_owned()));
+        for (i, msg) in messages.into_iter().enumerate() {
+            assert!(next_message(&mut joiner).await == Message::Binary(msg), "message {} lost", i);
+        }
+        assert_eq!(close_frame(&mut joiner).await, (4001, "counterpart left".to_owned()));
+    }
+
+    #[test]
+    fn shutting_down_gauge_is_set_when_shutdown_begins() {
+        let server = ServerBuilder::new().port(0).metrics_port(0).build().new_server();
+        assert!(!server.shutting_down.load(Ordering::Relaxed));
+        server.begin_shutdown();
+        assert!(server.shutting_down.load(Ordering::Relaxed));
+        assert_eq!(SHUTTING_DOWN.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_notice_is_sent_before_disconnecting() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .drain_timeout(Duration::from_secs(2))
+            .shutdown_reconnect_after(Duration::from_secs(5))
+            .build()
+            .new_server();
+        let (addr, server, _shutdown) = serve(server);
+        let mut creator = connect(addr).await;
+        request(&mut creator, json!({ "req": "create" })).await;
+        // a client which has not completed its handshake is notified too
+        let handshaking = connect(addr).await;
+        wait_for_clients(&server, 2).await;
+
+        tokio::spawn(async move {
+            server.broadcast_shutdown_notice().await;
+            server.disconnect_all_clients().await;
+        });
+        let notice = json!({ "resp": "server_shutdown", "in_ms": 2000, "reconnect_after_sec": 5 });
+        for mut socket in [creator, handshaking] {
+            let received: Value = serde_json::from_str(next_message(&mut socket).await.to_text().unwrap()).unwrap();
+            assert_eq!(received, notice);
+            assert_eq!(close_frame(&mut socket).await, (1001, "server shutdown".to_owned()));
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_notices_are_batched_and_kills_paced() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .shutdown_notice_batch_size(0)
+            .shutdown_notice_pause(Duration::ZERO)
+            .shutdown_kill_batch_size(2)
+            .shutdown_kill_pause(Duration::from_millis(150))
+            .build()
+            .new_server();
+        let (addr, server, _shutdown) = serve(server);
+        let mut sockets = Vec::new();
+        for _ in 0..6 {
+            sockets.push(connect(addr).await);
+        }
+        wait_for_clients(&server, 6).await;
+
+        // the notices go out at once
+        let started = Instant::now();
+        server.broadcast_shutdown_notice().await;
+        assert!(started.elapsed() < Duration::from_millis(150), "{:?}", started.elapsed());
+        let closed = sockets.into_iter().map(|mut socket| {
+            tokio::spawn(async move {
+                expect_notice(&mut socket, "server_shutdown").await;
+                assert_eq!(close_frame(&mut socket).await.0, 1001);
+                Instant::now()
+            })
+        });
+        let closed = future::join_all(closed.collect::<Vec<_>>());
+
+        // the kills are paced: three batches of two clients, with a pause after each
+        let started = Instant::now();
+        server.disconnect_all_clients().await;
+        assert!(started.elapsed() >= Duration::from_millis(450), "{:?}", started.elapsed());
+        let mut closed = closed.await.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+        closed.sort_unstable();
+        assert!(closed[5] - closed[0] >= Duration::from_millis(250), "{:?}", closed[5] - closed[0]);
+    }
+
+    #[tokio::test]
+    async fn server_shutdown_close_code() {
+        let (addr, server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let mut draining = connect(addr).await;
+        request(&mut draining, json!({ "req": "create" })).await;
+        let (addr, _, shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let mut terminated = connect(addr).await;
+        request(&mut terminated, json!({ "req": "create" })).await;
+
+        // the graceful shutdown drains the clients, then the remaining connections are terminated
+        tokio::spawn(async move { server.disconnect_all_clients().await });
+        assert_eq!(close_frame(&mut draining).await, (1001, "server shutdown".to_owned()));
+        drop(shutdown);
+        assert_eq!(close_frame(&mut terminated).await, (1001, "server shutdown".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn shutdown_drain_is_bounded_by_drain_timeout() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .drain_timeout(Duration::from_millis(300))
+            .close_timeout(Duration::from_millis(300))
+            .build()
+            .new_server();
+        let (addr, server, _shutdown) = serve(server);
+        let (mut creator, _joiner) = pair(addr, Some("safesync.v2")).await;
+        // a backlog the joiner never takes, as it is not reading
+        for _ in 0..40 {
+            creator.send(Message::Binary(vec![0; 256 * 1024])).await.unwrap();
+        }
+
+        let started = tokio::time::Instant::now();
+        server.disconnect_all_clients().await;
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+        // the flush is abandoned, then the close handshake times out
+        wait_for_clients(&server, 0).await;
+    }
+
+    #[tokio::test]
+    async fn server_time_is_echoed() {
+        for echo_server_time in [false, true] {
+            let server = ServerBuilder::new()
+                .port(0)
+                .metrics_port(0)
+                .echo_server_time(echo_server_time)
+                .build()
+                .new_server();
+            let (addr, _server, _shutdown) = serve(server);
+            let before = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+            let mut creator = connect(addr).await;
+            let created = request(&mut creator, json!({ "req": "create" })).await;
+            let mut joiner = connect(addr).await;
+            let connected = request(&mut joiner, json!({ "req": "connect", "id": created["id"] })).await;
+            if !echo_server_time {
+                assert_eq!((created.get("server_time_ms"), connected.get("server_time_ms")), (None, None));
+                continue;
+            }
+            let created_at = created["server_time_ms"].as_u64().unwrap();
+            let connected_at = connected["server_time_ms"].as_u64().unwrap();
+            assert!(
+                before <= created_at && created_at <= connected_at,
+                "{} {} {}",
+                before,
+                created_at,
+                connected_at
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn close_handshake_times_out() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .close_timeout(Duration::from_millis(300))
+            .flush_before_kill(false)
+            .build()
+            .new_server();
+        let (addr, server, _shutdown) = serve(server);
+        // the joiner never reads, so the close frame is stuck behind a backlog filling the socket buffers
+        let (mut creator, _joiner) = pair(addr, Some("safesync.v2")).await;
+        for _ in 0..40 {
+            creator.send(Message::Binary(vec![0; 256 * 1024])).await.unwrap();
+        }
+
+        for client in server.clients.all() {
+            client.kill(CloseReason::AdminDisconnect);
+        }
+        wait_for_clients(&server, 0).await;
+    }
+
+    #[tokio::test]
+    async fn time_sync_interval() {
+        let interval = Duration::from_millis(200);
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .time_sync_interval(Some(interval))
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let mut socket = connect(addr).await;
+        let mut last = std::time::Instant::now();
+        for _ in 0..3 {
+            let message: Value = serde_json::from_str(next_message(&mut socket).await.to_text().unwrap()).unwrap();
+            assert_eq!(message["resp"], "time_sync");
+            let now_ms = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+            assert!(now_ms.abs_diff(message["server_time_ms"].as_u64().unwrap()) < 1000, "{}", message);
+            // the first one comes an interval after connecting, then one every interval
+            let elapsed = last.elapsed();
+            assert!(elapsed > interval * 3 / 4 && elapsed < interval * 2, "{:?}", elapsed);
+            last = std::time::Instant::now();
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_close_code() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .client_idle_timeout(Some(Duration::from_millis(50)))
+            .client_pong_timeout(Duration::from_millis(50))
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let mut socket = connect(addr).await;
+        // not reading from the socket, so that the ping is not answered
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(close_frame(&mut socket).await, (4002, "idle timeout".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn no_idle_pings_while_data_is_flowing() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .client_idle_timeout(Some(Duration::from_millis(200)))
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let (mut sender, mut receiver) = pair(addr, None).await;
+        // the sender only sends and the receiver only receives, for several idle timeouts
+        for _ in 0..30 {
+            sender.send(Message::Text("data".to_owned())).await.unwrap();
+            assert_eq!(next_message(&mut receiver).await, Message::Text("data".to_owned()));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        for socket in [&mut sender, &mut receiver] {
+            while let Ok(msg) = tokio::time::timeout(Duration::from_millis(1), socket.next()).await {
+                let msg = msg.unwrap().unwrap();
+                assert!(!msg.is_ping(), "{:?}", msg);
+            }
+        }
+
+        // both are pinged once the data stops flowing
+        let ping = Duration::from_millis(500);
+        for socket in [&mut sender, &mut receiver] {
+            let msg = tokio::time::timeout(ping, socket.next()).await.unwrap().unwrap().unwrap();
+            assert!(msg.is_ping(), "{:?}", msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn unanswered_pings_are_capped() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .client_idle_timeout(Some(Duration::from_millis(50)))
+            .client_pong_timeout(Duration::from_secs(10))
+            .max_outstanding_pings(2)
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let mut socket = connect(addr).await;
+        // the client is idle between its messages, but it does not read the pings and so does not answer them:
+        // pinged after each message, it is disconnected instead of being pinged a third time
+        for _ in 0..3 {
+            socket.send(Message::Text(json!({ "req": "ping" }).to_string())).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(150)).await;
+        }
+        // read from the TCP stream itself, the websocket library would answer the pings while reading them
+        let frames = raw_frames(&mut socket).await;
+        let opcodes = frames.iter().map(|(opcode, _)| *opcode).collect::<Vec<_>>();
+        assert_eq!(opcodes.iter().filter(|&&opcode| opcode == 0x9).count(), 2, "{:?}", opcodes);
+        assert_eq!(close_codes(&frames), [4002]);
+    }
+
+    #[tokio::test]
+    async fn answered_pings_are_not_capped() {
+        let server = ServerBuilder::new()
+            .port(0)
+            .metrics_port(0)
+            .client_idle_timeout(Some(Duration::from_millis(50)))
+            .max_outstanding_pings(1)
+            .build()
+            .new_server();
+        let (addr, _server, _shutdown) = serve(server);
+        let mut socket = connect(addr).await;
+        // the websocket library answers each ping while reading
+        for _ in 0..5 {
+            let msg = socket.next().await.unwrap().unwrap();
+            assert!(msg.is_ping(), "{:?}", msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_close_codes() {
+        let (addr, server, _shutdown) = serve(ServerBuilder::new().port(0).metrics_port(0).build().new_server());
+        let mut evicted = connect(addr).await;
+        request(&mut evicted, json!({ "req": "create" })).await;
+        let status = admin_request(&server, "POST", "/admin/mailboxes/evict?older_than_sec=0").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(close_frame(&mut evicted).await, (4003, "mailbox expired".to_owned()));
+
+        let mut disconnected = connect(addr).await;
+        let whoami = request(&mut disconnected, json!({ "req": "whoami" })).await;
+        let path = format!("/admin/clients/{}/disconnect", whoami["client_id"]);
+        assert_eq!(admin_request(&server, "POST", &path).await, StatusCode::OK);
+        assert_eq!(close_frame(&mut disconnected).await, (4004, "disconnected by admin".to_owned()));
+        // the client is gone, as is any client which has never connected
+        wait_for_clients(&server, 0).await;
+        assert_eq!(admin_request(&server, "POST", &path).await, StatusCode::NOT_FOUND);
+        assert_eq!(
+            admin_request(&server, "POST", "/admin/clients/0/disconnect").await,
+            StatusCode::NOT_FOUND
+        );
     }
 }
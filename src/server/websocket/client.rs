@@ -1,32 +1,72 @@
 //! Clients management
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
 
 use parking_lot::Mutex;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit};
 use warp::ws;
 
-use super::mailbox::MailboxId;
+use super::{mailbox::MailboxId, subprotocol::ProtocolVersion};
 
 /// Client ID, cheap to clone or copy.
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct ClientId(u64);
 
+impl ClientId {
+    pub fn from_raw(id: u64) -> Self {
+        ClientId(id)
+    }
+
+    /// Stable numeric representation, as accepted by `from_raw`
+    pub fn raw(&self) -> u64 {
+        self.0
+    }
+}
+
 /// Client struct, cheaply cloneable.
 #[derive(Clone)]
 pub struct Client {
     pub id: ClientId,
+    /// IP address the client has connected from (if known)
+    pub remote_ip: Option<IpAddr>,
+    /// Protocol version negotiated when the connection was upgraded
+    pub protocol_version: ProtocolVersion,
+    /// Unique ID of the connection, attached to every log line concerning it
+    pub correlation_id: Arc<str>,
     inner: Arc<Mutex<ClientInner>>,
 }
 
 struct ClientInner {
-    sender: mpsc::UnboundedSender<ws::Message>,
-    kill_sender: Option<oneshot::Sender<()>>,
+    sender: mpsc::Sender<ws::Message>,
+    kill_sender: Option<oneshot::Sender<(KillMode, CloseReason)>>,
+    /// The only mailbox the connection is a member of: relayed messages carry no mailbox id,
+    /// so a connection cannot join another mailbox (its later requests are forwarded as data)
     mailbox_id: Option<MailboxId>,
+    reserved_mailbox_id: Option<MailboxId>,
+    tenant: Option<String>,
+    /// Number of messages sent to this client and not taken from the outgoing channel yet
+    queued: usize,
+    /// Total size of these messages
+    queued_bytes: usize,
+    /// Number of messages forwarded by this client to the other peer
+    relayed: u64,
+    /// Slot of the limited number of connections in the handshake state, released once a mailbox is assigned
+    handshake_permit: Option<OwnedSemaphorePermit>,
 }
 
 impl Client {
-    pub fn new(sender: mpsc::UnboundedSender<ws::Message>, kill_sender: oneshot::Sender<()>) -> Self {
+    pub fn new(
+        sender: mpsc::Sender<ws::Message>,
+        kill_sender: oneshot::Sender<(KillMode, CloseReason)>,
+        remote_ip: Option<IpAddr>,
+        protocol_version: ProtocolVersion,
+        correlation_id: String,
+    ) -> Self {
         let id = {
             use std::sync::atomic::{AtomicU64, Ordering};
             static COUNTER: AtomicU64 = AtomicU64::new(1);
@@ -37,8 +77,20 @@ impl Client {
             sender,
             kill_sender: Some(kill_sender),
             mailbox_id: None,
+            reserved_mailbox_id: None,
+            tenant: None,
+            queued: 0,
+            queued_bytes: 0,
+            relayed: 0,
+            handshake_permit: None,
         }));
-        Client { id, inner }
+        Client {
+            id,
+            remote_ip,
+            protocol_version,
+            correlation_id: correlation_id.into(),
+            inner,
+        }
     }
 
     pub fn mailbox_id(&self) -> Option<MailboxId> {
@@ -46,17 +98,184 @@ impl Client {
     }
 
     pub fn set_mailbox_id(&self, mailbox_id: MailboxId) {
-        self.inner.lock().mailbox_id = Some(mailbox_id);
+        let mut inner = self.inner.lock();
+        debug_assert!(inner.mailbox_id.is_none(), "{:?} is already a member of a mailbox", self.id);
+        inner.mailbox_id = Some(mailbox_id);
+        inner.handshake_permit = None;
+    }
+
+    /// Hold the permit while the client has no mailbox (it is released by `set_mailbox_id` or when the client is dropped)
+    pub fn hold_handshake_permit(&self, permit: OwnedSemaphorePermit) {
+        self.inner.lock().handshake_permit = Some(permit);
+    }
+
+    /// Mailbox in which the client has reserved a slot, but not confirmed it yet
+    pub fn reserved_mailbox_id(&self) -> Option<MailboxId> {
+        self.inner.lock().reserved_mailbox_id
+    }
+
+    pub fn set_reserved_mailbox_id(&self, mailbox_id: Option<MailboxId>) {
+        self.inner.lock().reserved_mailbox_id = mailbox_id;
+    }
+
+    /// Tenant label used in metrics, set on handshake
+    pub fn tenant(&self) -> Option<String> {
+        self.inner.lock().tenant.clone()
+    }
+
+    pub fn set_tenant(&self, tenant: String) {
+        self.inner.lock().tenant = Some(tenant);
+    }
+
+    /// Queue the message to be sent to the client, without waiting for room in its outgoing channel
+    pub fn send_message(&self, msg: ws::Message) -> Result<(), SendError> {
+        let size = msg.as_bytes().len();
+        let mut inner = self.inner.lock();
+        match inner.sender.try_send(msg) {
+            Ok(()) => {
+                inner.queued += 1;
+                inner.queued_bytes += size;
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => Err(SendError::Full),
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(SendError::Closed),
+        }
+    }
+
+    /// Queue the message to be sent to the client, waiting up to `timeout` for room in its outgoing channel if it is full
+    pub async fn send_message_waiting(&self, msg: ws::Message, timeout: Duration) -> Result<(), SendError> {
+        let sender = self.inner.lock().sender.clone();
+        let permit = match tokio::time::timeout(timeout, sender.reserve()).await {
+            Ok(Ok(permit)) => permit,
+            Ok(Err(_)) => return Err(SendError::Closed),
+            Err(_) => return Err(SendError::Full),
+        };
+        // counted under the lock, so that the message cannot be dequeued before it is counted
+        let mut inner = self.inner.lock();
+        inner.queued += 1;
+        inner.queued_bytes += msg.as_bytes().len();
+        permit.send(msg);
+        Ok(())
+    }
+
+    /// Number of messages waiting in the outgoing channel of this client
+    pub fn queue_len(&self) -> usize {
+        self.inner.lock().queued
+    }
+
+    /// Total size of the messages waiting in the outgoing channel of this client
+    pub fn queued_bytes(&self) -> usize {
+        self.inner.lock().queued_bytes
+    }
+
+    /// Must be called for each message taken from the outgoing channel
+    pub fn message_dequeued(&self, msg: &ws::Message) {
+        let mut inner = self.inner.lock();
+        inner.queued = inner.queued.saturating_sub(1);
+        inner.queued_bytes = inner.queued_bytes.saturating_sub(msg.as_bytes().len());
+    }
+
+    /// Sequence number of the last message forwarded by this client, starting from 1
+    pub fn relay_seq(&self) -> u64 {
+        self.inner.lock().relayed
     }
 
-    pub fn send_message(&self, msg: ws::Message) -> bool {
-        let res = self.inner.lock().sender.send(msg);
-        res.is_ok()
+    /// Must be called for each message forwarded by this client to the other peer
+    pub fn message_relayed(&self) {
+        self.inner.lock().relayed += 1;
     }
 
-    pub fn kill(&self) {
+    /// Ask the connection task of this client to terminate, closing the connection with the code of the reason.
+    /// Idempotent: only the first call sends the signal, later ones (e.g. from the graceful shutdown and from
+    /// the other peer leaving at the same time) do nothing, as do calls made after the connection task
+    /// has stopped listening for the signal.
+    pub fn kill(&self, reason: CloseReason) {
+        self.kill_with(KillMode::AsConfigured, reason);
+    }
+
+    /// Same as `kill`, but the messages already queued for the client are always delivered before the connection is closed
+    pub fn drain_and_kill(&self, reason: CloseReason) {
+        self.kill_with(KillMode::Drain, reason);
+    }
+
+    fn kill_with(&self, mode: KillMode, reason: CloseReason) {
         if let Some(tx) = self.inner.lock().kill_sender.take() {
-            let _ = tx.send(());
+            let _ = tx.send((mode, reason));
+        } else {
+            log::trace!("{:?} is already being killed", self.id);
+        }
+    }
+}
+
+/// Why a message could not be queued for a client
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SendError {
+    /// The outgoing channel of the client is full: it does not take its messages fast enough
+    Full,
+    /// The connection of the client is closed
+    Closed,
+}
+
+/// What happens to the messages queued for a killed client
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KillMode {
+    /// Delivered only if `flush_before_kill` is enabled
+    AsConfigured,
+    /// Always delivered (bounded by the drain timeout), e.g. on graceful shutdown
+    Drain,
+}
+
+/// Why the server closes a connection, sent to the client in the close frame
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CloseReason {
+    /// The client has closed the connection, or its transfer is complete
+    Normal,
+    /// The server is shutting down
+    ServerShutdown,
+    /// The other peer has left, and the mailbox is destroyed
+    CounterpartLeft,
+    /// The client has not answered the idle ping in time
+    IdleTimeout,
+    /// The mailbox has been idle for too long, or has been evicted by an admin
+    MailboxExpired,
+    /// The client has been disconnected by an admin
+    AdminDisconnect,
+    /// The joining client has been rejected by the creator of the mailbox
+    Rejected,
+    /// The client does not take the messages relayed to it fast enough
+    SlowConsumer,
+    /// The client has violated the protocol (with the strict protocol enabled)
+    ProtocolError,
+}
+
+impl CloseReason {
+    /// Websocket close code: a standard one where it applies, one from the 4000-4999 private range otherwise
+    pub fn code(self) -> u16 {
+        match self {
+            CloseReason::Normal => 1000,
+            CloseReason::ServerShutdown => 1001,
+            CloseReason::ProtocolError => 4000,
+            CloseReason::CounterpartLeft => 4001,
+            CloseReason::IdleTimeout => 4002,
+            CloseReason::MailboxExpired => 4003,
+            CloseReason::AdminDisconnect => 4004,
+            CloseReason::Rejected => 4005,
+            CloseReason::SlowConsumer => 4006,
+        }
+    }
+
+    /// Text sent along with the close code
+    pub fn description(self) -> &'static str {
+        match self {
+            CloseReason::Normal => "",
+            CloseReason::ServerShutdown => "server shutdown",
+            CloseReason::CounterpartLeft => "counterpart left",
+            CloseReason::IdleTimeout => "idle timeout",
+            CloseReason::MailboxExpired => "mailbox expired",
+            CloseReason::AdminDisconnect => "disconnected by admin",
+            CloseReason::Rejected => "rejected",
+            CloseReason::SlowConsumer => "slow consumer",
+            CloseReason::ProtocolError => "protocol error",
         }
     }
 }
@@ -66,11 +285,21 @@ impl Client {
 pub struct Clients(Arc<Mutex<HashMap<ClientId, Client>>>);
 
 impl Clients {
-    pub fn add(&self, client: Client) {
+    /// Add the client, unless a client with the same ID is already there (which is a bug):
+    /// that client is kept as-is and `false` is returned
+    pub fn add(&self, client: Client) -> bool {
         let Clients(clients) = self;
         let mut clients = clients.lock();
-        debug_assert!(!clients.contains_key(&client.id));
-        clients.insert(client.id, client);
+        match clients.entry(client.id) {
+            Entry::Occupied(_) => {
+                log::error!("{:?} is already registered, the new connection is rejected", client.id);
+                false
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(client);
+                true
+            }
+        }
     }
 
     pub fn remove(&self, id: ClientId) {
@@ -91,4 +320,37 @@ impl Clients {
         let clients = clients.lock();
         clients.values().cloned().collect()
     }
+
+    pub fn count(&self) -> usize {
+        let Clients(clients) = self;
+        let clients = clients.lock();
+        clients.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_client() -> Client {
+        let (sender, _) = mpsc::channel(1);
+        let (kill_sender, _) = oneshot::channel();
+        Client::new(sender, kill_sender, None, ProtocolVersion::V2, "test".to_owned())
+    }
+
+    #[test]
+    fn duplicate_client_id_is_not_added() {
+        let clients = Clients::default();
+        let first = new_client();
+        assert!(clients.add(first.clone()));
+
+        let duplicate = Client {
+            id: ClientId::from_raw(first.id.raw()),
+            correlation_id: "duplicate".into(),
+            ..new_client()
+        };
+        assert!(!clients.add(duplicate));
+        assert_eq!(clients.count(), 1);
+        assert_eq!(&*clients.find(first.id).unwrap().correlation_id, "test");
+    }
 }
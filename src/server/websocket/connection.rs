@@ -1,76 +1,343 @@
 //! Websocket connections management
 
-use std::iter;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    iter,
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use futures::{SinkExt, StreamExt};
-use tokio::sync::{mpsc, oneshot};
+use rand::{rngs::OsRng, Rng};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit};
 use warp::ws;
 
+use self::protocol::ErrorCode;
 use super::{
-    client::{Client, Clients},
-    mailbox::MailboxManager,
+    client::{Client, ClientId, Clients, CloseReason, KillMode, SendError},
+    mailbox::{Departure, MailboxError, MailboxId, MailboxManager, SendOutcome},
+    rate_limit::RateLimiter,
+    subprotocol::ProtocolVersion,
+    transform::MessageTransformer,
+};
+use crate::{
+    metrics::{
+        ACTIVE_CLIENTS, BYTES_RELAYED, CLIENT_CONNECT, CLIENT_DISCONNECT, CLIENT_HANDSHAKE_ABORTED, CLIENT_IDLE_TIMEOUTS,
+        CLIENT_PROTOCOL_ERRORS, CONNECTION_DURATION_SECONDS, MESSAGES_DROPPED_CLOSING, MESSAGES_RELAYED, RELAY_LATENCY_SECONDS,
+        SLOW_CONSUMER_DISCONNECTS, TENANT_ACTIVE_CLIENTS, TENANT_CLIENT_CONNECT,
+    },
+    server::config::{ClosingMessagePolicy, IdFormat, OutboundOverflowAction},
 };
-use crate::metrics::{ACTIVE_CLIENTS, CLIENT_CONNECT, CLIENT_DISCONNECT};
+
+/// Log a line concerning the connection of the client, with the correlation ID of the connection and the mailbox ID
+/// (once known) as structured fields, so that the whole lifecycle of a connection can be filtered by a single ID
+macro_rules! conn_log {
+    ($level:ident, $client:expr, $($arg:tt)+) => {
+        log::$level!($($arg)+; "conn_id" => &*$client.correlation_id, "mailbox_id" => $client.mailbox_id().map(|id| id.raw()))
+    };
+}
+
+/// Websocket connection handling settings
+#[derive(Clone)]
+pub struct ConnectionSettings {
+    /// Deliver messages already queued for a client before disconnecting it when it is killed
+    pub flush_before_kill: bool,
+
+    /// Transformation applied to every relayed message
+    pub transformer: Arc<dyn MessageTransformer>,
+
+    /// Tenants reported in metrics as-is, any other tenant is reported as "other"
+    pub metrics_tenants: Arc<HashSet<String>>,
+
+    /// How mailbox IDs are presented to clients
+    pub id_format: IdFormat,
+
+    /// What happens to messages sent to a mailbox which is being closed
+    pub closing_message_policy: ClosingMessagePolicy,
+
+    /// Environment prefix of the mailbox IDs presented to clients (if any)
+    pub id_prefix: Option<Arc<str>>,
+
+    /// Close the connection on protocol violations instead of replying with a non-fatal error
+    pub strict_protocol: bool,
+
+    /// Relay websocket ping and pong frames (with their payloads) to the other peer of the mailbox
+    pub relay_ping_frames: bool,
+
+    /// Include the server time in the handshake replies
+    pub echo_server_time: bool,
+
+    /// Interval of the 'time_sync' messages sent to the client (if enabled)
+    pub time_sync_interval: Option<Duration>,
+
+    /// Max time to wait for the client to complete the close handshake, the socket is dropped afterwards
+    pub close_timeout: Duration,
+
+    /// Max time to deliver the messages queued for a killed client, the rest are dropped afterwards
+    pub drain_timeout: Duration,
+
+    /// Time without any frames from the client after which it is pinged (if enabled)
+    pub client_idle_timeout: Option<Duration>,
+
+    /// Max time to wait for any frame from an idle client after pinging it, it is disconnected afterwards
+    pub client_pong_timeout: Duration,
+
+    /// Max number of idle pings a client can leave unanswered at once, it is disconnected when another one is due
+    /// (0 means unlimited)
+    pub max_outstanding_pings: usize,
+
+    /// Number of messages queued for a receiver above which the sender is advised that the receiver is slow (0 disables)
+    pub peer_slow_threshold: usize,
+
+    /// Reply with 'nack' to the sender of a message which could not be delivered to the other peer
+    pub nack_undelivered: bool,
+
+    /// Max total size of messages waiting to be sent to a client (0 means unlimited)
+    pub max_outbound_bytes: usize,
+
+    /// Max number of messages waiting to be sent to a client
+    pub outbound_buffer: usize,
+
+    /// Max size of a single text message sent by a client, a larger one closes the connection (0 means unlimited)
+    pub max_text_bytes: usize,
+
+    /// Max size of a single binary message sent by a client, a larger one closes the connection (0 means unlimited)
+    pub max_binary_bytes: usize,
+
+    /// What happens to a message which its receiver is too slow to take (over the size limit or with a full buffer)
+    pub outbound_overflow: OutboundOverflowAction,
+
+    /// Server-wide limit of mailbox creations per second (if any)
+    pub create_limiter: Option<Arc<RateLimiter>>,
+
+    /// Limiters of mailbox creation in the namespaces which have their own rate limit
+    pub namespace_create_limiters: Arc<HashMap<String, RateLimiter>>,
+}
+
+impl ConnectionSettings {
+    /// Metrics label for the tenant declared by the client on handshake
+    fn tenant_label(&self, tenant: Option<String>) -> String {
+        match tenant {
+            None => "default".to_owned(),
+            Some(tenant) if self.metrics_tenants.contains(&tenant) => tenant,
+            Some(_) => "other".to_owned(),
+        }
+    }
+
+    /// Server time to include in the handshake replies (if enabled)
+    fn server_time_ms(&self) -> Option<u64> {
+        self.echo_server_time.then(server_time_ms)
+    }
+
+    /// Mailbox ID as presented to clients
+    fn wire_id(&self, mailbox_id: MailboxId) -> protocol::WireId {
+        match (&self.id_prefix, self.id_format) {
+            (None, IdFormat::Numeric) => protocol::WireId::Numeric(mailbox_id.raw()),
+            (None, IdFormat::Alphanumeric) => protocol::WireId::Code(mailbox_id.to_code()),
+            (Some(prefix), IdFormat::Numeric) => protocol::WireId::Code(format!("{}-{}", prefix, mailbox_id.raw())),
+            (Some(prefix), IdFormat::Alphanumeric) => protocol::WireId::Code(format!("{}-{}", prefix, mailbox_id.to_code())),
+        }
+    }
+
+    /// Raw mailbox ID sent by a client, rejecting IDs issued by a server with another environment prefix
+    fn parse_id(&self, id: &protocol::WireId) -> Result<u32, &'static str> {
+        const FOREIGN_ID: &str = "mailbox id from another environment";
+        let prefix = match &self.id_prefix {
+            Some(prefix) => prefix,
+            None if matches!(id, protocol::WireId::Code(code) if code.contains('-')) => return Err(FOREIGN_ID),
+            None => return id.to_raw().ok_or("malformed mailbox id"),
+        };
+        // the prefixed ID is always a string, with the ID itself in the configured format
+        let unprefixed = match id {
+            protocol::WireId::Code(code) => code.strip_prefix(&**prefix).and_then(|code| code.strip_prefix('-')),
+            protocol::WireId::Numeric(_) => None,
+        };
+        let unprefixed = unprefixed.ok_or(FOREIGN_ID)?;
+        match self.id_format {
+            IdFormat::Numeric => unprefixed.parse().ok(),
+            IdFormat::Alphanumeric => MailboxId::parse_code(unprefixed),
+        }
+        .ok_or("malformed mailbox id")
+    }
+}
+
+/// What is known about the client when its connection is upgraded to a websocket
+pub struct UpgradeInfo {
+    /// IP address the client has connected from (if known)
+    pub remote_ip: Option<IpAddr>,
+    /// Protocol version negotiated with the `Sec-WebSocket-Protocol` header
+    pub protocol_version: ProtocolVersion,
+}
 
 pub async fn handle_connection(
     mut socket: ws::WebSocket,
+    upgrade_info: UpgradeInfo,
+    handshake_permit: Option<OwnedSemaphorePermit>,
     mailbox_manager: MailboxManager,
     clients: Clients,
+    settings: ConnectionSettings,
     shutdown_signal: mpsc::Sender<()>,
 ) {
-    let (client_tx, client_rx) = mpsc::unbounded_channel();
+    let connected_at = Instant::now();
+    let (client_tx, mut client_rx) = mpsc::channel(settings.outbound_buffer);
     let (kill_tx, kill_rx) = oneshot::channel();
 
-    let client = Client::new(client_tx, kill_tx);
-    log::info!("{:?} connected", client.id);
+    let UpgradeInfo {
+        remote_ip,
+        protocol_version,
+    } = upgrade_info;
+    let client = Client::new(client_tx, kill_tx, remote_ip, protocol_version, new_correlation_id());
+    if let Some(permit) = handshake_permit {
+        client.hold_handshake_permit(permit);
+    }
+    conn_log!(info, client, "{:?} connected ({})", client.id, protocol_version.name());
+
+    // never replace a live client registered under the same ID, that would orphan its connection
+    if !clients.add(client.clone()) {
+        let _ = tokio::time::timeout(settings.close_timeout, finalize_connection(socket, CloseReason::Normal)).await;
+        return;
+    }
 
     ACTIVE_CLIENTS.inc();
     CLIENT_CONNECT.inc();
 
-    clients.add(client.clone());
+    // run ws messages processing loop, until it ends by itself or the connection is terminated from outside;
+    // whichever comes first, the teardown below runs exactly once (kills arriving during it are no-ops)
+    let run_handler = run(&mut socket, &client, &mut client_rx, &mailbox_manager, &clients, &settings);
 
-    // run ws messages processing loop
-    let run_handler = run(&mut socket, &client, client_rx, &mailbox_manager, &clients);
-
-    tokio::select! {
-        _ = run_handler => {}
+    let (kill_mode, connection_lost, close_reason) = tokio::select! {
+        (connection_lost, close_reason) = run_handler => (None, connection_lost, close_reason),
         _ = shutdown_signal.closed() => {
-            log::trace!("terminating {:?} due to server shutdown", client.id);
+            conn_log!(trace, client, "terminating {:?} due to server shutdown", client.id);
+            (None, false, CloseReason::ServerShutdown)
         }
-        _ = kill_rx => {
-            log::trace!("kill signal handled by {:?}", client.id);
+        kill = kill_rx => {
+            conn_log!(trace, client, "kill signal handled by {:?}", client.id);
+            let (kill_mode, close_reason) = kill.unwrap_or((KillMode::AsConfigured, CloseReason::Normal));
+            (Some(kill_mode), false, close_reason)
         }
+    };
+
+    // deliver messages relayed to this client right before it was killed (e.g. a handshake reply during a restart),
+    // a client not taking them must not hold this task
+    let flush = match kill_mode {
+        Some(KillMode::Drain) => true,
+        Some(KillMode::AsConfigured) => settings.flush_before_kill,
+        None => false,
+    };
+    if flush
+        && tokio::time::timeout(settings.drain_timeout, flush_outgoing_messages(&mut socket, &mut client_rx))
+            .await
+            .is_err()
+    {
+        conn_log!(
+            debug,
+            client,
+            "{:?} has not taken its queued messages in time, dropping them",
+            client.id
+        );
     }
 
-    // close the associated mailbox (if any) and kick the other client connected to the same mailbox
+    // free the slot reserved by this client (if any) but not confirmed
+    if let Some(mailbox_id) = client.reserved_mailbox_id() {
+        mailbox_manager.release_reservation(mailbox_id, client.id);
+    }
+
+    // close the associated mailbox (if any) and kick the other client connected to the same mailbox,
+    // unless the slot of a client which has lost its connection is held for it to reconnect
     if let Some(mailbox_id) = client.mailbox_id() {
-        let to_kill = mailbox_manager.close_mailbox(mailbox_id, client.id);
-        for target_id in to_kill {
-            if let Some(target) = clients.find(target_id) {
-                log::trace!("forcibly killing {:?} because {:?} is being destroyed", target_id, mailbox_id);
-                target.kill();
-            }
-        }
+        let departure = mailbox_manager.close_mailbox(mailbox_id, client.id, connection_lost);
+        handle_departure(mailbox_id, departure, &clients, &settings);
     }
 
-    // handle connection close
-    finalize_connection(socket).await;
+    // handle connection close (a client not completing the close handshake must not hold this task)
+    if tokio::time::timeout(settings.close_timeout, finalize_connection(socket, close_reason))
+        .await
+        .is_err()
+    {
+        conn_log!(
+            debug,
+            client,
+            "{:?} has not completed the close handshake in time, dropping the connection",
+            client.id
+        );
+    }
 
     clients.remove(client.id);
 
     ACTIVE_CLIENTS.dec();
     CLIENT_DISCONNECT.inc();
+    CONNECTION_DURATION_SECONDS.observe(connected_at.elapsed().as_secs_f64());
+    if let Some(tenant) = client.tenant() {
+        TENANT_ACTIVE_CLIENTS.with_label_values(&[&tenant]).dec();
+    }
+
+    conn_log!(info, client, "{:?} disconnected", client.id);
+}
 
-    log::info!("{:?} disconnected", client.id);
+/// Tell the remaining peers of the mailbox that a client has left it (or is reconnecting),
+/// or disconnect them if the mailbox is being closed
+pub fn handle_departure(mailbox_id: MailboxId, departure: Departure, clients: &Clients, settings: &ConnectionSettings) {
+    let to_kill = match departure {
+        Departure::Nothing => Vec::new(),
+        Departure::PeerLeft(peers) => {
+            for peer in peers {
+                send_to_client(clients, peer, iter::once(protocol::Reply::PeerDisconnected.format()));
+            }
+            Vec::new()
+        }
+        Departure::Reconnecting(peers) => {
+            for peer in peers {
+                send_to_client(clients, peer, iter::once(protocol::Reply::PeerReconnecting.format()));
+            }
+            Vec::new()
+        }
+        Departure::Closing(to_kill) => to_kill,
+    };
+    for (target_id, pending_messages) in to_kill {
+        if let Some(target) = clients.find(target_id) {
+            if settings.flush_before_kill {
+                // messages still enqueued in the mailbox for the target (if it has not taken them yet)
+                for msg in pending_messages {
+                    let _ = target.send_message(msg);
+                }
+            }
+            conn_log!(
+                trace,
+                target,
+                "forcibly killing {:?} because {:?} is being destroyed",
+                target_id,
+                mailbox_id
+            );
+            target.kill(CloseReason::CounterpartLeft);
+        }
+    }
 }
 
+/// Process the messages of the connection until it ends.
+/// Returns whether the connection was lost (as opposed to closed by either side), and why the server closes it.
 async fn run(
     socket: &mut ws::WebSocket,
     client: &Client,
-    mut client_rx: mpsc::UnboundedReceiver<ws::Message>,
+    client_rx: &mut mpsc::Receiver<ws::Message>,
     mailbox_manager: &MailboxManager,
     clients: &Clients,
-) {
+    settings: &ConnectionSettings,
+) -> (bool, CloseReason) {
+    // the first 'time_sync' message is sent one interval after connecting, not right away
+    let mut time_sync = settings.time_sync_interval.map(|period| {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval
+    });
+    // a client exchanging nothing for the idle timeout is pinged, and disconnected if it stays silent for the pong timeout
+    let mut idle_timer = settings.client_idle_timeout.map(|timeout| Box::pin(tokio::time::sleep(timeout)));
+    let mut awaiting_pong = false;
+    // idle pings not answered yet (if their number is limited), by nonce sent as their payload, with the time they were sent
+    let mut outstanding_pings = VecDeque::<(u64, Instant)>::new();
+    let mut last_ping_nonce = 0u64;
     loop {
         tokio::select! {
             // Incoming message (from ws)
@@ -79,24 +346,48 @@ async fn run(
                     let msg = match next_msg_result {
                         Ok(msg) => msg,
                         Err(disconnected_err) => {
-                            log::debug!("Connection to {:?} closed: {}", client.id, disconnected_err);
-                            break;
+                            conn_log!(debug, client, "Connection to {:?} closed: {}", client.id, disconnected_err);
+                            break (true, CloseReason::Normal);
                         }
                     };
 
+                    // any frame (pongs included) proves the client is still there
+                    if let (Some(timer), Some(timeout)) = (&mut idle_timer, settings.client_idle_timeout) {
+                        timer.as_mut().reset(tokio::time::Instant::now() + timeout);
+                        awaiting_pong = false;
+                    }
+
                     if msg.is_close() {
-                        log::debug!("Connection to {:?} was closed by the remote side", client.id);
-                        break;
+                        if client.mailbox_id().is_none() {
+                            // no mailbox is created or attached yet, so there is nothing to clean up except the client itself
+                            conn_log!(debug, client, "Connection to {:?} was closed by the remote side before handshake", client.id);
+                            CLIENT_HANDSHAKE_ABORTED.inc();
+                        } else {
+                            conn_log!(debug, client, "Connection to {:?} was closed by the remote side", client.id);
+                        }
+                        break (false, CloseReason::Normal);
+                    }
+
+                    if msg.is_pong() {
+                        acknowledge_ping(client, &mut outstanding_pings, msg.as_bytes());
                     }
 
                     if msg.is_ping() || msg.is_pong() {
+                        // pings are answered by the websocket layer itself, relaying is in addition to that
+                        if let (true, Some(mailbox_id)) = (settings.relay_ping_frames, client.mailbox_id()) {
+                            // numbered like any other forwarded message, so that the 'nack' replies match the sender's count
+                            client.message_relayed();
+                            relay_message(client, mailbox_id, msg, Instant::now(), mailbox_manager, clients, settings);
+                        }
                         continue;
                     }
 
-                    if let Err(failed_msg) = handle_incoming_message(client, msg, mailbox_manager, &clients) {
-                        log::trace!("Error processing {:?} message: {:?}", client.id, failed_msg);
-                        log::debug!("Error occurred while sending message to {:?}", client.id);
-                        break;
+                    if let Err((failed_msg, reason)) = handle_incoming_message(client, msg, mailbox_manager, clients, settings) {
+                        conn_log!(trace, client, "Error processing {:?} message: {:?}", client.id, failed_msg);
+                        conn_log!(debug, client, "Error occurred while sending message to {:?}", client.id);
+                        // deliver the error frame (if any) before the connection is closed
+                        flush_outgoing_messages(socket, client_rx).await;
+                        break (false, reason);
                     }
                 }
             }
@@ -104,110 +395,1023 @@ async fn run(
             // Outgoing message
             msg = client_rx.recv() => {
                 if let Some(message) = msg {
-                    log::debug!("Sending message to {:?}", client.id);
+                    client.message_dequeued(&message);
+                    conn_log!(debug, client, "Sending message to {:?}", client.id);
                     if let Err(err) = socket.send(message).await {
-                        log::debug!("Error while sending to {:?}: {:?}", client.id, err);
-                        break;
+                        conn_log!(debug, client, "Error while sending to {:?}: {:?}", client.id, err);
+                        break (true, CloseReason::Normal);
+                    }
+                    // data flowing to the client keeps the connection busy too, so that it is only pinged when idle;
+                    // a pinged client must still answer in time
+                    if let (Some(timer), Some(timeout), false) = (&mut idle_timer, settings.client_idle_timeout, awaiting_pong) {
+                        timer.as_mut().reset(tokio::time::Instant::now() + timeout);
                     }
                 } else {
-                    break;
+                    break (false, CloseReason::Normal);
+                }
+            }
+
+            // No frames from the client for too long (if the idle timeout is enabled)
+            _ = next_idle_timeout(&mut idle_timer) => {
+                if awaiting_pong {
+                    conn_log!(debug, client, "{:?} has not answered the ping in time, disconnecting", client.id);
+                    CLIENT_IDLE_TIMEOUTS.inc();
+                    break (true, CloseReason::IdleTimeout);
+                }
+                let max_pings = settings.max_outstanding_pings;
+                if max_pings > 0 && outstanding_pings.len() >= max_pings {
+                    conn_log!(debug, client, "{:?} has left {} pings unanswered, disconnecting", client.id, outstanding_pings.len());
+                    CLIENT_IDLE_TIMEOUTS.inc();
+                    break (true, CloseReason::IdleTimeout);
+                }
+                conn_log!(trace, client, "{:?} is idle, sending ping", client.id);
+                last_ping_nonce += 1;
+                if let Err(err) = socket.send(ws::Message::ping(last_ping_nonce.to_be_bytes().to_vec())).await {
+                    conn_log!(debug, client, "Error while sending to {:?}: {:?}", client.id, err);
+                    break (true, CloseReason::Normal);
+                }
+                if max_pings > 0 {
+                    outstanding_pings.push_back((last_ping_nonce, Instant::now()));
+                }
+                awaiting_pong = true;
+                if let Some(timer) = &mut idle_timer {
+                    timer.as_mut().reset(tokio::time::Instant::now() + settings.client_pong_timeout);
+                }
+            }
+
+            // Periodic server time (if enabled)
+            _ = next_time_sync(&mut time_sync) => {
+                let message = protocol::Reply::TimeSync { server_time_ms: server_time_ms() }.format();
+                if let Err(err) = socket.send(message).await {
+                    conn_log!(debug, client, "Error while sending to {:?}: {:?}", client.id, err);
+                    break (true, CloseReason::Normal);
                 }
             }
         }
     }
 }
 
+/// Wait for the next tick of the 'time_sync' interval, never completes if time sync is disabled
+async fn next_time_sync(time_sync: &mut Option<tokio::time::Interval>) {
+    match time_sync {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+/// Forget the idle ping answered by the pong, which echoes the nonce of the ping as its payload
+/// (pongs not answering an idle ping, e.g. unsolicited ones, are ignored)
+fn acknowledge_ping(client: &Client, outstanding_pings: &mut VecDeque<(u64, Instant)>, payload: &[u8]) {
+    let nonce = match <[u8; 8]>::try_from(payload) {
+        Ok(bytes) => u64::from_be_bytes(bytes),
+        Err(_) => return,
+    };
+    if let Some(position) = outstanding_pings.iter().position(|&(sent_nonce, _)| sent_nonce == nonce) {
+        let (_, sent_at) = outstanding_pings.remove(position).expect("outstanding ping");
+        conn_log!(trace, client, "{:?} has answered the ping in {:?}", client.id, sent_at.elapsed());
+    }
+}
+
+/// Wait for the idle timer to fire, never completes if the idle timeout is disabled
+async fn next_idle_timeout(idle_timer: &mut Option<Pin<Box<tokio::time::Sleep>>>) {
+    match idle_timer {
+        Some(timer) => timer.as_mut().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Random (version 4) UUID identifying a connection in the logs
+fn new_correlation_id() -> String {
+    let mut bytes: [u8; 16] = OsRng.gen();
+    bytes[6] = (bytes[6] & 0x0F) | 0x40; // version 4
+    bytes[8] = (bytes[8] & 0x3F) | 0x80; // RFC 4122 variant
+    let hex = bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Current server time in milliseconds since the Unix epoch
+fn server_time_ms() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_millis() as u64
+}
+
+/// Send all messages already queued for the client to the socket, without waiting for new ones.
+async fn flush_outgoing_messages(socket: &mut ws::WebSocket, client_rx: &mut mpsc::Receiver<ws::Message>) {
+    while let Ok(message) = client_rx.try_recv() {
+        if socket.send(message).await.is_err() {
+            break;
+        }
+    }
+}
+
 /// Handle incoming message for the given client.
-/// Returns the same message in case of errors (when the message is not processed).
+/// Returns the same message in case of fatal errors (when the message is not processed and the connection must be closed),
+/// together with the reason to close the connection with.
 fn handle_incoming_message(
     client: &Client,
     msg: ws::Message,
     mailbox_manager: &MailboxManager,
     clients: &Clients,
-) -> Result<(), ws::Message> {
+    settings: &ConnectionSettings,
+) -> Result<(), (ws::Message, CloseReason)> {
+    let received_at = Instant::now();
+    let (kind, max_size) = if msg.is_binary() {
+        ("binary", settings.max_binary_bytes)
+    } else {
+        ("text", settings.max_text_bytes)
+    };
+    if max_size > 0 && msg.as_bytes().len() > max_size {
+        conn_log!(
+            debug,
+            client,
+            "{:?} has sent a {} message of {} bytes, over the limit",
+            client.id,
+            kind,
+            msg.as_bytes().len()
+        );
+        let message = format!("{} message larger than {} bytes", kind, max_size);
+        return send_error(client, ErrorCode::MessageTooLarge, message, true).map_err(|()| (msg, CloseReason::Normal));
+    }
     if let Some(mailbox_id) = client.mailbox_id() {
-        let immediate_send = mailbox_manager.send_to_mailbox(mailbox_id, client.id, msg);
-        if let Some((client_id, msg)) = immediate_send {
-            if let Some(client) = clients.find(client_id) {
-                let sent = client.send_message(msg);
-                if !sent {
-                    log::debug!("Send message to {:?} failed - disconnected early?", client_id);
-                }
-            } else {
-                log::debug!(
-                    "{:?} not found (disconnected early?) - failed to send message: {:?}",
-                    client_id,
-                    msg,
-                );
+        // Control requests are handled by the server (if the client speaks a protocol version which has them),
+        // everything else is forwarded to the other peer as-is
+        let control_request = if client.protocol_version.control_messages() {
+            protocol::Request::parse_control(&msg)
+        } else {
+            None
+        };
+        match control_request {
+            Some(request) => handle_control_request(client, request, mailbox_manager, clients, settings),
+            None => {
+                // every forwarded message is numbered, so that the sender can match the 'nack' replies
+                client.message_relayed();
+                match settings.transformer.transform(msg) {
+                    Ok(msg) => relay_message(client, mailbox_id, msg, received_at, mailbox_manager, clients, settings),
+                    Err(reason) => {
+                        conn_log!(debug, client, "Message from {:?} rejected: {}", client.id, reason);
+                        let _ = send_error(client, ErrorCode::MessageRejected, reason, false);
+                    }
+                }
             }
         }
     } else {
-        let (reply_message, pending_messages) = match initial_message::Request::parse(&msg) {
-            Ok(initial_message::Request::CreateMailbox) => {
-                let mailbox_id = mailbox_manager.create_mailbox();
-                client.set_mailbox_id(mailbox_id);
-                mailbox_manager.attach_client(mailbox_id, client.id).expect("new mailbox failed");
-                log::debug!("{:?} has created {:?}", client.id, mailbox_id);
-                let reply = initial_message::Reply::Created { id: mailbox_id.raw() };
-                (reply, None)
-            }
-            Ok(initial_message::Request::ConnectToMailbox { id }) => match mailbox_manager.find_mailbox(id) {
-                Ok(mailbox_id) => {
+        match protocol::Request::parse(&msg) {
+            Ok(request) if request.is_control() => {
+                if settings.strict_protocol && !matches!(request, protocol::Request::Whoami | protocol::Request::Ping) {
+                    return protocol_violation(client, "control request before handshake").map_err(|()| (msg, CloseReason::ProtocolError));
+                }
+                handle_control_request(client, request, mailbox_manager, clients, settings)
+            }
+            Ok(request) => {
+                return handle_handshake_request(client, request, msg, mailbox_manager, clients, settings)
+                    .map_err(|msg| (msg, CloseReason::Normal))
+            }
+            Err(err) => {
+                conn_log!(debug, client, "{:?} error: {} - {:?}", client.id, err, msg);
+                if settings.strict_protocol {
+                    return protocol_violation(client, err).map_err(|()| (msg, CloseReason::ProtocolError));
+                }
+                return send_error(client, ErrorCode::BadRequest, err, false).map_err(|()| (msg, CloseReason::Normal));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Forward the message to the other peers of the mailbox (or enqueue it for the peers not connected yet)
+fn relay_message(
+    client: &Client,
+    mailbox_id: MailboxId,
+    msg: ws::Message,
+    received_at: Instant,
+    mailbox_manager: &MailboxManager,
+    clients: &Clients,
+    settings: &ConnectionSettings,
+) {
+    match mailbox_manager.send_to_mailbox(mailbox_id, client.id, msg) {
+        SendOutcome::Deliver(targets) => {
+            // the sender is told at most once that its message has not reached every peer
+            let mut undelivered = false;
+            for (client_id, msg) in targets {
+                undelivered |= !deliver_message(client, client_id, msg, received_at, clients, settings);
+            }
+            if undelivered {
+                nack_undelivered(client, settings);
+            }
+            // a group mailbox may have enqueued the message for its free slots as well
+            evict_pending_over_limit(mailbox_manager, clients);
+        }
+        SendOutcome::Enqueued => evict_pending_over_limit(mailbox_manager, clients),
+        SendOutcome::Closing => {
+            conn_log!(
+                debug,
+                client,
+                "Message from {:?} dropped: {:?} is being closed",
+                client.id,
+                mailbox_id
+            );
+            MESSAGES_DROPPED_CLOSING.inc();
+            if settings.closing_message_policy == ClosingMessagePolicy::Reject
+                && client.send_message(protocol::Reply::Closing.format()).is_err()
+            {
+                conn_log!(debug, client, "Send reply message to {:?} failed - disconnected early?", client.id);
+            }
+        }
+        SendOutcome::ReadOnly => {
+            conn_log!(debug, client, "Message from read-only {:?} rejected", client.id);
+            let _ = send_error(client, ErrorCode::MessageRejected, "read-only peer cannot send messages", false);
+        }
+        SendOutcome::EnqueuedDroppedOldest => {
+            conn_log!(
+                debug,
+                client,
+                "{:?} has overflowed the pending messages of {:?}, oldest ones dropped",
+                client.id,
+                mailbox_id
+            );
+            let sent = client.send_message(protocol::Reply::BufferFull.format()).is_ok();
+            if !sent {
+                conn_log!(debug, client, "Send reply message to {:?} failed - disconnected early?", client.id);
+            }
+            evict_pending_over_limit(mailbox_manager, clients);
+        }
+    }
+}
+
+/// Send the message relayed from the client to the given receiver.
+/// Returns `false` if the message has not been delivered and the sender is to be sent a 'nack' for it.
+fn deliver_message(
+    client: &Client,
+    client_id: ClientId,
+    msg: ws::Message,
+    received_at: Instant,
+    clients: &Clients,
+    settings: &ConnectionSettings,
+) -> bool {
+    let target = match clients.find(client_id) {
+        Some(target) => target,
+        None => {
+            conn_log!(
+                debug,
+                client,
+                "{:?} not found (disconnected early?) - failed to send message: {:?}",
+                client_id,
+                msg
+            );
+            return false;
+        }
+    };
+    let max_bytes = settings.max_outbound_bytes;
+    if max_bytes > 0 && target.queued_bytes() + msg.as_bytes().len() > max_bytes {
+        return handle_outbound_overflow(client, &target, "Too much data waiting to be sent", settings);
+    }
+    let size = msg.as_bytes().len();
+    let sent = match target.send_message(msg) {
+        Ok(()) => true,
+        Err(SendError::Full) => return handle_outbound_overflow(client, &target, "Outgoing buffer full", settings),
+        Err(SendError::Closed) => false,
+    };
+    if sent {
+        RELAY_LATENCY_SECONDS.observe(received_at.elapsed().as_secs_f64());
+        MESSAGES_RELAYED.inc();
+        BYTES_RELAYED.inc_by(size as f64);
+    } else {
+        conn_log!(debug, client, "Send message to {:?} failed - disconnected early?", client_id);
+    }
+    // advise the sender once each time the receiver's queue grows past the threshold
+    let threshold = settings.peer_slow_threshold;
+    if threshold > 0 && target.queue_len() == threshold + 1 {
+        conn_log!(debug, client, "{:?} is slow to receive messages from {:?}", client_id, client.id);
+        if client.send_message(protocol::Reply::PeerSlow.format()).is_err() {
+            conn_log!(debug, client, "Send reply message to {:?} failed - disconnected early?", client.id);
+        }
+    }
+    sent
+}
+
+/// Drop the message which the target is too slow to receive (it would take the data waiting to be sent to the target
+/// over the limit, or the outgoing buffer of the target is full), then either disconnect the target
+/// or tell the sender that its message was rejected.
+/// Returns `false` if the sender is to be sent a 'nack' for the dropped message.
+fn handle_outbound_overflow(client: &Client, target: &Client, reason: &str, settings: &ConnectionSettings) -> bool {
+    match settings.outbound_overflow {
+        OutboundOverflowAction::Disconnect => {
+            conn_log!(debug, client, "{} for {:?}, disconnecting it", reason, target.id);
+            SLOW_CONSUMER_DISCONNECTS.inc();
+            target.kill(CloseReason::SlowConsumer);
+            false
+        }
+        OutboundOverflowAction::Reject => {
+            conn_log!(
+                debug,
+                client,
+                "{} for {:?}, message from {:?} rejected",
+                reason,
+                target.id,
+                client.id
+            );
+            let _ = send_error(
+                client,
+                ErrorCode::MessageRejected,
+                "too much data waiting to be sent to the other peer",
+                false,
+            );
+            // the error reply already tells the sender about the dropped message
+            true
+        }
+    }
+}
+
+/// Tell the sender that its message being relayed was not delivered to the other peer (if enabled)
+fn nack_undelivered(client: &Client, settings: &ConnectionSettings) {
+    let seq = client.relay_seq();
+    if settings.nack_undelivered && client.send_message(protocol::Reply::Nack { seq }.format()).is_err() {
+        conn_log!(debug, client, "Send reply message to {:?} failed - disconnected early?", client.id);
+    }
+}
+
+/// Enforce the limit on the total size of pending messages, notifying the senders of the evicted messages
+fn evict_pending_over_limit(mailbox_manager: &MailboxManager, clients: &Clients) {
+    for sender_id in mailbox_manager.evict_pending_over_limit() {
+        send_to_client(clients, sender_id, iter::once(protocol::Reply::BufferFull.format()));
+    }
+}
+
+/// Handle the initial request which creates or connects to a mailbox.
+/// Returns the original message in case of fatal errors.
+fn handle_handshake_request(
+    client: &Client,
+    request: protocol::Request,
+    msg: ws::Message,
+    mailbox_manager: &MailboxManager,
+    clients: &Clients,
+    settings: &ConnectionSettings,
+) -> Result<(), ws::Message> {
+    let has_reservation = client.reserved_mailbox_id().is_some();
+    if has_reservation != matches!(request, protocol::Request::ConfirmReservation { .. }) {
+        let message = if has_reservation {
+            "slot already reserved"
+        } else {
+            "no slot reserved"
+        };
+        return send_error(client, ErrorCode::BadRequest, message, false).map_err(|()| msg);
+    }
+    let (reply_message, pending_messages, tenant) = match request {
+        protocol::Request::CreateMailbox {
+            require_approval,
+            require_ready,
+            tenant,
+            first,
+            read_only,
+            ns,
+            capacity,
+        } => {
+            // the creator could not send the control messages these options wait for
+            if (require_approval || require_ready) && !client.protocol_version.control_messages() {
+                conn_log!(
+                    debug,
+                    client,
+                    "{:?} cannot require approval or ready signals without control messages",
+                    client.id
+                );
+                let message = "require_approval and require_ready need the safesync.v2 protocol";
+                return send_error(client, ErrorCode::BadRequest, message, false).map_err(|()| msg);
+            }
+            if let Some(limiter) = &settings.create_limiter {
+                if !limiter.try_acquire() {
+                    conn_log!(
+                        debug,
+                        client,
+                        "{:?} is not allowed to create a mailbox: rate limit exceeded",
+                        client.id
+                    );
+                    return send_error(client, ErrorCode::RateLimited, "too many mailboxes created, retry later", false).map_err(|()| msg);
+                }
+            }
+            if let Some(limiter) = ns.as_ref().and_then(|ns| settings.namespace_create_limiters.get(ns)) {
+                if !limiter.try_acquire() {
+                    conn_log!(
+                        debug,
+                        client,
+                        "{:?} is not allowed to create a mailbox in {:?}: rate limit exceeded",
+                        client.id,
+                        ns
+                    );
+                    return send_error(client, ErrorCode::RateLimited, "too many mailboxes created, retry later", false).map_err(|()| msg);
+                }
+            }
+            let (mailbox_id, token) = match mailbox_manager.create_mailbox(require_approval, require_ready, ns, capacity) {
+                Ok(created) => created,
+                Err(
+                    err @ (MailboxError::CapacityExceeded
+                    | MailboxError::NamespaceLimit(_)
+                    | MailboxError::IdSpaceExhausted
+                    | MailboxError::InvalidCapacity(_)),
+                ) => {
+                    conn_log!(debug, client, "{:?} is not allowed to create a mailbox: {}", client.id, err);
+                    return send_mailbox_error(client, &err, settings, false).map_err(|()| msg);
+                }
+                Err(err) => {
+                    conn_log!(error, client, "{:?} has failed to create a mailbox: {}", client.id, err);
+                    return send_mailbox_error(client, &err, settings, false).map_err(|()| msg);
+                }
+            };
+            client.set_mailbox_id(mailbox_id);
+            // nobody else can be attached to a new mailbox, so there is nobody to notify
+            mailbox_manager
+                .attach_client(mailbox_id, client.id, client.remote_ip, read_only)
+                .expect("new mailbox failed");
+            let reconnect_token = mailbox_manager.reconnect_token(mailbox_id, client.id);
+            conn_log!(debug, client, "{:?} has created {:?}", client.id, mailbox_id);
+            if let Some(first) = first {
+                // nobody else can be attached yet, so the first message is always enqueued for the future peer
+                let first = match first {
+                    serde_json::Value::String(text) => ws::Message::text(text),
+                    value => ws::Message::text(value.to_string()),
+                };
+                match settings.transformer.transform(first) {
+                    Ok(first) => {
+                        if let SendOutcome::ReadOnly = mailbox_manager.send_to_mailbox(mailbox_id, client.id, first) {
+                            let _ = send_error(client, ErrorCode::MessageRejected, "read-only peer cannot send messages", false);
+                        }
+                    }
+                    Err(reason) => {
+                        conn_log!(debug, client, "First message from {:?} rejected: {}", client.id, reason);
+                        let _ = send_error(client, ErrorCode::MessageRejected, reason, false);
+                    }
+                }
+            }
+            let reply = protocol::Reply::Created {
+                id: settings.wire_id(mailbox_id),
+                token,
+                reconnect_token,
+                server_time_ms: settings.server_time_ms(),
+            };
+            (reply, None, tenant)
+        }
+        protocol::Request::ConnectToMailbox {
+            id,
+            tenant,
+            read_only,
+            ns,
+            token,
+            reconnect_token,
+        } => {
+            let id = match settings.parse_id(&id) {
+                Ok(id) => id,
+                Err(err) => {
+                    conn_log!(
+                        debug,
+                        client,
+                        "{:?} has tried to connect to an invalid mailbox id {:?}: {}",
+                        client.id,
+                        id,
+                        err
+                    );
+                    return send_error(client, ErrorCode::BadRequest, err, false).map_err(|()| msg);
+                }
+            };
+            let attached = match reconnect_token.as_deref() {
+                // the client takes back the slot held since it has lost its connection
+                Some(reconnect_token) => mailbox_manager.reattach_client(id, ns.as_deref(), client.id, reconnect_token),
+                // the mailbox can become busy or be destroyed between these two calls, so both can fail
+                None => mailbox_manager
+                    .find_mailbox(id, ns.as_deref(), token.as_deref())
+                    .and_then(|mailbox_id| {
+                        mailbox_manager
+                            .attach_client(mailbox_id, client.id, client.remote_ip, read_only)
+                            .map(|(peer, pending)| (mailbox_id, peer, pending))
+                    }),
+            };
+            match attached {
+                Ok((mailbox_id, peer, pending)) => {
                     client.set_mailbox_id(mailbox_id);
-                    match mailbox_manager.attach_client(mailbox_id, client.id) {
-                        Ok(()) => log::debug!("{:?} has connected to {:?}", client.id, mailbox_id),
-                        Err(err) => log::debug!("{:?} has failed to connect to mailbox: {:?}", client.id, err),
+                    if reconnect_token.is_some() {
+                        conn_log!(debug, client, "{:?} has reconnected to {:?}", client.id, mailbox_id);
+                        notify_peer_reconnected(clients, peer);
+                    } else {
+                        conn_log!(debug, client, "{:?} has connected to {:?}", client.id, mailbox_id);
+                        notify_peer_connected(clients, peer);
+                    }
+                    let reply = protocol::Reply::Connected {
+                        id: settings.wire_id(mailbox_id),
+                        pending_count: pending.len(),
+                        reconnect_token: mailbox_manager.reconnect_token(mailbox_id, client.id),
+                        server_time_ms: settings.server_time_ms(),
+                    };
+                    (reply, Some(pending), tenant)
+                }
+                Err(err) => {
+                    conn_log!(debug, client, "{:?} has failed to connect to mailbox: {:?}", client.id, err);
+                    return send_mailbox_error(client, &err, settings, err.is_fatal()).map_err(|()| msg);
+                }
+            }
+        }
+        protocol::Request::ReserveSlot { id, ns, token } => {
+            let id = match settings.parse_id(&id) {
+                Ok(id) => id,
+                Err(err) => {
+                    conn_log!(
+                        debug,
+                        client,
+                        "{:?} has tried to reserve a slot in an invalid mailbox id {:?}: {}",
+                        client.id,
+                        id,
+                        err
+                    );
+                    return send_error(client, ErrorCode::BadRequest, err, false).map_err(|()| msg);
+                }
+            };
+            let reserved = mailbox_manager
+                .find_mailbox(id, ns.as_deref(), token.as_deref())
+                .and_then(|mailbox_id| {
+                    mailbox_manager
+                        .reserve_slot(mailbox_id, client.id, client.remote_ip)
+                        .map(|()| mailbox_id)
+                });
+            match reserved {
+                Ok(mailbox_id) => {
+                    client.set_reserved_mailbox_id(Some(mailbox_id));
+                    conn_log!(debug, client, "{:?} has reserved a slot in {:?}", client.id, mailbox_id);
+                    let reply = protocol::Reply::Reserved {
+                        id: settings.wire_id(mailbox_id),
+                    };
+                    if client.send_message(reply.format()).is_err() {
+                        conn_log!(debug, client, "Send reply message to {:?} failed - disconnected early?", client.id);
                     }
-                    let reply = initial_message::Reply::Connected { id: mailbox_id.raw() };
-                    let pending = mailbox_manager.pending_messages_for_client(mailbox_id, client.id);
-                    (reply, Some(pending))
+                    // the handshake is completed by the 'confirm' request
+                    return Ok(());
                 }
                 Err(err) => {
-                    log::debug!("{:?} has tried to connect to an invalid mailbox: {:?}", client.id, err);
-                    return Err(msg);
+                    conn_log!(debug, client, "{:?} has failed to reserve a slot in mailbox: {:?}", client.id, err);
+                    return send_mailbox_error(client, &err, settings, err.is_fatal()).map_err(|()| msg);
                 }
-            },
+            }
+        }
+        protocol::Request::ConfirmReservation { tenant, read_only } => {
+            let mailbox_id = client.reserved_mailbox_id().expect("reserved mailbox");
+            client.set_reserved_mailbox_id(None);
+            match mailbox_manager.confirm_reservation(mailbox_id, client.id, read_only) {
+                Ok((peer, pending)) => {
+                    client.set_mailbox_id(mailbox_id);
+                    conn_log!(debug, client, "{:?} has connected to {:?}", client.id, mailbox_id);
+                    notify_peer_connected(clients, peer);
+                    let reply = protocol::Reply::Connected {
+                        id: settings.wire_id(mailbox_id),
+                        pending_count: pending.len(),
+                        reconnect_token: mailbox_manager.reconnect_token(mailbox_id, client.id),
+                        server_time_ms: settings.server_time_ms(),
+                    };
+                    (reply, Some(pending), tenant)
+                }
+                Err(err) => {
+                    conn_log!(debug, client, "{:?} has failed to confirm its reservation: {:?}", client.id, err);
+                    return send_mailbox_error(client, &err, settings, false).map_err(|()| msg);
+                }
+            }
+        }
+        protocol::Request::Usage
+        | protocol::Request::PendingCount
+        | protocol::Request::Status
+        | protocol::Request::Whoami
+        | protocol::Request::Ping
+        | protocol::Request::Ready
+        | protocol::Request::Approve
+        | protocol::Request::Reject
+        | protocol::Request::Done => unreachable!("control request"),
+    };
+    let tenant = settings.tenant_label(tenant);
+    TENANT_ACTIVE_CLIENTS.with_label_values(&[&tenant]).inc();
+    TENANT_CLIENT_CONNECT.with_label_values(&[&tenant]).inc();
+    client.set_tenant(tenant);
+
+    let reply_message = reply_message.format();
+    for msg in iter::once(reply_message).chain(pending_messages.unwrap_or_default()) {
+        let sent = client.send_message(msg).is_ok();
+        if !sent {
+            conn_log!(debug, client, "Send reply message to {:?} failed - disconnected early?", client.id);
+        }
+    }
+
+    // a client which cannot send the 'ready' control message is ready as soon as it has joined
+    // (this fails if the mailbox does not wait for the ready signals)
+    if let (false, Some(mailbox_id)) = (client.protocol_version.control_messages(), client.mailbox_id()) {
+        if let Ok(held_messages) = mailbox_manager.set_ready(mailbox_id, client.id) {
+            notify_ready(clients, held_messages);
+        }
+    }
+
+    Ok(())
+}
+
+/// Tell the peers that all of them are ready (nothing is returned by `set_ready` until then),
+/// delivering the messages held until then
+fn notify_ready(clients: &Clients, held_messages: Vec<(ClientId, Vec<ws::Message>)>) {
+    for (target_id, messages) in held_messages {
+        send_to_client(clients, target_id, iter::once(protocol::Reply::Ready.format()).chain(messages));
+    }
+}
+
+/// Handle a control request, which is answered by the server itself and never forwarded to the other peer.
+fn handle_control_request(
+    client: &Client,
+    request: protocol::Request,
+    mailbox_manager: &MailboxManager,
+    clients: &Clients,
+    settings: &ConnectionSettings,
+) {
+    // the control requests which do not need a mailbox
+    let reply = match request {
+        protocol::Request::Whoami => Some(protocol::Reply::Whoami {
+            client_id: client.id.raw(),
+            mailbox_id: client.mailbox_id().map(|mailbox_id| settings.wire_id(mailbox_id)),
+            protocol: client.protocol_version.name(),
+        }),
+        protocol::Request::Ping => Some(protocol::Reply::Pong),
+        _ => None,
+    };
+    if let Some(reply) = reply {
+        if client.send_message(reply.format()).is_err() {
+            conn_log!(debug, client, "Send reply message to {:?} failed - disconnected early?", client.id);
+        }
+        return;
+    }
+    let mailbox_id = match client.mailbox_id() {
+        Some(mailbox_id) => mailbox_id,
+        None => {
+            let _ = send_error(client, ErrorCode::BadRequest, "not connected to a mailbox", false);
+            return;
+        }
+    };
+    let reply = match request {
+        protocol::Request::Usage => {
+            let (sent, received) = mailbox_manager.usage(mailbox_id, client.id);
+            protocol::Reply::Usage { sent, received }
+        }
+        protocol::Request::PendingCount => {
+            let count = mailbox_manager.pending_count(mailbox_id, client.id);
+            protocol::Reply::PendingCount { count }
+        }
+        protocol::Request::Status => {
+            let status = mailbox_manager.status(mailbox_id);
+            protocol::Reply::Status {
+                peers: status.peers,
+                // rounded up, so that zero is only reported once the mailbox is expiring
+                expires_in_sec: status.expires_in.map(|left| left.as_secs() + u64::from(left.subsec_nanos() > 0)),
+                closing: status.is_closing,
+            }
+        }
+        protocol::Request::Ready => match mailbox_manager.set_ready(mailbox_id, client.id) {
+            Ok(held_messages) => {
+                conn_log!(debug, client, "{:?} is ready in {:?}", client.id, mailbox_id);
+                notify_ready(clients, held_messages);
+                return;
+            }
             Err(err) => {
-                log::debug!("{:?} error: {} - {:?}", client.id, err, msg);
-                return Err(msg);
+                let _ = send_mailbox_error(client, &err, settings, false);
+                return;
             }
-        };
-        let reply_message = reply_message.format();
-        for msg in iter::once(reply_message).chain(pending_messages.unwrap_or_default()) {
-            let sent = client.send_message(msg);
+        },
+        protocol::Request::Approve => match mailbox_manager.approve(mailbox_id, client.id) {
+            Ok(held_messages) => {
+                conn_log!(debug, client, "{:?} has approved the joining peer of {:?}", client.id, mailbox_id);
+                for (target_id, messages) in held_messages {
+                    send_to_client(clients, target_id, iter::once(protocol::Reply::Approved.format()).chain(messages));
+                }
+                return;
+            }
+            Err(err) => {
+                let _ = send_mailbox_error(client, &err, settings, false);
+                return;
+            }
+        },
+        protocol::Request::Reject => match mailbox_manager.reject(mailbox_id, client.id) {
+            Ok(to_kill) => {
+                conn_log!(debug, client, "{:?} has rejected the joining peer of {:?}", client.id, mailbox_id);
+                for target_id in to_kill {
+                    send_to_client(clients, target_id, iter::once(protocol::Reply::Rejected.format()));
+                    if let Some(target) = clients.find(target_id) {
+                        conn_log!(trace, client, "killing {:?} because {:?} is rejected", target_id, mailbox_id);
+                        target.kill(CloseReason::Rejected);
+                    }
+                }
+                return;
+            }
+            Err(err) => {
+                let _ = send_mailbox_error(client, &err, settings, false);
+                return;
+            }
+        },
+        protocol::Request::Done => match mailbox_manager.complete(mailbox_id, client.id) {
+            Ok(to_close) => {
+                conn_log!(debug, client, "{:?} has completed the transfer in {:?}", client.id, mailbox_id);
+                for (target_id, pending_messages) in to_close {
+                    // the messages still pending for the target are delivered before the notice
+                    let notice = iter::once(protocol::Reply::TransferComplete.format());
+                    send_to_client(clients, target_id, pending_messages.into_iter().chain(notice));
+                    if let Some(target) = clients.find(target_id) {
+                        conn_log!(
+                            trace,
+                            client,
+                            "closing {:?} because the transfer in {:?} is complete",
+                            target_id,
+                            mailbox_id
+                        );
+                        target.drain_and_kill(CloseReason::Normal);
+                    }
+                }
+                client.drain_and_kill(CloseReason::Normal);
+                return;
+            }
+            Err(err) => {
+                let _ = send_mailbox_error(client, &err, settings, false);
+                return;
+            }
+        },
+        protocol::Request::Whoami | protocol::Request::Ping => unreachable!("handled above"),
+        protocol::Request::CreateMailbox { .. }
+        | protocol::Request::ConnectToMailbox { .. }
+        | protocol::Request::ReserveSlot { .. }
+        | protocol::Request::ConfirmReservation { .. } => unreachable!("not a control request"),
+    };
+    let sent = client.send_message(reply.format()).is_ok();
+    if !sent {
+        conn_log!(debug, client, "Send reply message to {:?} failed - disconnected early?", client.id);
+    }
+}
+
+/// Notice sent to all clients when the server starts shutting down, `eta` is the max time until disconnection
+/// and `reconnect_after` the time clients are advised to wait before reconnecting
+pub fn shutdown_notice(eta: Duration, reconnect_after: Duration) -> ws::Message {
+    protocol::Reply::ServerShutdown {
+        in_ms: eta.as_millis() as u64,
+        reconnect_after_sec: reconnect_after.as_secs(),
+    }
+    .format()
+}
+
+/// Tell the peers already attached to the mailbox that another client has joined it
+fn notify_peer_connected(clients: &Clients, peers: Vec<ClientId>) {
+    for peer in peers {
+        send_to_client(clients, peer, iter::once(protocol::Reply::PeerConnected.format()));
+    }
+}
+
+/// Tell the peers attached to the mailbox that the client which has lost its connection is back
+fn notify_peer_reconnected(clients: &Clients, peers: Vec<ClientId>) {
+    for peer in peers {
+        send_to_client(clients, peer, iter::once(protocol::Reply::PeerReconnected.format()));
+    }
+}
+
+/// Send messages to the client with the given ID (if it is still connected)
+fn send_to_client(clients: &Clients, client_id: ClientId, messages: impl IntoIterator<Item = ws::Message>) {
+    if let Some(client) = clients.find(client_id) {
+        for msg in messages {
+            let sent = client.send_message(msg).is_ok();
             if !sent {
-                log::debug!("Send reply message to {:?} failed - disconnected early?", client.id);
+                log::debug!("Send message to {:?} failed - disconnected early?", client_id);
             }
         }
+    } else {
+        log::debug!("{:?} not found (disconnected early?) - failed to send messages", client_id);
     }
+}
 
-    Ok(())
+/// Error code to report the mailbox error to the client
+fn error_code(err: &MailboxError) -> ErrorCode {
+    match err {
+        MailboxError::NotFound(_) => ErrorCode::NotFound,
+        MailboxError::Busy(_) => ErrorCode::Busy,
+        MailboxError::PeerIpRejected(_) => ErrorCode::Forbidden,
+        MailboxError::Unauthorized(_) => ErrorCode::Unauthorized,
+        MailboxError::Closing(_) => ErrorCode::Closing,
+        MailboxError::CapacityExceeded => ErrorCode::Unavailable,
+        MailboxError::NamespaceLimit(_) => ErrorCode::NamespaceLimit,
+        MailboxError::IdSpaceExhausted => ErrorCode::IdSpaceExhausted,
+        MailboxError::ReservationExpired(_) => ErrorCode::ReservationExpired,
+        MailboxError::ReconnectRejected(_) => ErrorCode::ReconnectRejected,
+        MailboxError::NotAllowed(_) | MailboxError::ReadyNotExpected(_) | MailboxError::InvalidCapacity(_) => ErrorCode::BadRequest,
+    }
+}
+
+/// Send an application-level error frame to the client.
+/// Non-fatal errors leave the connection open, so that the client can retry with a valid request;
+/// fatal errors return `Err`, meaning that the connection must be closed once the error frame is sent.
+fn send_error(client: &Client, code: ErrorCode, message: impl ToString, fatal: bool) -> Result<(), ()> {
+    send_error_reply(client, code, message, None, fatal)
+}
+
+/// Send the error reply for a failed mailbox operation, including the ID of the mailbox concerned (if any)
+fn send_mailbox_error(client: &Client, err: &MailboxError, settings: &ConnectionSettings, fatal: bool) -> Result<(), ()> {
+    let id = err.mailbox_id().map(|mailbox_id| settings.wire_id(mailbox_id));
+    send_error_reply(client, error_code(err), err, id, fatal)
+}
+
+fn send_error_reply(client: &Client, code: ErrorCode, message: impl ToString, id: Option<protocol::WireId>, fatal: bool) -> Result<(), ()> {
+    let reply = protocol::Reply::Error {
+        code,
+        message: message.to_string(),
+        id,
+    };
+    let sent = client.send_message(reply.format()).is_ok();
+    if !sent {
+        conn_log!(debug, client, "Send error message to {:?} failed - disconnected early?", client.id);
+    }
+    if fatal {
+        Err(())
+    } else {
+        Ok(())
+    }
 }
 
-mod initial_message {
+/// Handle a frame violating the protocol: send the error frame.
+/// Always returns `Err`, meaning that the connection must be closed (with `CloseReason::ProtocolError`).
+fn protocol_violation(client: &Client, message: impl ToString) -> Result<(), ()> {
+    let message = message.to_string();
+    conn_log!(debug, client, "{:?} violated the protocol: {}", client.id, message);
+    CLIENT_PROTOCOL_ERRORS.inc();
+    send_error(client, ErrorCode::BadRequest, message, true)
+}
+
+mod protocol {
     use serde::{Deserialize, Serialize};
     use warp::ws;
 
+    use super::MailboxId;
+
     #[derive(Debug, Deserialize)]
     #[serde(tag = "req")]
     pub(super) enum Request {
         /// 'Create a nex mailbox' message
         #[serde(rename = "create")]
-        CreateMailbox,
+        CreateMailbox {
+            /// Hold all messages until the creator approves the joining peer
+            #[serde(default)]
+            require_approval: bool,
+            /// Hold all messages until both peers signal they are ready
+            #[serde(default)]
+            require_ready: bool,
+            /// Tenant used to label metrics
+            #[serde(default)]
+            tenant: Option<String>,
+            /// The first message to be delivered to the joining peer (a string is sent as-is, any other value as JSON)
+            #[serde(default)]
+            first: Option<serde_json::Value>,
+            /// The creator can only receive messages
+            #[serde(default)]
+            read_only: bool,
+            /// Namespace of the mailbox, only clients specifying the same namespace can connect to it
+            #[serde(default)]
+            ns: Option<String>,
+            /// Max number of peers of a group mailbox, every message is relayed to all other peers (2 if not set)
+            #[serde(default)]
+            capacity: Option<usize>,
+        },
 
         /// 'Connect to an existing mailbox' message
         #[serde(rename = "connect")]
-        ConnectToMailbox { id: u32 },
+        ConnectToMailbox {
+            id: WireId,
+            /// Tenant used to label metrics
+            #[serde(default)]
+            tenant: Option<String>,
+            /// The joining peer can only receive messages
+            #[serde(default)]
+            read_only: bool,
+            /// Namespace of the mailbox
+            #[serde(default)]
+            ns: Option<String>,
+            /// Connect token issued with the mailbox (if tokens are required)
+            #[serde(default)]
+            token: Option<String>,
+            /// Reconnect token issued to a client which has lost its connection, to take back its slot
+            #[serde(default)]
+            reconnect_token: Option<String>,
+        },
+
+        /// 'Reserve a slot in an existing mailbox' message, the attachment is completed by the 'confirm' message
+        #[serde(rename = "reserve")]
+        ReserveSlot {
+            id: WireId,
+            /// Namespace of the mailbox
+            #[serde(default)]
+            ns: Option<String>,
+            /// Connect token issued with the mailbox (if tokens are required)
+            #[serde(default)]
+            token: Option<String>,
+        },
+
+        /// 'Complete the attachment to the mailbox with a reserved slot' message
+        #[serde(rename = "confirm")]
+        ConfirmReservation {
+            /// Tenant used to label metrics
+            #[serde(default)]
+            tenant: Option<String>,
+            /// The joining peer can only receive messages
+            #[serde(default)]
+            read_only: bool,
+        },
+
+        /// 'Get bytes sent and received in the current mailbox' control message
+        #[serde(rename = "usage")]
+        Usage,
+
+        /// 'Get the number of messages queued for this client' control message
+        #[serde(rename = "pending_count")]
+        PendingCount,
+
+        /// 'Get the number of peers and the time left until the current mailbox expires' control message
+        #[serde(rename = "status")]
+        Status,
+
+        /// 'Get the ID of this client and its mailbox' control message
+        #[serde(rename = "whoami")]
+        Whoami,
+
+        /// 'Keep the connection alive' control message, for clients which cannot send websocket ping frames
+        #[serde(rename = "ping")]
+        Ping,
+
+        /// 'Ready to exchange messages' control message
+        #[serde(rename = "ready")]
+        Ready,
+
+        /// 'Approve the joining peer' control message
+        #[serde(rename = "approve")]
+        Approve,
+
+        /// 'Reject the joining peer and close the mailbox' control message
+        #[serde(rename = "reject")]
+        Reject,
+
+        /// 'Transfer completed successfully, close the mailbox' control message
+        #[serde(rename = "done")]
+        Done,
     }
 
+    /// Control messages are small, so larger messages are never parsed and always forwarded as-is
+    const MAX_CONTROL_MESSAGE_SIZE: usize = 1024;
+
     impl Request {
         pub(super) fn parse(msg: &ws::Message) -> Result<Request, Error> {
+            if msg.is_binary() {
+                return Err(Error::BinaryInitialMessage);
+            }
             let msg = msg.as_bytes();
             serde_json::from_slice(msg).map_err(|e| match e.classify() {
                 serde_json::error::Category::Data => Error::UnrecognizedInitialMessage(e.to_string()),
-                _ => Error::ErrorParsingJson(e),
+                // the JSON document is cut short, most likely split by the client across several messages
+                serde_json::error::Category::Eof => Error::IncompleteInitialMessage,
+                _ => Error::MalformedJson(e),
             })
         }
+
+        /// Parse a control message sent after the handshake.
+        /// Returns `None` if the message is not a control message and must be forwarded to the other peer.
+        pub(super) fn parse_control(msg: &ws::Message) -> Option<Request> {
+            if !msg.is_text() || msg.as_bytes().len() > MAX_CONTROL_MESSAGE_SIZE {
+                return None;
+            }
+            Self::parse(msg).ok().filter(Request::is_control)
+        }
+
+        /// Whether this is a control message (handled by the server at any time, never forwarded)
+        pub(super) fn is_control(&self) -> bool {
+            matches!(
+                self,
+                Request::Usage
+                    | Request::PendingCount
+                    | Request::Status
+                    | Request::Whoami
+                    | Request::Ping
+                    | Request::Ready
+                    | Request::Approve
+                    | Request::Reject
+                    | Request::Done
+            )
+        }
+    }
+
+    /// Mailbox ID as sent over the wire, either numeric or alphanumeric (both are accepted in requests)
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(untagged)]
+    pub enum WireId {
+        Numeric(u32),
+        Code(String),
+    }
+
+    impl WireId {
+        /// Raw mailbox ID, `None` if the alphanumeric ID is malformed
+        pub(super) fn to_raw(&self) -> Option<u32> {
+            match self {
+                WireId::Numeric(id) => Some(*id),
+                WireId::Code(code) => MailboxId::parse_code(code),
+            }
+        }
     }
 
     #[derive(Clone, Debug, Serialize)]
@@ -217,15 +1421,167 @@ mod initial_message {
         #[serde(rename = "created")]
         Created {
             #[serde(rename = "id")]
-            id: u32,
+            id: WireId,
+            /// Secret the other peers must present to connect (only if tokens are required)
+            #[serde(skip_serializing_if = "Option::is_none")]
+            token: Option<String>,
+            /// Secret this client presents to reconnect after losing its connection (only if reconnection is enabled)
+            #[serde(skip_serializing_if = "Option::is_none")]
+            reconnect_token: Option<String>,
+            /// Server time in milliseconds since the Unix epoch (only if enabled)
+            #[serde(skip_serializing_if = "Option::is_none")]
+            server_time_ms: Option<u64>,
         },
 
         /// 'Successfully connected to mailbox' message
         #[serde(rename = "connected")]
         Connected {
             #[serde(rename = "id")]
-            id: u32,
+            id: WireId,
+            /// Number of enqueued messages delivered right after this reply
+            pending_count: usize,
+            /// Secret this client presents to reconnect after losing its connection (only if reconnection is enabled)
+            #[serde(skip_serializing_if = "Option::is_none")]
+            reconnect_token: Option<String>,
+            /// Server time in milliseconds since the Unix epoch (only if enabled)
+            #[serde(skip_serializing_if = "Option::is_none")]
+            server_time_ms: Option<u64>,
+        },
+
+        /// 'Slot in the mailbox reserved' message
+        #[serde(rename = "reserved")]
+        Reserved {
+            #[serde(rename = "id")]
+            id: WireId,
         },
+
+        /// 'Bytes sent and received in the current mailbox' message
+        #[serde(rename = "usage")]
+        Usage { sent: u64, received: u64 },
+
+        /// 'Number of messages queued for this client' message
+        #[serde(rename = "pending_count")]
+        PendingCount { count: usize },
+
+        /// 'Number of peers and time left until the current mailbox expires (null if it does not expire)' message
+        #[serde(rename = "status")]
+        Status {
+            peers: usize,
+            expires_in_sec: Option<u64>,
+            closing: bool,
+        },
+
+        /// 'ID of this client and its mailbox (null before the handshake), and the protocol version it speaks' message
+        #[serde(rename = "whoami")]
+        Whoami {
+            client_id: u64,
+            mailbox_id: Option<WireId>,
+            protocol: &'static str,
+        },
+
+        /// 'Reply to the ping control message' message
+        #[serde(rename = "pong")]
+        Pong,
+
+        /// 'Both peers are ready to exchange messages' message
+        #[serde(rename = "ready")]
+        Ready,
+
+        /// 'Joining peer approved by the creator' message
+        #[serde(rename = "approved")]
+        Approved,
+
+        /// 'Joining peer rejected by the creator' message
+        #[serde(rename = "rejected")]
+        Rejected,
+
+        /// 'The other peer has completed the transfer, the mailbox is closed' message
+        #[serde(rename = "transfer_complete")]
+        TransferComplete,
+
+        /// 'Another client has joined the mailbox' message
+        #[serde(rename = "peer_connected")]
+        PeerConnected,
+
+        /// 'The other client has left the mailbox, which stays open for a new one' message
+        #[serde(rename = "peer_disconnected")]
+        PeerDisconnected,
+
+        /// 'The other client has lost its connection, its slot is held for it to reconnect' message
+        #[serde(rename = "peer_reconnecting")]
+        PeerReconnecting,
+
+        /// 'The other client has reconnected after losing its connection' message
+        #[serde(rename = "peer_reconnected")]
+        PeerReconnected,
+
+        /// 'The other peer is slow to receive messages' advisory message
+        #[serde(rename = "peer_slow")]
+        PeerSlow,
+
+        /// 'The message with the given sequence number was not delivered to the other peer' message
+        #[serde(rename = "nack")]
+        Nack { seq: u64 },
+
+        /// 'The mailbox is being closed, the message was dropped' message
+        #[serde(rename = "closing")]
+        Closing,
+
+        /// 'Pending messages limit reached, the oldest messages were dropped' message
+        #[serde(rename = "buffer_full")]
+        BufferFull,
+
+        /// 'Current server time' message sent periodically (only if enabled)
+        #[serde(rename = "time_sync")]
+        TimeSync { server_time_ms: u64 },
+
+        /// 'Server is shutting down, the connection will be closed within the given time' message
+        #[serde(rename = "server_shutdown")]
+        ServerShutdown { in_ms: u64, reconnect_after_sec: u64 },
+
+        /// 'Request failed' message
+        #[serde(rename = "error")]
+        Error {
+            code: ErrorCode,
+            message: String,
+            /// ID of the mailbox concerned by the error (if any)
+            #[serde(skip_serializing_if = "Option::is_none")]
+            id: Option<WireId>,
+        },
+    }
+
+    /// Machine-readable error code sent in the 'error' reply
+    #[derive(Clone, Copy, Debug, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ErrorCode {
+        /// The request is malformed or not recognized
+        BadRequest,
+        /// The requested mailbox does not exist
+        NotFound,
+        /// The requested mailbox has no free slots for another peer
+        Busy,
+        /// The requested mailbox is being closed
+        Closing,
+        /// The client is not allowed to join the requested mailbox from its IP address
+        Forbidden,
+        /// The client has not presented the valid connect token of the requested mailbox
+        Unauthorized,
+        /// Too many mailboxes are being created, the request can be retried later
+        RateLimited,
+        /// The server cannot create a mailbox right now, the request can be retried later
+        Unavailable,
+        /// The namespace of the requested mailbox has too many open mailboxes, the request can be retried later
+        NamespaceLimit,
+        /// No free mailbox id is left to create a mailbox, the request can be retried later
+        IdSpaceExhausted,
+        /// The reserved slot has expired before the reservation was confirmed
+        ReservationExpired,
+        /// No slot is held for the reconnect token, e.g. the grace period is over
+        ReconnectRejected,
+        /// The message was not relayed to the other peer
+        MessageRejected,
+        /// The message is larger than allowed
+        MessageTooLarge,
     }
 
     impl Reply {
@@ -238,14 +1594,18 @@ mod initial_message {
     #[derive(thiserror::Error, Debug)]
     pub(super) enum Error {
         #[error("failed to parse initial message as JSON: {0}")]
-        ErrorParsingJson(#[from] serde_json::Error),
+        MalformedJson(#[from] serde_json::Error),
         #[error("unrecognized initial message: {0}")]
         UnrecognizedInitialMessage(String),
+        #[error("incomplete initial message: the handshake request must be sent as a single websocket message")]
+        IncompleteInitialMessage,
+        #[error("binary initial message: the handshake request must be sent as a JSON text message")]
+        BinaryInitialMessage,
     }
 }
 
-async fn finalize_connection(mut socket: ws::WebSocket) {
+async fn finalize_connection(mut socket: ws::WebSocket, reason: CloseReason) {
     // Can safely ignore errors here because this is the final message before socket closing
-    let _ = socket.send(ws::Message::close_with(1000u16, "")).await;
+    let _ = socket.send(ws::Message::close_with(reason.code(), reason.description())).await;
     let _ = socket.close().await;
 }
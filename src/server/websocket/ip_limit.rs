@@ -0,0 +1,49 @@
+//! Per-IP limiting of simultaneous connections
+
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use parking_lot::Mutex;
+
+/// Allows up to `max_per_ip` simultaneous connections from each IP address
+pub struct IpConnectionLimiter {
+    max_per_ip: usize,
+    connections: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Connection counted by the limiter for as long as this guard exists
+pub struct IpConnectionGuard {
+    limiter: Arc<IpConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl IpConnectionLimiter {
+    pub fn new(max_per_ip: usize) -> Self {
+        IpConnectionLimiter {
+            max_per_ip,
+            connections: Mutex::default(),
+        }
+    }
+
+    /// Count a new connection from the IP address, `None` if the limit for that address is reached
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<IpConnectionGuard> {
+        let mut connections = self.connections.lock();
+        let count = connections.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(IpConnectionGuard { limiter: self.clone(), ip })
+    }
+}
+
+impl Drop for IpConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.limiter.connections.lock();
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
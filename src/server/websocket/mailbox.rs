@@ -1,292 +1,2505 @@
 //! Mailbox management
 
 use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    iter,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use parking_lot::{Mutex, RwLock};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
 use warp::ws;
 
 use super::client::ClientId;
+use crate::{
+    metrics::{ACTIVE_MAILBOXES, BYTES_ENQUEUED, MAILBOX_CREATED, MAILBOX_DESTROYED, MESSAGES_ENQUEUED},
+    server::config::{ClosePolicy, NamespaceLimits, PeerIpPolicy},
+};
 
 /// Mailbox ID is a 30-bit unsigned integer
-#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct MailboxId(u32);
 
+/// Alphabet of the alphanumeric mailbox ID representation (Crockford's Base32), 5 bits per character
+const ID_CODE_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Number of characters in the alphanumeric mailbox ID representation (6 * 5 bits = 30 bits)
+const ID_CODE_LENGTH: usize = 6;
+
 impl MailboxId {
     pub fn raw(&self) -> u32 {
         self.0
     }
+
+    /// Alphanumeric representation of this ID
+    pub fn to_code(self) -> String {
+        (0..ID_CODE_LENGTH)
+            .rev()
+            .map(|i| ID_CODE_ALPHABET[(self.0 >> (5 * i)) as usize & 0x1F] as char)
+            .collect()
+    }
+
+    /// Parse the alphanumeric representation of an ID (case-insensitive, `I`/`L` are read as `1` and `O` as `0`)
+    pub fn parse_code(code: &str) -> Option<u32> {
+        if code.len() != ID_CODE_LENGTH {
+            return None;
+        }
+        code.bytes().try_fold(0u32, |id, c| {
+            let c = match c.to_ascii_uppercase() {
+                b'I' | b'L' => b'1',
+                b'O' => b'0',
+                c => c,
+            };
+            let value = ID_CODE_ALPHABET.iter().position(|&a| a == c)?;
+            Some((id << 5) | value as u32)
+        })
+    }
+}
+
+/// Mailbox limits
+#[derive(Clone, Debug)]
+pub struct MailboxSettings {
+    /// Max number of messages enqueued before the second peer connects (the oldest ones are dropped)
+    pub max_prepairing_messages: usize,
+
+    /// Max total size of messages enqueued before the second peer connects, the oldest ones are dropped (0 means unlimited)
+    pub max_prepairing_bytes: usize,
+
+    /// Which peer leaving a mailbox ends the session
+    pub close_policy: ClosePolicy,
+
+    /// Number of mailbox IDs generated in advance (0 disables the pool)
+    pub id_pool_size: usize,
+
+    /// How long a reserved slot is held for a client before it expires unless confirmed
+    pub reservation_timeout: Duration,
+
+    /// Max total size of pending messages in all mailboxes, the oldest ones are evicted above it (0 means unlimited)
+    pub max_pending_bytes: usize,
+
+    /// Max number of open mailboxes, creating more fails (0 means unlimited)
+    pub max_open_mailboxes: usize,
+
+    /// Max number of peers of a group mailbox (a mailbox always accepts at least 2 peers)
+    pub max_capacity: usize,
+
+    /// Which IP addresses the joining peer of a mailbox may connect from, compared to its creator
+    pub peer_ip_policy: PeerIpPolicy,
+
+    /// Generate a secret token for every new mailbox, only clients presenting it can connect to the mailbox
+    pub require_connect_token: bool,
+
+    /// Max idle time of a mailbox with more than one peer connected (if any)
+    pub mailbox_timeout: Option<Duration>,
+
+    /// Max idle time of a mailbox with a single peer connected (if any)
+    pub unpaired_timeout: Option<Duration>,
+
+    /// How long the slot of a client which has lost its connection is held for it to reconnect (if reconnection is enabled)
+    pub reconnect_grace: Option<Duration>,
+
+    /// Max age of a mailbox restored with its pending messages while nobody is connected to it
+    pub restored_mailbox_ttl: Duration,
+
+    /// Limits of the listed namespaces, applied on top of the server-wide ones
+    pub namespace_limits: HashMap<String, NamespaceLimits>,
 }
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct MailboxManager {
     ids: Arc<RwLock<IdManager>>,
     mailboxes: Arc<Mutex<HashMap<MailboxId, Mailbox>>>,
+    settings: Arc<MailboxSettings>,
+    /// Total size of the messages pending in all mailboxes, maintained by `PendingMessage`
+    pending_bytes: Arc<AtomicUsize>,
+}
+
+/// Outcome of sending a message to a mailbox
+#[must_use]
+pub enum SendOutcome {
+    /// The receivers are connected, the message must be sent to each of them directly
+    /// (in a group mailbox it is also enqueued for the slots no client is attached to)
+    Deliver(Vec<(ClientId, ws::Message)>),
+    /// The message is enqueued until the receiver connects
+    Enqueued,
+    /// The message is enqueued, but the oldest pending messages were dropped to make room for it
+    EnqueuedDroppedOldest,
+    /// The sender is a read-only peer, the message is dropped
+    ReadOnly,
+    /// The mailbox is being closed, the message is dropped
+    Closing,
+}
+
+/// What is left to do after a client has left its mailbox
+#[must_use]
+pub enum Departure {
+    /// Nothing: the mailbox is destroyed, or the client was not attached to it
+    Nothing,
+    /// The mailbox stays open for a new peer, the remaining peers are to be told that the client has left
+    PeerLeft(Vec<ClientId>),
+    /// The slot of the client is held for it to reconnect, the remaining peers are to be told that it is reconnecting
+    Reconnecting(Vec<ClientId>),
+    /// The mailbox is being closed, the remaining peers must be disconnected (with the messages still pending for them)
+    Closing(Vec<(ClientId, Vec<ws::Message>)>),
 }
 
 impl MailboxManager {
-    /// Create an empty mailbox with an unique ID
-    pub fn create_mailbox(&self) -> MailboxId {
+    pub fn new(settings: MailboxSettings) -> Self {
+        MailboxManager {
+            ids: Arc::default(),
+            mailboxes: Arc::default(),
+            settings: Arc::new(settings),
+            pending_bytes: Arc::default(),
+        }
+    }
+
+    /// Create an empty mailbox with an unique ID.
+    /// If `require_approval` is set, no messages are relayed until the creator approves the joining peer.
+    /// If `require_ready` is set, no messages are relayed until all attached peers (at least two) have signaled they are ready.
+    /// Only clients specifying the same `namespace` can connect to the mailbox.
+    /// The mailbox accepts up to `capacity` peers (2 if not specified), each message is relayed to all other peers.
+    /// Returns the ID of the new mailbox, together with its connect token if tokens are required.
+    /// Fails if the capacity is not allowed, the limit of open mailboxes is reached or no free ID is found.
+    pub fn create_mailbox(
+        &self,
+        require_approval: bool,
+        require_ready: bool,
+        namespace: Option<String>,
+        capacity: Option<usize>,
+    ) -> Result<(MailboxId, Option<String>), MailboxError> {
+        let capacity = capacity.unwrap_or(2);
+        let max_capacity = self.settings.max_capacity.max(2);
+        if !(2..=max_capacity).contains(&capacity) {
+            return Err(MailboxError::InvalidCapacity(max_capacity));
+        }
         let mut ids = self.ids.write();
-        let id = ids.create_id();
+        let max_open_mailboxes = self.settings.max_open_mailboxes;
+        if max_open_mailboxes > 0 && self.mailboxes.lock().len() >= max_open_mailboxes {
+            return Err(MailboxError::CapacityExceeded);
+        }
+        let namespace_max_open = namespace
+            .as_ref()
+            .and_then(|ns| self.settings.namespace_limits.get(ns))
+            .map_or(0, |limits| limits.max_open_mailboxes);
+        if namespace_max_open > 0 {
+            let namespace_open = self
+                .mailboxes
+                .lock()
+                .values()
+                .filter(|mailbox| mailbox.namespace == namespace)
+                .count();
+            if namespace_open >= namespace_max_open {
+                return Err(MailboxError::NamespaceLimit(namespace.unwrap_or_default()));
+            }
+        }
+        let id = match ids.create_id() {
+            Some(id) => id,
+            None => {
+                log::warn!(
+                    "No free mailbox id found in {} attempts, the id space is nearly exhausted",
+                    MAX_ID_ATTEMPTS
+                );
+                return Err(MailboxError::IdSpaceExhausted);
+            }
+        };
         let mut mailboxes = self.mailboxes.lock();
         debug_assert!(!mailboxes.contains_key(&id));
-        mailboxes.insert(id, Mailbox::default());
+        let mut mailbox = Mailbox::new(require_approval, require_ready, namespace, capacity, &self.pending_bytes);
+        if self.settings.require_connect_token {
+            mailbox.token = Some(random_token());
+        }
+        let token = mailbox.token.clone();
+        mailboxes.insert(id, mailbox);
+        MAILBOX_CREATED.inc();
+        ACTIVE_MAILBOXES.inc();
         log::trace!("{:?} created", id);
-        id
+        Ok((id, token))
     }
 
-    /// Find an existing mailbox by ID
-    pub fn find_mailbox(&self, id: u32) -> Result<MailboxId, MailboxError> {
+    /// Find an existing mailbox by ID, a mailbox in another namespace is reported as not found.
+    /// A mailbox with a connect token can only be found by presenting the same token.
+    pub fn find_mailbox(&self, id: u32, namespace: Option<&str>, token: Option<&str>) -> Result<MailboxId, MailboxError> {
         let id = MailboxId(id);
         let ids = self.ids.read();
-        if !ids.id_exists(id) {
+        if !ids.id_exists(id) || ids.is_denied(id) {
             return Err(MailboxError::NotFound(id));
         }
-        let mailboxes = self.mailboxes.lock();
-        let mailbox = mailboxes.get(&id).expect("mailbox");
-        if mailbox.can_accept_connection() {
-            Ok(id)
-        } else {
-            Err(MailboxError::Busy(id))
+        let mut mailboxes = self.mailboxes.lock();
+        let mailbox = mailboxes.get_mut(&id).expect("mailbox");
+        if mailbox.namespace.as_deref() != namespace {
+            return Err(MailboxError::NotFound(id));
+        }
+        if mailbox.token.is_some() && mailbox.token.as_deref() != token {
+            return Err(MailboxError::Unauthorized(id));
+        }
+        mailbox.expire_reservations();
+        mailbox.check_accepts_connection(id).map(|()| id)
+    }
+
+    /// Attach client to a mailbox, a read-only client can only receive messages.
+    /// Returns the other peers already attached to the mailbox, to be told that the client has joined,
+    /// and the messages pending for the client (taken under the same lock, so that no relayed message can overtake them).
+    pub fn attach_client(
+        &self,
+        mailbox_id: MailboxId,
+        client_id: ClientId,
+        client_ip: Option<IpAddr>,
+        read_only: bool,
+    ) -> Result<(Vec<ClientId>, Vec<ws::Message>), MailboxError> {
+        self.attach(mailbox_id, client_id, client_ip, read_only, None)
+    }
+
+    /// Reserve a slot in a mailbox for the client: the slot counts as taken, but nothing is delivered to the client
+    /// until it confirms the reservation with `confirm_reservation`. An unconfirmed reservation expires after a timeout.
+    pub fn reserve_slot(&self, mailbox_id: MailboxId, client_id: ClientId, client_ip: Option<IpAddr>) -> Result<(), MailboxError> {
+        let expires_at = Instant::now() + self.settings.reservation_timeout;
+        self.attach(mailbox_id, client_id, client_ip, false, Some(expires_at)).map(|_| ())
+    }
+
+    /// Complete the attachment of a client which has reserved a slot in the mailbox.
+    /// Returns the other peers already attached to the mailbox, to be told that the client has joined,
+    /// and the messages enqueued for the slot while it was reserved (taken under the same lock as the confirmation).
+    pub fn confirm_reservation(
+        &self,
+        mailbox_id: MailboxId,
+        client_id: ClientId,
+        read_only: bool,
+    ) -> Result<(Vec<ClientId>, Vec<ws::Message>), MailboxError> {
+        let ids = self.ids.read();
+        if !ids.id_exists(mailbox_id) {
+            return Err(MailboxError::NotFound(mailbox_id));
+        }
+        let mut mailboxes = self.mailboxes.lock();
+        let mailbox = mailboxes.get_mut(&mailbox_id).expect("mailbox");
+        mailbox.expire_reservations();
+        match mailbox.peers.iter_mut().find(|peer| peer.client_id == Some(client_id)) {
+            Some(peer) if peer.reserved_until.is_some() => {
+                peer.reserved_until = None;
+                peer.read_only = read_only;
+                log::trace!("{:?} has confirmed its reservation in {:?}", client_id, mailbox_id);
+                Ok((mailbox.other_active_peers(client_id), mailbox.pending_messages(client_id)))
+            }
+            _ => Err(MailboxError::ReservationExpired(mailbox_id)),
+        }
+    }
+
+    /// Release the slot reserved by the client (if it is still reserved) without closing the mailbox.
+    /// A closing mailbox is destroyed once it has no peers left.
+    pub fn release_reservation(&self, mailbox_id: MailboxId, client_id: ClientId) {
+        let mut ids = self.ids.write();
+        let mut mailboxes = self.mailboxes.lock();
+        let mailbox = match mailboxes.get_mut(&mailbox_id) {
+            Some(mailbox) => mailbox,
+            None => return,
+        };
+        if let Some(peer) = mailbox.peers.iter_mut().find(|peer| peer.client_id == Some(client_id)) {
+            if peer.reserved_until.is_some() {
+                peer.detach();
+                log::trace!("{:?} has released its reservation in {:?}", client_id, mailbox_id);
+            }
+        }
+        if mailbox.is_closing && !mailbox.has_connected_peers() {
+            Self::destroy(&mut ids, &mut mailboxes, mailbox_id);
+        }
+    }
+
+    /// Attach the client to the slot held for a client which has lost its connection to the mailbox in the given namespace,
+    /// presenting the reconnect token issued to that client. The slot keeps its pending messages and its read-only mode.
+    /// Returns the ID of the mailbox, the other peers attached to it, to be told that the peer is back,
+    /// and the messages enqueued for the slot meanwhile (taken under the same lock as the slot itself).
+    pub fn reattach_client(
+        &self,
+        id: u32,
+        namespace: Option<&str>,
+        client_id: ClientId,
+        reconnect_token: &str,
+    ) -> Result<(MailboxId, Vec<ClientId>, Vec<ws::Message>), MailboxError> {
+        let id = MailboxId(id);
+        let ids = self.ids.read();
+        if !ids.id_exists(id) || ids.is_denied(id) {
+            return Err(MailboxError::NotFound(id));
+        }
+        let mut mailboxes = self.mailboxes.lock();
+        let mailbox = mailboxes.get_mut(&id).expect("mailbox");
+        if mailbox.namespace.as_deref() != namespace {
+            return Err(MailboxError::NotFound(id));
         }
+        if mailbox.is_closing {
+            return Err(MailboxError::Closing(id));
+        }
+        let now = Instant::now();
+        let held_slot = mailbox.peers.iter_mut().find(|peer| {
+            matches!(&peer.detached, Some(detached) if detached.until > now) && peer.reconnect_token.as_deref() == Some(reconnect_token)
+        });
+        let former_client_id = match held_slot {
+            Some(peer) => peer.reattach(client_id),
+            None => return Err(MailboxError::ReconnectRejected(id)),
+        };
+        if mailbox.creator == Some(former_client_id) {
+            mailbox.creator = Some(client_id);
+        }
+        log::trace!("{:?} has reconnected to {:?} in place of {:?}", client_id, id, former_client_id);
+        Ok((id, mailbox.other_active_peers(client_id), mailbox.pending_messages(client_id)))
+    }
+
+    /// Secret the client can present to reconnect to its slot after losing its connection (`None` if reconnection is disabled).
+    /// The token is issued on the first call, and a new one once the client has reconnected with it.
+    pub fn reconnect_token(&self, mailbox_id: MailboxId, client_id: ClientId) -> Option<String> {
+        self.settings.reconnect_grace?;
+        let ids = self.ids.read();
+        debug_assert!(ids.id_exists(mailbox_id));
+        let mut mailboxes = self.mailboxes.lock();
+        let mailbox = mailboxes.get_mut(&mailbox_id).expect("mailbox");
+        let peer = mailbox.find_peer_mut(client_id);
+        Some(peer.reconnect_token.get_or_insert_with(random_token).clone())
     }
 
-    /// Attach client to a mailbox
-    pub fn attach_client(&self, mailbox_id: MailboxId, client_id: ClientId) -> Result<(), MailboxError> {
+    fn attach(
+        &self,
+        mailbox_id: MailboxId,
+        client_id: ClientId,
+        client_ip: Option<IpAddr>,
+        read_only: bool,
+        reserved_until: Option<Instant>,
+    ) -> Result<(Vec<ClientId>, Vec<ws::Message>), MailboxError> {
         let ids = self.ids.read();
         if !ids.id_exists(mailbox_id) {
             return Err(MailboxError::NotFound(mailbox_id));
         }
         let mut mailboxes = self.mailboxes.lock();
         let mailbox = mailboxes.get_mut(&mailbox_id).expect("mailbox");
-        if !mailbox.can_accept_connection() {
-            return Err(MailboxError::Busy(mailbox_id));
+        // the creator is always allowed to attach to the mailbox just created for it,
+        // whereas the first client attaching to a restored mailbox is a joining peer like any other
+        let is_creator = mailbox.creator.is_none() && !mailbox.restored;
+        if !is_creator && ids.is_denied(mailbox_id) {
+            return Err(MailboxError::NotFound(mailbox_id));
         }
-        mailbox.attach_peer(client_id);
+        mailbox.expire_reservations();
+        mailbox.check_accepts_connection(mailbox_id)?;
+        if !is_creator && !is_peer_ip_allowed(self.settings.peer_ip_policy, mailbox.creator_ip, client_ip) {
+            return Err(MailboxError::PeerIpRejected(mailbox_id));
+        }
+        mailbox.attach_peer(client_id, client_ip, read_only, reserved_until);
         log::trace!("{:?} has attached to {:?}", client_id, mailbox_id);
-        Ok(())
+        // nothing is delivered to a client which has only reserved its slot
+        let pending = if reserved_until.is_none() {
+            mailbox.pending_messages(client_id)
+        } else {
+            Vec::new()
+        };
+        Ok((mailbox.other_active_peers(client_id), pending))
     }
 
     /// Send a message to a mailbox from a specified client
-    #[must_use]
-    pub fn send_to_mailbox(&self, mailbox_id: MailboxId, from_client: ClientId, msg: ws::Message) -> Option<(ClientId, ws::Message)> {
+    pub fn send_to_mailbox(&self, mailbox_id: MailboxId, from_client: ClientId, msg: ws::Message) -> SendOutcome {
         let ids = self.ids.read();
         debug_assert!(ids.id_exists(mailbox_id));
         let mut mailboxes = self.mailboxes.lock();
         let mailbox = mailboxes.get_mut(&mailbox_id).expect("mailbox");
-        mailbox.send_message(from_client, msg)
+        let size = msg.as_bytes().len();
+        let outcome = mailbox.send_message(from_client, msg, &self.settings);
+        if let SendOutcome::Enqueued | SendOutcome::EnqueuedDroppedOldest = outcome {
+            MESSAGES_ENQUEUED.inc();
+            BYTES_ENQUEUED.inc_by(size as f64);
+        }
+        outcome
     }
 
-    /// Returns (and removes from the queue) all messages in a specified mailbox pending for a specified client
-    #[must_use]
-    pub fn pending_messages_for_client(&self, mailbox_id: MailboxId, for_client: ClientId) -> Vec<ws::Message> {
+    /// If the total size of pending messages exceeds the limit, evict the oldest pending messages across all mailboxes
+    /// until the total is back under 90% of the limit.
+    /// Returns the clients whose messages were evicted (the senders, if still connected).
+    pub fn evict_pending_over_limit(&self) -> Vec<ClientId> {
+        let max_bytes = self.settings.max_pending_bytes;
+        if max_bytes == 0 || self.pending_bytes.load(Ordering::Relaxed) <= max_bytes {
+            return Vec::new();
+        }
+        let target_bytes = max_bytes / 10 * 9;
+        let mut mailboxes = self.mailboxes.lock();
+        // the oldest pending message is always at the front of some queue, so only the fronts need to be compared
+        let mut fronts = BinaryHeap::new();
+        for (&id, mailbox) in mailboxes.iter() {
+            for (slot, peer) in mailbox.peers.iter().enumerate() {
+                if let Some(front) = peer.pending_messages.front() {
+                    fronts.push(Reverse((front.seq, id, slot)));
+                }
+            }
+        }
+        let mut senders = HashSet::new();
+        let mut evicted = 0;
+        while self.pending_bytes.load(Ordering::Relaxed) > target_bytes {
+            let Reverse((_, id, slot)) = match fronts.pop() {
+                Some(front) => front,
+                None => break,
+            };
+            let mailbox = mailboxes.get_mut(&id).expect("mailbox");
+            let peer = &mut mailbox.peers[slot];
+            peer.pending_messages.pop_front();
+            evicted += 1;
+            if let Some(front) = peer.pending_messages.front() {
+                fronts.push(Reverse((front.seq, id, slot)));
+            }
+            // the message may come from any other peer of a group mailbox
+            let other_peers = mailbox.peers.iter().enumerate().filter(|&(other_slot, _)| other_slot != slot);
+            senders.extend(other_peers.filter_map(|(_, peer)| peer.client_id));
+        }
+        log::warn!(
+            "Pending messages over the limit of {} bytes: {} oldest messages evicted",
+            max_bytes,
+            evicted
+        );
+        senders.into_iter().collect()
+    }
+
+    /// Approve the joining peer of the specified mailbox, can only be done by the creator of the mailbox.
+    /// Returns messages held until approval for each connected client (they must be sent externally).
+    pub fn approve(&self, mailbox_id: MailboxId, client_id: ClientId) -> Result<Vec<(ClientId, Vec<ws::Message>)>, MailboxError> {
         let ids = self.ids.read();
         debug_assert!(ids.id_exists(mailbox_id));
         let mut mailboxes = self.mailboxes.lock();
         let mailbox = mailboxes.get_mut(&mailbox_id).expect("mailbox");
-        mailbox.pending_messages(for_client)
+        if !mailbox.can_be_approved_by(client_id) {
+            return Err(MailboxError::NotAllowed(mailbox_id));
+        }
+        mailbox.approved = true;
+        log::trace!("{:?} has approved the joining peer of {:?}", client_id, mailbox_id);
+        let peers = mailbox.connected_peers();
+        Ok(peers.into_iter().map(|peer| (peer, mailbox.pending_messages(peer))).collect())
     }
 
-    /// Close specified mailbox for the given client.
-    /// Destroys that mailbox if no more peers connected to it,
-    /// otherwise list of still connected clients is returned (they must be closed externally).
-    pub fn close_mailbox(&self, mailbox_id: MailboxId, for_client: ClientId) -> Vec<ClientId> {
-        let mut ids = self.ids.write();
+    /// Mark the specified client as ready to exchange messages.
+    /// Once all attached peers are ready, returns messages held until then for each connected client (they must be sent externally),
+    /// nothing is returned while another peer is not ready yet or the client is alone in the mailbox.
+    pub fn set_ready(&self, mailbox_id: MailboxId, client_id: ClientId) -> Result<Vec<(ClientId, Vec<ws::Message>)>, MailboxError> {
+        let ids = self.ids.read();
         debug_assert!(ids.id_exists(mailbox_id));
         let mut mailboxes = self.mailboxes.lock();
         let mailbox = mailboxes.get_mut(&mailbox_id).expect("mailbox");
-        mailbox.detach_peer(for_client);
-        log::trace!("{:?} has detached from {:?}", for_client, mailbox_id);
-        if mailbox.has_connected_peers() {
-            mailbox.connected_peers()
-        } else {
-            mailboxes.remove(&mailbox_id);
-            ids.dispose_id(mailbox_id);
-            log::trace!("{:?} destroyed", mailbox_id);
-            Vec::default()
+        if !mailbox.require_ready || mailbox.is_closing || mailbox.find_peer_mut(client_id).ready {
+            return Err(MailboxError::ReadyNotExpected(mailbox_id));
+        }
+        mailbox.find_peer_mut(client_id).ready = true;
+        log::trace!("{:?} is ready in {:?}", client_id, mailbox_id);
+        if !mailbox.all_peers_ready() {
+            return Ok(Vec::new());
         }
+        let peers = mailbox.connected_peers();
+        Ok(peers.into_iter().map(|peer| (peer, mailbox.pending_messages(peer))).collect())
     }
-}
 
-/// Private API, manages mailbox IDs, ensures uniqueness
-#[derive(Default)]
-struct IdManager {
-    used_ids: HashSet<MailboxId>,
-}
+    /// Reject the joining peer of the specified mailbox, can only be done by the creator of the mailbox.
+    /// The mailbox is marked as closing and the list of connected clients is returned (they must be closed externally).
+    pub fn reject(&self, mailbox_id: MailboxId, client_id: ClientId) -> Result<Vec<ClientId>, MailboxError> {
+        let ids = self.ids.read();
+        debug_assert!(ids.id_exists(mailbox_id));
+        let mut mailboxes = self.mailboxes.lock();
+        let mailbox = mailboxes.get_mut(&mailbox_id).expect("mailbox");
+        if !mailbox.can_be_approved_by(client_id) {
+            return Err(MailboxError::NotAllowed(mailbox_id));
+        }
+        mailbox.is_closing = true;
+        log::trace!("{:?} has rejected the joining peer of {:?}", client_id, mailbox_id);
+        Ok(mailbox.connected_peers())
+    }
 
-impl IdManager {
-    fn random_id() -> MailboxId {
-        use std::sync::atomic::{AtomicU32, Ordering};
-        static COUNTER: AtomicU32 = AtomicU32::new(1000001);
-        let id = COUNTER.fetch_add(1, Ordering::SeqCst);
-        let id = id & 0x3FFFFFFF; // cut 30 bits
-        MailboxId(id)
-    }
-
-    /// Create a new mailbox id that is guaranteed to be unique
-    pub fn create_id(&mut self) -> MailboxId {
-        let id = loop {
-            let id = Self::random_id();
-            if !self.used_ids.contains(&id) {
-                break id;
+    /// End the session of the specified mailbox successfully, on behalf of one of its peers.
+    /// The mailbox is marked as closing and the other connected clients are returned (they must be closed externally),
+    /// together with the messages still pending for each of them.
+    pub fn complete(&self, mailbox_id: MailboxId, client_id: ClientId) -> Result<Vec<(ClientId, Vec<ws::Message>)>, MailboxError> {
+        let ids = self.ids.read();
+        debug_assert!(ids.id_exists(mailbox_id));
+        let mut mailboxes = self.mailboxes.lock();
+        let mailbox = mailboxes.get_mut(&mailbox_id).expect("mailbox");
+        if mailbox.is_closing {
+            return Err(MailboxError::Closing(mailbox_id));
+        }
+        mailbox.is_closing = true;
+        log::trace!("{:?} has completed the transfer in {:?}", client_id, mailbox_id);
+        let other_peers = mailbox.connected_peers().into_iter().filter(|&peer| peer != client_id);
+        Ok(other_peers.map(|peer| (peer, mailbox.pending_messages(peer))).collect())
+    }
+
+    /// Returns the number of bytes (sent, received) by the specified client in the specified mailbox
+    pub fn usage(&self, mailbox_id: MailboxId, client_id: ClientId) -> (u64, u64) {
+        let ids = self.ids.read();
+        debug_assert!(ids.id_exists(mailbox_id));
+        let mut mailboxes = self.mailboxes.lock();
+        let mailbox = mailboxes.get_mut(&mailbox_id).expect("mailbox");
+        let peer = mailbox.find_peer_mut(client_id);
+        (peer.bytes_sent, peer.bytes_received)
+    }
+
+    /// Number of messages queued for the given client and not delivered yet
+    pub fn pending_count(&self, mailbox_id: MailboxId, client_id: ClientId) -> usize {
+        let ids = self.ids.read();
+        debug_assert!(ids.id_exists(mailbox_id));
+        let mut mailboxes = self.mailboxes.lock();
+        let mailbox = mailboxes.get_mut(&mailbox_id).expect("mailbox");
+        mailbox.find_peer_mut(client_id).pending_messages.len()
+    }
+
+    /// Close specified mailbox for the given client.
+    /// Destroys that mailbox if no more peers connected to it, otherwise either the mailbox stays open
+    /// waiting for a new peer, or the list of still connected clients is returned (they must be closed externally),
+    /// together with the messages still pending for each of them (see `Departure`).
+    /// If the client has lost its connection and reconnection is enabled, its slot is held for it instead,
+    /// until it reconnects or the grace period ends (see `expire_detached_peers`).
+    /// Closing a mailbox which is already destroyed, or which the client is not attached to, does nothing,
+    /// so that peers closing the same mailbox simultaneously destroy it exactly once.
+    pub fn close_mailbox(&self, mailbox_id: MailboxId, for_client: ClientId, connection_lost: bool) -> Departure {
+        let mut ids = self.ids.write();
+        let mut mailboxes = self.mailboxes.lock();
+        let mailbox = match mailboxes.get_mut(&mailbox_id) {
+            Some(mailbox) => mailbox,
+            None => {
+                log::debug!("{:?} is already destroyed - nothing to close for {:?}", mailbox_id, for_client);
+                return Departure::Nothing;
             }
         };
-        debug_assert!(!self.used_ids.contains(&id));
-        self.used_ids.insert(id);
-        id
+        if let (true, Some(grace)) = (connection_lost && !mailbox.is_closing, self.settings.reconnect_grace) {
+            // a client which has not been told its reconnect token cannot come back, so its slot is not held
+            let slot = mailbox.peers.iter_mut().find(|peer| peer.client_id == Some(for_client));
+            if let Some(peer) = slot.filter(|peer| peer.reconnect_token.is_some()) {
+                peer.hold_for_reconnect(Instant::now() + grace);
+                log::trace!("{:?} has lost its connection to {:?}, its slot is held", for_client, mailbox_id);
+                return Departure::Reconnecting(mailbox.other_active_peers(for_client));
+            }
+        }
+        if !mailbox.detach_peer(for_client, self.settings.close_policy) {
+            log::debug!("{:?} is not attached to {:?} - nothing to close", for_client, mailbox_id);
+            return Departure::Nothing;
+        }
+        log::trace!("{:?} has detached from {:?}", for_client, mailbox_id);
+        Self::departure(&mut ids, &mut mailboxes, mailbox_id, for_client)
     }
 
-    /// Remove existing mailbox id
-    pub fn dispose_id(&mut self, id: MailboxId) {
-        debug_assert!(self.used_ids.contains(&id));
-        self.used_ids.remove(&id);
+    /// Free the slots held for clients which have not reconnected within the grace period, as if these clients had left.
+    /// Returns what is left to do for each of the mailboxes concerned (see `Departure`).
+    pub fn expire_detached_peers(&self) -> Vec<(MailboxId, Departure)> {
+        let mut ids = self.ids.write();
+        let mut mailboxes = self.mailboxes.lock();
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for (&id, mailbox) in mailboxes.iter() {
+            for peer in &mailbox.peers {
+                if let Some(detached) = peer.detached.as_ref().filter(|detached| detached.until <= now) {
+                    expired.push((id, detached.client_id));
+                }
+            }
+        }
+        let mut departures = Vec::new();
+        for (mailbox_id, client_id) in expired {
+            // the mailbox may have been destroyed because of another slot expiring at the same time
+            let mailbox = match mailboxes.get_mut(&mailbox_id) {
+                Some(mailbox) => mailbox,
+                None => continue,
+            };
+            if mailbox.detach_lost_peer(client_id, self.settings.close_policy) {
+                log::trace!("{:?} has not reconnected to {:?} in time", client_id, mailbox_id);
+                departures.push((mailbox_id, Self::departure(&mut ids, &mut mailboxes, mailbox_id, client_id)));
+            }
+        }
+        departures
     }
 
-    /// Checks if specified ID exists
-    pub fn id_exists(&self, id: MailboxId) -> bool {
-        self.used_ids.contains(&id)
+    /// What is left to do after the client has left the mailbox, which is destroyed if no peers are left
+    /// (the slots held for reconnecting clients do not keep a closing mailbox open)
+    fn departure(
+        ids: &mut IdManager,
+        mailboxes: &mut HashMap<MailboxId, Mailbox>,
+        mailbox_id: MailboxId,
+        left_client: ClientId,
+    ) -> Departure {
+        let mailbox = mailboxes.get_mut(&mailbox_id).expect("mailbox");
+        if mailbox.is_closing {
+            mailbox.drop_detached_peers();
+        }
+        if mailbox.has_connected_peers() {
+            if !mailbox.is_closing {
+                log::trace!("{:?} is waiting for a new peer", mailbox_id);
+                return Departure::PeerLeft(mailbox.other_active_peers(left_client));
+            }
+            let peers = mailbox.connected_peers();
+            Departure::Closing(peers.into_iter().map(|peer| (peer, mailbox.pending_messages(peer))).collect())
+        } else {
+            Self::destroy(ids, mailboxes, mailbox_id);
+            Departure::Nothing
+        }
     }
-}
 
-/// Private API, manages peers: each mailbox can have up to 2 peers
-#[derive(Default)]
-struct Mailbox {
-    peers: [Peer; 2],
-    is_closing: bool,
-}
+    /// Remove the mailbox and free its ID
+    fn destroy(ids: &mut IdManager, mailboxes: &mut HashMap<MailboxId, Mailbox>, mailbox_id: MailboxId) {
+        mailboxes.remove(&mailbox_id);
+        ids.dispose_id(mailbox_id);
+        MAILBOX_DESTROYED.inc();
+        ACTIVE_MAILBOXES.dec();
+        log::trace!("{:?} destroyed", mailbox_id);
+    }
 
-impl Mailbox {
-    /// Check if mailbox is not closed and has available slot for a peer to be attached
-    /// (i.e. has less than 2 peers now)
-    pub fn can_accept_connection(&self) -> bool {
-        if self.is_closing {
-            false
-        } else {
-            self.peers[0].is_free_slot() || self.peers[1].is_free_slot()
+    /// Add the ID to the deny-list: it is never issued to a new mailbox and nobody can connect to it
+    pub fn deny_id(&self, id: u32) {
+        let mut ids = self.ids.write();
+        ids.denied_ids.insert(MailboxId(id));
+        log::info!("{:?} added to the deny-list", MailboxId(id));
+    }
+
+    /// Remove the ID from the deny-list, returns whether it was there
+    pub fn allow_id(&self, id: u32) -> bool {
+        let mut ids = self.ids.write();
+        let removed = ids.denied_ids.remove(&MailboxId(id));
+        if removed {
+            log::info!("{:?} removed from the deny-list", MailboxId(id));
         }
+        removed
     }
 
-    /// Attach peer to this mailbox
-    pub fn attach_peer(&mut self, client_id: ClientId) {
-        if self.peers[0].is_free_slot() {
-            self.peers[0].attach(client_id);
-        } else if self.peers[1].is_free_slot() {
-            self.peers[1].attach(client_id);
-        } else {
-            unreachable!()
+    /// Top up the pool of pre-generated mailbox IDs, so that `create_mailbox` does not need to search for a free ID
+    pub fn refill_id_pool(&self) {
+        let pool_size = self.settings.id_pool_size;
+        if self.ids.read().pooled_ids.len() >= pool_size {
+            return;
         }
+        self.ids.write().refill_pool(pool_size);
     }
 
-    /// Detach peer from this mailbox
-    pub fn detach_peer(&mut self, client_id: ClientId) {
-        let peer = self.find_peer_mut(client_id);
-        peer.detach();
-        self.is_closing = true;
+    /// Returns the deny-listed IDs
+    pub fn denied_ids(&self) -> Vec<u32> {
+        let ids = self.ids.read();
+        ids.denied_ids.iter().map(MailboxId::raw).collect()
     }
 
-    /// Whether this mailbox has at least one peer attached to it
-    pub fn has_connected_peers(&self) -> bool {
-        !self.peers[0].is_free_slot() || !self.peers[1].is_free_slot()
+    /// Returns copies of the messages pending for peers which are not connected yet, for all mailboxes which are not closing.
+    /// Used to persist these messages across restarts.
+    pub fn export_pending_messages(&self) -> Vec<PendingMessages> {
+        let mailboxes = self.mailboxes.lock();
+        mailboxes
+            .iter()
+            .filter(|(_, mailbox)| !mailbox.is_closing)
+            .filter_map(|(id, mailbox)| {
+                // the free slots of a group mailbox hold the same messages, so exporting one of them is enough
+                let waiting_peer = mailbox
+                    .peers
+                    .iter()
+                    .find(|peer| peer.is_free_slot() && !peer.pending_messages.is_empty());
+                waiting_peer.map(|peer| PendingMessages {
+                    id: id.raw(),
+                    namespace: mailbox.namespace.clone(),
+                    token: mailbox.token.clone(),
+                    capacity: mailbox.peers.len(),
+                    creator_ip: mailbox.creator_ip,
+                    age_sec: mailbox.created_at.elapsed().as_secs(),
+                    messages: peer
+                        .pending_messages
+                        .iter()
+                        .filter_map(|pending| StoredMessage::from_message(&pending.msg))
+                        .collect(),
+                })
+            })
+            .collect()
     }
 
-    /// Returns the list of connected peers
-    pub fn connected_peers(&self) -> Vec<ClientId> {
-        self.peers.iter().filter_map(|peer| peer.client_id).collect()
+    /// Summary of every open mailbox, without any message contents
+    pub fn snapshot(&self) -> Vec<MailboxSummary> {
+        let mailboxes = self.mailboxes.lock();
+        mailboxes.iter().map(|(&id, mailbox)| mailbox.summary(id)).collect()
     }
 
-    /// Send message to this mailbox, using the specified client as the sender.
-    /// If the receiver (the other peer in this mailbox) is not connected yet,
-    /// the message is enqueued and the returned value is `None`,
-    /// otherwise (if the received is connected and his ID is known) the same message
-    /// is returned together with the receiver's ID, so that it can be sent to him directly.
-    #[must_use]
-    pub fn send_message(&mut self, src: ClientId, msg: ws::Message) -> Option<(ClientId, ws::Message)> {
-        let target_peer = self.find_other_peer_mut(src);
-        target_peer.enqueue_or_send_message(msg)
+    /// Detailed state of the mailbox (including its recent throughput), `None` if there is no such mailbox
+    pub fn details(&self, mailbox_id: u32) -> Option<MailboxDetails> {
+        let id = MailboxId(mailbox_id);
+        let mailboxes = self.mailboxes.lock();
+        let mailbox = mailboxes.get(&id)?;
+        let age = mailbox.created_at.elapsed();
+        let (messages_per_sec, bytes_per_sec) = mailbox.throughput.rates(age.as_secs());
+        Some(MailboxDetails {
+            summary: mailbox.summary(id),
+            age_seconds: age.as_secs(),
+            idle_seconds: mailbox.last_activity.elapsed().as_secs(),
+            messages_per_sec,
+            bytes_per_sec,
+            bytes_sent: mailbox.peers.iter().map(|peer| peer.bytes_sent).collect(),
+            bytes_received: mailbox.peers.iter().map(|peer| peer.bytes_received).collect(),
+        })
     }
 
-    /// Returns enqueued messages for the specified client (and removes these from the queue)
-    #[must_use]
-    pub fn pending_messages(&mut self, dest: ClientId) -> Vec<ws::Message> {
-        let peer = self.find_peer_mut(dest);
-        peer.take_pending_messages()
+    /// Sizes of the messages pending for each peer of the mailbox (and their contents if `with_contents`),
+    /// `None` if there is no such mailbox. The messages are not affected.
+    pub fn pending_messages_snapshot(&self, mailbox_id: u32, with_contents: bool) -> Option<Vec<PeerPendingMessages>> {
+        let mailboxes = self.mailboxes.lock();
+        let mailbox = mailboxes.get(&MailboxId(mailbox_id))?;
+        let peers = mailbox.peers.iter().map(|peer| PeerPendingMessages {
+            client_id: peer.client_id.map(|client_id| client_id.raw()),
+            count: peer.pending_messages.len(),
+            total_bytes: peer.pending_messages.iter().map(|pending| pending.size).sum(),
+            sizes: peer.pending_messages.iter().map(|pending| pending.size).collect(),
+            messages: with_contents.then(|| {
+                let messages = peer.pending_messages.iter();
+                messages.filter_map(|pending| StoredMessage::from_message(&pending.msg)).collect()
+            }),
+        });
+        Some(peers.collect())
     }
 
-    fn find_peer_mut(&mut self, client_id: ClientId) -> &mut Peer {
-        debug_assert!(self.has_connected_peers());
-        if self.peers[0].client_id == Some(client_id) {
-            &mut self.peers[0]
-        } else if self.peers[1].client_id == Some(client_id) {
-            &mut self.peers[1]
-        } else {
-            unreachable!()
+    /// Restore mailboxes with messages pending for a peer which is not connected yet, as exported by `export_pending_messages`.
+    /// Mailboxes with IDs which are already in use are skipped. A restored mailbox keeps its age, so that it expires
+    /// `restored_mailbox_ttl` after its creation while nobody connects to it, however many restarts it goes through.
+    pub fn import_pending_messages(&self, pending: Vec<PendingMessages>) {
+        let mut ids = self.ids.write();
+        let mut mailboxes = self.mailboxes.lock();
+        for PendingMessages {
+            id,
+            namespace,
+            token,
+            capacity,
+            creator_ip,
+            age_sec,
+            messages,
+        } in pending
+        {
+            let id = MailboxId(id);
+            if ids.id_exists(id) {
+                log::debug!("{:?} already exists - pending messages not restored", id);
+                continue;
+            }
+            ids.reserve_id(id);
+            let mut mailbox = Mailbox::new(false, false, namespace, capacity.max(2), &self.pending_bytes);
+            mailbox.token = token;
+            mailbox.creator_ip = creator_ip;
+            mailbox.restored = true;
+            let created_at = Instant::now().checked_sub(Duration::from_secs(age_sec));
+            mailbox.created_at = created_at.unwrap_or(mailbox.created_at);
+            mailbox.last_activity = mailbox.created_at;
+            for msg in messages {
+                let msg = msg.into_message();
+                for peer in &mut mailbox.peers[1..] {
+                    peer.enqueue_message(msg.clone(), &mailbox.pending_bytes);
+                }
+            }
+            mailboxes.insert(id, mailbox);
+            MAILBOX_CREATED.inc();
+            ACTIVE_MAILBOXES.inc();
+            log::trace!("{:?} restored with pending messages", id);
         }
     }
 
-    fn find_other_peer_mut(&mut self, client_id: ClientId) -> &mut Peer {
-        debug_assert!(self.has_connected_peers());
-        if self.peers[0].client_id == Some(client_id) {
-            &mut self.peers[1]
-        } else if self.peers[1].client_id == Some(client_id) {
-            &mut self.peers[0]
+    /// Average number of peers connected to a mailbox (zero if there are no mailboxes)
+    pub fn average_peers(&self) -> f64 {
+        let mailboxes = self.mailboxes.lock();
+        if mailboxes.is_empty() {
+            return 0.0;
+        }
+        let peers = mailboxes.values().map(|mailbox| mailbox.connected_peers().len()).sum::<usize>();
+        peers as f64 / mailboxes.len() as f64
+    }
+
+    /// Mark as closing the mailboxes (which are not closing yet) created more than `max_age` ago,
+    /// the mailboxes nobody is connected to (e.g. restored ones, or holding only the slots of reconnecting clients)
+    /// are destroyed right away.
+    /// Returns these mailboxes together with the list of clients connected to each of them (they must be closed externally).
+    pub fn close_mailboxes_older_than(&self, max_age: Duration) -> Vec<(MailboxId, Vec<ClientId>)> {
+        let mut ids = self.ids.write();
+        let mut mailboxes = self.mailboxes.lock();
+        let mut closed = Vec::new();
+        for (&id, mailbox) in mailboxes.iter_mut() {
+            if !mailbox.is_closing && mailbox.created_at.elapsed() > max_age {
+                mailbox.is_closing = true;
+                mailbox.drop_detached_peers();
+                closed.push((id, mailbox.connected_peers()));
+            }
+        }
+        for (id, _) in closed.iter().filter(|(_, peers)| peers.is_empty()) {
+            Self::destroy(&mut ids, &mut mailboxes, *id);
+        }
+        closed
+    }
+
+    /// Mark as closing the mailboxes idle for longer than the timeout for their number of connected peers
+    /// (see `idle_timeout`), the mailboxes nobody is connected to are destroyed right away.
+    /// Returns these mailboxes together with the list of clients connected to each of them (they must be closed externally).
+    pub fn close_expired_mailboxes(&self) -> Vec<(MailboxId, Vec<ClientId>)> {
+        let mut ids = self.ids.write();
+        let mut mailboxes = self.mailboxes.lock();
+        let mut expired = Vec::new();
+        for (&id, mailbox) in mailboxes.iter_mut().filter(|(_, mailbox)| !mailbox.is_closing) {
+            let timeout = self.idle_timeout(mailbox);
+            if matches!(timeout, Some(timeout) if mailbox.last_activity.elapsed() > timeout) {
+                mailbox.is_closing = true;
+                mailbox.drop_detached_peers();
+                expired.push((id, mailbox.connected_peers()));
+            }
+        }
+        for (id, _) in expired.iter().filter(|(_, peers)| peers.is_empty()) {
+            Self::destroy(&mut ids, &mut mailboxes, *id);
+        }
+        expired
+    }
+
+    /// Number of peers connected to the mailbox and the time left until it is closed if it stays idle
+    /// (`None` if no timeout applies to it now, zero if it is already being closed)
+    pub fn status(&self, mailbox_id: MailboxId) -> MailboxStatus {
+        let ids = self.ids.read();
+        debug_assert!(ids.id_exists(mailbox_id));
+        let mailboxes = self.mailboxes.lock();
+        let mailbox = mailboxes.get(&mailbox_id).expect("mailbox");
+        let expires_in = if mailbox.is_closing {
+            Some(Duration::ZERO)
         } else {
-            unreachable!()
+            let timeout = self.idle_timeout(mailbox);
+            timeout.map(|timeout| timeout.saturating_sub(mailbox.last_activity.elapsed()))
+        };
+        MailboxStatus {
+            peers: mailbox.connected_peers().len(),
+            expires_in,
+            is_closing: mailbox.is_closing,
         }
     }
-}
 
-#[derive(Default)]
-struct Peer {
-    client_id: Option<ClientId>,
-    pending_messages: Vec<ws::Message>,
-}
+    /// Max idle time of the mailbox for its number of connected peers (`None` means no timeout):
+    /// `unpaired_timeout` if only one peer is connected, `mailbox_timeout` otherwise,
+    /// `restored_mailbox_ttl` for a restored mailbox nobody is connected to (it has been idle since its creation).
+    /// A namespace timeout replaces the server-wide one, an unpaired mailbox never waits longer than a paired one.
+    fn idle_timeout(&self, mailbox: &Mailbox) -> Option<Duration> {
+        let (mailbox_timeout, unpaired_timeout) = match self.namespace_timeout(mailbox.namespace.as_deref()) {
+            Some(timeout) => (
+                Some(timeout),
+                Some(self.settings.unpaired_timeout.map_or(timeout, |unpaired| unpaired.min(timeout))),
+            ),
+            None => (self.settings.mailbox_timeout, self.settings.unpaired_timeout),
+        };
+        match mailbox.connected_peers().len() {
+            // a restored mailbox does not wait forever for a client to come back after the restart
+            0 if mailbox.restored => Some(self.settings.restored_mailbox_ttl),
+            0 => None, // nobody to disconnect, pending messages are kept for the peer to come
+            1 => unpaired_timeout,
+            _ => mailbox_timeout,
+        }
+    }
 
-impl Peer {
-    /// Whether client id is not attached to this peer yet
-    pub fn is_free_slot(&self) -> bool {
-        self.client_id.is_none()
+    /// Idle timeout of the mailboxes in the namespace, if the namespace has its own
+    fn namespace_timeout(&self, namespace: Option<&str>) -> Option<Duration> {
+        let limits = self.settings.namespace_limits.get(namespace?)?;
+        (limits.mailbox_timeout_sec > 0).then(|| Duration::from_secs(limits.mailbox_timeout_sec))
     }
 
-    /// Attach client id to this peer
-    pub fn attach(&mut self, client_id: ClientId) {
-        debug_assert!(self.client_id.is_none());
-        self.client_id = Some(client_id);
+    /// How long the slot of a client which has lost its connection is held for it (`None` if reconnection is disabled)
+    pub fn reconnect_grace(&self) -> Option<Duration> {
+        self.settings.reconnect_grace
     }
 
-    /// Detach client from this peer
-    pub fn detach(&mut self) {
-        debug_assert!(self.client_id.is_some());
-        self.client_id = None;
+    /// All configured idle timeouts: the server-wide ones, those of the namespaces which have their own
+    /// and the max age of restored mailboxes
+    pub fn configured_timeouts(&self) -> Vec<Duration> {
+        let namespaces = self.settings.namespace_limits.keys();
+        let namespace_timeouts = namespaces.filter_map(|ns| self.namespace_timeout(Some(ns)));
+        let server_timeouts = self.settings.mailbox_timeout.into_iter().chain(self.settings.unpaired_timeout);
+        let restored_ttl = iter::once(self.settings.restored_mailbox_ttl);
+        server_timeouts.chain(namespace_timeouts).chain(restored_ttl).collect()
     }
+}
 
-    /// Enqueue the message if the client is not attached yet,
-    /// otherwise returns the same message together with the client ID
-    /// so that it can be sent directly to him.
-    #[must_use]
-    pub fn enqueue_or_send_message(&mut self, msg: ws::Message) -> Option<(ClientId, ws::Message)> {
-        if let Some(client_id) = self.client_id {
-            debug_assert!(self.pending_messages.is_empty());
-            Some((client_id, msg))
-        } else {
-            self.pending_messages.push(msg);
-            None
+/// Whether the policy allows a peer connecting from `peer_ip` to join a mailbox created from `creator_ip`.
+/// Unknown addresses are only allowed if the policy allows any address.
+fn is_peer_ip_allowed(policy: PeerIpPolicy, creator_ip: Option<IpAddr>, peer_ip: Option<IpAddr>) -> bool {
+    match (policy, creator_ip, peer_ip) {
+        (PeerIpPolicy::Any, _, _) => true,
+        (PeerIpPolicy::SameSubnet, Some(IpAddr::V4(creator_ip)), Some(IpAddr::V4(peer_ip))) => {
+            creator_ip.octets()[..3] == peer_ip.octets()[..3]
+        }
+        (PeerIpPolicy::SameSubnet, Some(IpAddr::V6(creator_ip)), Some(IpAddr::V6(peer_ip))) => {
+            creator_ip.segments()[..4] == peer_ip.segments()[..4]
         }
+        (PeerIpPolicy::DifferentIp, Some(creator_ip), Some(peer_ip)) => creator_ip != peer_ip,
+        _ => false,
     }
+}
 
-    /// Take enqueued messages
-    #[must_use]
-    pub fn take_pending_messages(&mut self) -> Vec<ws::Message> {
-        std::mem::replace(&mut self.pending_messages, Vec::new())
-    }
+/// Length of the connect and reconnect tokens (22 alphanumeric characters hold more than 128 random bits)
+const TOKEN_LENGTH: usize = 22;
+
+/// Unpredictable secret token from the OS random number generator
+fn random_token() -> String {
+    OsRng.sample_iter(&Alphanumeric).take(TOKEN_LENGTH).map(char::from).collect()
+}
+
+/// Private API, manages mailbox IDs, ensures uniqueness
+struct IdManager {
+    used_ids: HashSet<MailboxId>,
+    denied_ids: HashSet<MailboxId>,
+    /// Unique IDs generated in advance, not used by any mailbox yet
+    pooled_ids: VecDeque<MailboxId>,
+    /// Number of IDs to generate from (`ID_SPACE_SIZE`, smaller in tests)
+    space_size: usize,
+}
+
+impl Default for IdManager {
+    fn default() -> Self {
+        IdManager {
+            used_ids: HashSet::new(),
+            denied_ids: HashSet::new(),
+            pooled_ids: VecDeque::new(),
+            space_size: ID_SPACE_SIZE,
+        }
+    }
+}
+
+/// Number of distinct mailbox IDs (IDs are 30-bit)
+const ID_SPACE_SIZE: usize = 1 << 30;
+
+/// Random ids tried before giving up on generating a free id, so that a nearly exhausted id space
+/// fails the request instead of holding the write lock for a long search
+const MAX_ID_ATTEMPTS: usize = 32;
+
+impl IdManager {
+    /// Unpredictable id from the OS random number generator, so that knowing one mailbox id tells nothing about others
+    fn random_id(&self) -> MailboxId {
+        MailboxId(OsRng.gen_range(0..self.space_size as u32))
+    }
+
+    fn is_free(&self, id: &MailboxId) -> bool {
+        !self.used_ids.contains(id) && !self.denied_ids.contains(id) && !self.pooled_ids.contains(id)
+    }
+
+    /// Generate an id which is neither used, nor denied, nor pooled, `None` if none of `MAX_ID_ATTEMPTS` random ids is free.
+    /// Failing is unlikely until most of the id space is taken (even with half of it taken, the odds are 2^-32).
+    fn generate_unique_id(&self) -> Option<MailboxId> {
+        let taken = self.used_ids.len() + self.denied_ids.len() + self.pooled_ids.len();
+        if taken >= self.space_size {
+            return None;
+        }
+        iter::repeat_with(|| self.random_id())
+            .take(MAX_ID_ATTEMPTS)
+            .find(|id| self.is_free(id))
+    }
+
+    /// Create a new mailbox id that is guaranteed to be unique (taken from the pool if possible),
+    /// `None` if no free id is found
+    pub fn create_id(&mut self) -> Option<MailboxId> {
+        // skip the pooled ids that have been deny-listed after they were generated
+        let pooled = std::iter::from_fn(|| self.pooled_ids.pop_front()).find(|id| !self.denied_ids.contains(id));
+        let id = pooled.or_else(|| self.generate_unique_id())?;
+        debug_assert!(!self.used_ids.contains(&id));
+        self.used_ids.insert(id);
+        Some(id)
+    }
+
+    /// Generate unique ids until the pool has `size` of them (or no free id is found)
+    pub fn refill_pool(&mut self, size: usize) {
+        while self.pooled_ids.len() < size {
+            match self.generate_unique_id() {
+                Some(id) => self.pooled_ids.push_back(id),
+                None => break,
+            }
+        }
+    }
+
+    /// Mark the specified id as used (e.g. when restoring a mailbox)
+    pub fn reserve_id(&mut self, id: MailboxId) {
+        debug_assert!(!self.used_ids.contains(&id));
+        self.pooled_ids.retain(|&pooled| pooled != id);
+        self.used_ids.insert(id);
+    }
+
+    /// Remove existing mailbox id
+    pub fn dispose_id(&mut self, id: MailboxId) {
+        debug_assert!(self.used_ids.contains(&id));
+        self.used_ids.remove(&id);
+    }
+
+    /// Checks if specified ID exists
+    pub fn id_exists(&self, id: MailboxId) -> bool {
+        self.used_ids.contains(&id)
+    }
+
+    /// Checks if specified ID is in the deny-list
+    pub fn is_denied(&self, id: MailboxId) -> bool {
+        self.denied_ids.contains(&id)
+    }
+}
+
+/// Private API, manages peers: each mailbox has a slot for each of its peers (2 unless it is a group mailbox)
+struct Mailbox {
+    peers: Vec<Peer>,
+    is_closing: bool,
+    created_at: Instant,
+    /// Last time a message was sent to this mailbox or taken from it
+    last_activity: Instant,
+    /// The first client attached to this mailbox
+    creator: Option<ClientId>,
+    /// IP address the creator has connected from (if known)
+    creator_ip: Option<IpAddr>,
+    /// Restored with its pending messages on startup, its creator is gone
+    restored: bool,
+    /// Whether the joining peer must be approved by the creator before any messages are relayed
+    require_approval: bool,
+    approved: bool,
+    /// Whether all peers must signal they are ready before any messages are relayed
+    require_ready: bool,
+    /// Only clients specifying the same namespace can connect to this mailbox
+    namespace: Option<String>,
+    /// Only clients presenting this secret can connect to this mailbox (if set)
+    token: Option<String>,
+    /// Recent traffic, for the throughput reported by the admin API
+    throughput: Throughput,
+    /// Total size of the pending messages of the manager, which the messages of this mailbox are accounted in
+    pending_bytes: Arc<AtomicUsize>,
+}
+
+impl Mailbox {
+    pub fn new(
+        require_approval: bool,
+        require_ready: bool,
+        namespace: Option<String>,
+        capacity: usize,
+        pending_bytes: &Arc<AtomicUsize>,
+    ) -> Self {
+        Mailbox {
+            peers: iter::repeat_with(Peer::default).take(capacity).collect(),
+            is_closing: false,
+            created_at: Instant::now(),
+            last_activity: Instant::now(),
+            creator: None,
+            creator_ip: None,
+            restored: false,
+            require_approval,
+            approved: false,
+            require_ready,
+            namespace,
+            token: None,
+            throughput: Throughput::default(),
+            pending_bytes: pending_bytes.clone(),
+        }
+    }
+
+    fn summary(&self, id: MailboxId) -> MailboxSummary {
+        MailboxSummary {
+            id: id.raw(),
+            capacity: self.peers.len(),
+            peers: self.connected_peers().len(),
+            pending_counts: self.peers.iter().map(|peer| peer.pending_messages.len()).collect(),
+            is_closing: self.is_closing,
+        }
+    }
+
+    /// Whether messages are held in this mailbox until the creator approves the joining peer,
+    /// or until all peers are ready
+    fn is_relay_blocked(&self) -> bool {
+        (self.require_approval && !self.approved) || (self.require_ready && !self.all_peers_ready())
+    }
+
+    /// Whether at least two peers are attached (or hold their slots to reconnect) and all of them are ready,
+    /// the free slots of a group mailbox are not waited for
+    fn all_peers_ready(&self) -> bool {
+        let attached = self.peers.iter().filter(|peer| !peer.is_free_slot()).collect::<Vec<_>>();
+        attached.len() >= 2 && attached.iter().all(|peer| peer.ready)
+    }
+
+    /// Whether the specified client can approve or reject the joining peer right now
+    fn can_be_approved_by(&self, client_id: ClientId) -> bool {
+        self.is_relay_blocked() && !self.is_closing && self.creator == Some(client_id) && self.connected_peers().len() >= 2
+    }
+
+    /// Check if mailbox is not closing and has available slot for a peer to be attached
+    /// (i.e. has less peers now than its capacity)
+    pub fn check_accepts_connection(&self, id: MailboxId) -> Result<(), MailboxError> {
+        if self.is_closing {
+            Err(MailboxError::Closing(id))
+        } else if self.peers.iter().any(Peer::is_free_slot) {
+            Ok(())
+        } else {
+            Err(MailboxError::Busy(id))
+        }
+    }
+
+    /// Attach peer to this mailbox (a free slot with messages waiting for a peer is preferred)
+    pub fn attach_peer(&mut self, client_id: ClientId, client_ip: Option<IpAddr>, read_only: bool, reserved_until: Option<Instant>) {
+        if self.creator.is_none() {
+            self.creator = Some(client_id);
+            // the joining peers of a restored mailbox are still checked against the address it was created from
+            if !self.restored {
+                self.creator_ip = client_ip;
+            }
+        }
+        let waiting_slot = self
+            .peers
+            .iter()
+            .position(|peer| peer.is_free_slot() && !peer.pending_messages.is_empty());
+        let slot = waiting_slot
+            .or_else(|| self.peers.iter().position(Peer::is_free_slot))
+            .expect("free slot");
+        self.peers[slot].attach(client_id, read_only, reserved_until);
+    }
+
+    /// Free the slots of reservations which have not been confirmed in time
+    fn expire_reservations(&mut self) {
+        let now = Instant::now();
+        for peer in self.peers.iter_mut() {
+            if matches!(peer.reserved_until, Some(expires_at) if expires_at <= now) {
+                log::trace!("reservation of {:?} has expired", peer.client_id);
+                peer.detach();
+            }
+        }
+    }
+
+    /// Detach peer from this mailbox, returns `false` if the client was not attached to it.
+    /// The mailbox is marked as closing unless the policy allows the leaving peer to be replaced.
+    pub fn detach_peer(&mut self, client_id: ClientId, close_policy: ClosePolicy) -> bool {
+        match self.peers.iter_mut().find(|peer| peer.client_id == Some(client_id)) {
+            Some(peer) => {
+                peer.detach();
+                self.peer_left(client_id, close_policy);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Free the slot held for the client which has lost its connection, returns `false` if no slot is held for it.
+    /// The mailbox is marked as closing unless the policy allows the leaving peer to be replaced.
+    fn detach_lost_peer(&mut self, client_id: ClientId, close_policy: ClosePolicy) -> bool {
+        let held_slot = self
+            .peers
+            .iter_mut()
+            .find(|peer| matches!(&peer.detached, Some(detached) if detached.client_id == client_id));
+        match held_slot {
+            Some(peer) => {
+                peer.detach();
+                self.peer_left(client_id, close_policy);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn peer_left(&mut self, client_id: ClientId, close_policy: ClosePolicy) {
+        match close_policy {
+            ClosePolicy::Creator if self.creator != Some(client_id) => self.approved = false, // a new joiner needs approval
+            _ => self.is_closing = true,
+        }
+    }
+
+    /// Free the slots held for clients which have lost their connection, nobody can reconnect to a closing mailbox
+    fn drop_detached_peers(&mut self) {
+        for peer in self.peers.iter_mut().filter(|peer| peer.detached.is_some()) {
+            peer.detach();
+        }
+    }
+
+    /// Whether this mailbox has at least one peer attached to it (or holding its slot to reconnect)
+    pub fn has_connected_peers(&self) -> bool {
+        self.peers.iter().any(|peer| !peer.is_free_slot())
+    }
+
+    /// Returns the list of connected peers
+    pub fn connected_peers(&self) -> Vec<ClientId> {
+        self.peers.iter().filter_map(|peer| peer.client_id).collect()
+    }
+
+    /// The peers other than the given client which are attached for good (not just holding a reservation)
+    pub fn other_active_peers(&self, client_id: ClientId) -> Vec<ClientId> {
+        let active_peers = self.peers.iter().filter(|peer| peer.reserved_until.is_none());
+        active_peers
+            .filter_map(|peer| peer.client_id)
+            .filter(|&peer_id| peer_id != client_id)
+            .collect()
+    }
+
+    /// Send message to this mailbox, using the specified client as the sender.
+    /// The message goes to every other peer: for each receiver which is not connected yet
+    /// it is enqueued (keeping the queue within `max_prepairing_messages` and `max_prepairing_bytes`),
+    /// the connected receivers are returned together with a copy of the message each, so that it can be sent to them directly.
+    /// The sender is only told about dropped messages if nobody has received the message directly.
+    pub fn send_message(&mut self, src: ClientId, msg: ws::Message, settings: &MailboxSettings) -> SendOutcome {
+        if self.is_closing {
+            return SendOutcome::Closing;
+        }
+        self.last_activity = Instant::now();
+        let source_peer = self.find_peer_mut(src);
+        if source_peer.read_only {
+            return SendOutcome::ReadOnly;
+        }
+        source_peer.bytes_sent += msg.as_bytes().len() as u64;
+        self.throughput.record(self.created_at.elapsed().as_secs(), msg.as_bytes().len());
+        let relay_blocked = self.is_relay_blocked();
+        let mut deliveries = Vec::new();
+        let mut dropped_oldest = false;
+        let mut targets = self.peers.iter_mut().filter(|peer| peer.client_id != Some(src)).peekable();
+        let mut msg = Some(msg);
+        while let Some(target_peer) = targets.next() {
+            // only a group mailbox needs copies of the message, the last receiver always takes the original
+            let msg = if targets.peek().is_some() { msg.clone() } else { msg.take() }.expect("message");
+            let outcome = if relay_blocked {
+                target_peer.enqueue_bounded_message(msg, settings, &self.pending_bytes)
+            } else {
+                target_peer.enqueue_or_send_message(msg, settings, &self.pending_bytes)
+            };
+            match outcome {
+                SendOutcome::Deliver(targets) => deliveries.extend(targets),
+                SendOutcome::EnqueuedDroppedOldest => dropped_oldest = true,
+                _ => {}
+            }
+        }
+        if !deliveries.is_empty() {
+            SendOutcome::Deliver(deliveries)
+        } else if dropped_oldest {
+            SendOutcome::EnqueuedDroppedOldest
+        } else {
+            SendOutcome::Enqueued
+        }
+    }
+
+    /// Returns enqueued messages for the specified client (and removes these from the queue).
+    /// Nothing is returned while messages are held until approval or until all peers are ready.
+    #[must_use]
+    pub fn pending_messages(&mut self, dest: ClientId) -> Vec<ws::Message> {
+        self.last_activity = Instant::now();
+        if self.is_relay_blocked() {
+            return Vec::new();
+        }
+        let peer = self.find_peer_mut(dest);
+        peer.take_pending_messages()
+    }
+
+    fn find_peer_mut(&mut self, client_id: ClientId) -> &mut Peer {
+        debug_assert!(self.has_connected_peers());
+        let peer = self.peers.iter_mut().find(|peer| peer.client_id == Some(client_id));
+        peer.expect("attached peer")
+    }
+}
+
+/// A message waiting in the queue of a peer, accounted in the pending bytes of its manager for as long as it exists
+struct PendingMessage {
+    /// Global insertion order of pending messages
+    seq: u64,
+    size: usize,
+    msg: ws::Message,
+    pending_bytes: Arc<AtomicUsize>,
+}
+
+impl PendingMessage {
+    fn new(msg: ws::Message, pending_bytes: &Arc<AtomicUsize>) -> Self {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let size = msg.as_bytes().len();
+        pending_bytes.fetch_add(size, Ordering::Relaxed);
+        PendingMessage {
+            seq: SEQ.fetch_add(1, Ordering::Relaxed),
+            size,
+            msg,
+            pending_bytes: pending_bytes.clone(),
+        }
+    }
+
+    fn into_message(mut self) -> ws::Message {
+        std::mem::replace(&mut self.msg, ws::Message::binary(Vec::new()))
+    }
+}
+
+impl Drop for PendingMessage {
+    fn drop(&mut self) {
+        self.pending_bytes.fetch_sub(self.size, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct Peer {
+    client_id: Option<ClientId>,
+    pending_messages: VecDeque<PendingMessage>,
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// The attached client can only receive messages
+    read_only: bool,
+    /// The slot is only reserved for the client until the given time, messages are enqueued until it confirms
+    reserved_until: Option<Instant>,
+    /// The attached client has signaled it is ready to exchange messages
+    ready: bool,
+    /// The client has lost its connection, the slot is held for it to reconnect (messages are enqueued meanwhile)
+    detached: Option<DetachedPeer>,
+    /// Secret the attached client presents to reconnect to this slot after losing its connection (once issued)
+    reconnect_token: Option<String>,
+}
+
+/// Client which has lost its connection, holding its slot until it reconnects or the grace period ends
+struct DetachedPeer {
+    client_id: ClientId,
+    until: Instant,
+}
+
+impl Peer {
+    /// Whether client id is not attached to this peer yet (nor is the slot held for a reconnecting client)
+    pub fn is_free_slot(&self) -> bool {
+        self.client_id.is_none() && self.detached.is_none()
+    }
+
+    /// Attach client id to this peer
+    pub fn attach(&mut self, client_id: ClientId, read_only: bool, reserved_until: Option<Instant>) {
+        debug_assert!(self.client_id.is_none());
+        self.client_id = Some(client_id);
+        self.read_only = read_only;
+        self.reserved_until = reserved_until;
+    }
+
+    /// Detach client from this peer (or free the slot held for a reconnecting client)
+    pub fn detach(&mut self) {
+        debug_assert!(self.client_id.is_some() || self.detached.is_some());
+        self.client_id = None;
+        self.bytes_sent = 0;
+        self.bytes_received = 0;
+        self.read_only = false;
+        self.reserved_until = None;
+        self.ready = false;
+        self.detached = None;
+        self.reconnect_token = None;
+    }
+
+    /// Hold the slot for the attached client, which has lost its connection, to reconnect until the given time
+    pub fn hold_for_reconnect(&mut self, until: Instant) {
+        let client_id = self.client_id.take().expect("attached peer");
+        self.detached = Some(DetachedPeer { client_id, until });
+    }
+
+    /// Attach the reconnecting client to the held slot, returns the client which has lost its connection.
+    /// The reconnect token is used up, a new one is issued to the new connection.
+    pub fn reattach(&mut self, client_id: ClientId) -> ClientId {
+        let detached = self.detached.take().expect("detached peer");
+        self.client_id = Some(client_id);
+        self.reconnect_token = None;
+        detached.client_id
+    }
+
+    /// Enqueue the message if the client is not attached yet (or has only reserved the slot)
+    /// (see `enqueue_bounded_message`), otherwise returns the same message together with the client ID
+    /// so that it can be sent directly to him.
+    pub fn enqueue_or_send_message(
+        &mut self,
+        msg: ws::Message,
+        settings: &MailboxSettings,
+        pending_bytes: &Arc<AtomicUsize>,
+    ) -> SendOutcome {
+        if let (Some(client_id), None) = (self.client_id, self.reserved_until) {
+            debug_assert!(self.pending_messages.is_empty());
+            self.bytes_received += msg.as_bytes().len() as u64;
+            SendOutcome::Deliver(vec![(client_id, msg)])
+        } else {
+            self.enqueue_bounded_message(msg, settings, pending_bytes)
+        }
+    }
+
+    /// Enqueue the message, dropping the oldest messages to keep the queue within
+    /// `max_prepairing_messages` and `max_prepairing_bytes` (a single message larger than that is still enqueued)
+    pub fn enqueue_bounded_message(
+        &mut self,
+        msg: ws::Message,
+        settings: &MailboxSettings,
+        pending_bytes: &Arc<AtomicUsize>,
+    ) -> SendOutcome {
+        let max_messages = settings.max_prepairing_messages;
+        let max_bytes = settings.max_prepairing_bytes;
+        let size = msg.as_bytes().len();
+        let mut queued_bytes = if max_bytes > 0 {
+            self.pending_messages.iter().map(|pending| pending.size).sum()
+        } else {
+            0
+        };
+        let mut dropped = false;
+        while self.pending_messages.len() >= max_messages || (max_bytes > 0 && queued_bytes + size > max_bytes) {
+            match self.pending_messages.pop_front() {
+                // the queued bytes are not counted without a limit on them
+                Some(oldest) => queued_bytes = queued_bytes.saturating_sub(oldest.size),
+                None => break,
+            }
+            dropped = true;
+        }
+        self.pending_messages.push_back(PendingMessage::new(msg, pending_bytes));
+        if dropped {
+            SendOutcome::EnqueuedDroppedOldest
+        } else {
+            SendOutcome::Enqueued
+        }
+    }
+
+    /// Enqueue the message regardless of whether the client is attached
+    pub fn enqueue_message(&mut self, msg: ws::Message, pending_bytes: &Arc<AtomicUsize>) {
+        self.pending_messages.push_back(PendingMessage::new(msg, pending_bytes));
+    }
+
+    /// Take enqueued messages
+    #[must_use]
+    pub fn take_pending_messages(&mut self) -> Vec<ws::Message> {
+        let messages = std::mem::take(&mut self.pending_messages);
+        self.bytes_received += messages.iter().map(|pending| pending.size as u64).sum::<u64>();
+        messages.into_iter().map(PendingMessage::into_message).collect()
+    }
+}
+
+/// Messages pending for a peer of a mailbox, in a serializable form
+#[derive(Serialize, Deserialize)]
+pub struct PendingMessages {
+    id: u32,
+    #[serde(default)]
+    namespace: Option<String>,
+    #[serde(default)]
+    token: Option<String>,
+    /// Max number of peers of the mailbox (files saved before group mailboxes were supported have none)
+    #[serde(default = "default_capacity")]
+    capacity: usize,
+    /// IP address the mailbox was created from (if known), the joining peers are checked against it
+    #[serde(default)]
+    creator_ip: Option<IpAddr>,
+    /// Age of the mailbox when it was saved (files saved by older versions have none, the mailbox is then as good as new)
+    #[serde(default)]
+    age_sec: u64,
+    messages: Vec<StoredMessage>,
+}
+
+fn default_capacity() -> usize {
+    2
+}
+
+/// State of a mailbox, as reported by the admin API
+#[derive(Serialize)]
+pub struct MailboxSummary {
+    id: u32,
+    /// Max number of peers
+    capacity: usize,
+    /// Number of attached clients
+    peers: usize,
+    /// Number of messages pending for each peer slot
+    pending_counts: Vec<usize>,
+    is_closing: bool,
+}
+
+/// State of a mailbox, as reported to its peers
+pub struct MailboxStatus {
+    /// Number of attached clients
+    pub peers: usize,
+    /// Time left until the mailbox is closed if it stays idle (`None` if no timeout applies to it now)
+    pub expires_in: Option<Duration>,
+    pub is_closing: bool,
+}
+
+/// Length of the sliding window the mailbox throughput is computed over
+const THROUGHPUT_WINDOW_SECS: u64 = 10;
+
+/// Messages sent to a mailbox over the last `THROUGHPUT_WINDOW_SECS` seconds, in a ring of one-second buckets
+#[derive(Default)]
+struct Throughput {
+    buckets: [ThroughputBucket; THROUGHPUT_WINDOW_SECS as usize],
+}
+
+#[derive(Default, Clone, Copy)]
+struct ThroughputBucket {
+    /// Second of the mailbox lifetime this bucket counts (it is reused once that second is out of the window)
+    second: u64,
+    messages: u64,
+    bytes: u64,
+}
+
+impl Throughput {
+    fn record(&mut self, second: u64, bytes: usize) {
+        let bucket = &mut self.buckets[(second % THROUGHPUT_WINDOW_SECS) as usize];
+        if bucket.second != second {
+            *bucket = ThroughputBucket {
+                second,
+                ..Default::default()
+            };
+        }
+        bucket.messages += 1;
+        bucket.bytes += bytes as u64;
+    }
+
+    /// Average messages and bytes per second over the window ending at the given second of the mailbox lifetime
+    /// (over the whole lifetime of a mailbox younger than the window)
+    fn rates(&self, second: u64) -> (f64, f64) {
+        let recent = self.buckets.iter().filter(|bucket| bucket.second + THROUGHPUT_WINDOW_SECS > second);
+        let (messages, bytes) = recent.fold((0, 0), |(messages, bytes), bucket| {
+            (messages + bucket.messages, bytes + bucket.bytes)
+        });
+        let window = (second + 1).min(THROUGHPUT_WINDOW_SECS) as f64;
+        (messages as f64 / window, bytes as f64 / window)
+    }
+}
+
+/// Detailed state of a mailbox, as reported by the admin API
+#[derive(Serialize)]
+pub struct MailboxDetails {
+    #[serde(flatten)]
+    summary: MailboxSummary,
+    age_seconds: u64,
+    /// Time since the last message was sent to the mailbox or taken from it
+    idle_seconds: u64,
+    /// Messages sent to the mailbox per second, over the last `THROUGHPUT_WINDOW_SECS` seconds
+    messages_per_sec: f64,
+    /// Bytes sent to the mailbox per second, over the last `THROUGHPUT_WINDOW_SECS` seconds
+    bytes_per_sec: f64,
+    /// Bytes sent and received by the client attached to each slot
+    bytes_sent: Vec<u64>,
+    bytes_received: Vec<u64>,
+}
+
+/// Messages pending for a peer of a mailbox, as reported by the admin API
+#[derive(Serialize)]
+pub struct PeerPendingMessages {
+    /// ID of the attached client (`None` if the slot is free)
+    client_id: Option<u64>,
+    count: usize,
+    total_bytes: usize,
+    /// Size of each message, oldest first
+    sizes: Vec<usize>,
+    /// Contents of each message, oldest first (only if requested)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    messages: Option<Vec<StoredMessage>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum StoredMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl StoredMessage {
+    /// Only text and binary messages are stored, control frames are never enqueued anyway
+    fn from_message(msg: &ws::Message) -> Option<Self> {
+        if let Ok(text) = msg.to_str() {
+            Some(StoredMessage::Text(text.to_owned()))
+        } else if msg.is_binary() {
+            Some(StoredMessage::Binary(msg.as_bytes().to_vec()))
+        } else {
+            None
+        }
+    }
+
+    fn into_message(self) -> ws::Message {
+        match self {
+            StoredMessage::Text(text) => ws::Message::text(text),
+            StoredMessage::Binary(bytes) => ws::Message::binary(bytes),
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum MailboxError {
     #[error("not found: {0:?}")]
     NotFound(MailboxId),
-    #[error("busy: {0:?} has already two peers connected")]
+    #[error("busy: {0:?} has no free slots for another peer")]
     Busy(MailboxId),
+    #[error("closing: {0:?} is being closed")]
+    Closing(MailboxId),
+    #[error("reservation expired: the slot reserved in {0:?} is no longer held")]
+    ReservationExpired(MailboxId),
+    #[error("reconnect rejected: {0:?} holds no slot for this reconnect token")]
+    ReconnectRejected(MailboxId),
+    #[error("too many open mailboxes")]
+    CapacityExceeded,
+    #[error("too many open mailboxes in namespace {0:?}")]
+    NamespaceLimit(String),
+    #[error("no free mailbox id: nearly all of them are in use")]
+    IdSpaceExhausted,
+    #[error("invalid capacity: a mailbox can have from 2 to {0} peers")]
+    InvalidCapacity(usize),
+    #[error("not allowed to join {0:?} from this IP address")]
+    PeerIpRejected(MailboxId),
+    #[error("unauthorized: a valid connect token is required to join {0:?}")]
+    Unauthorized(MailboxId),
+    #[error("not allowed: only the creator of {0:?} can approve or reject its joining peer")]
+    NotAllowed(MailboxId),
+    #[error("not expected: {0:?} does not require the ready signal or this client has already sent it")]
+    ReadyNotExpected(MailboxId),
+}
+
+impl MailboxError {
+    /// Whether the client must be disconnected after the error: retrying from the same connection cannot succeed
+    /// (its IP address stays the same) or must not be allowed (guessing connect tokens).
+    /// Other errors are transient or caused by the request, so the client can send another one.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            MailboxError::PeerIpRejected(_) | MailboxError::Unauthorized(_) => true,
+            MailboxError::NotFound(_)
+            | MailboxError::Busy(_)
+            | MailboxError::Closing(_)
+            | MailboxError::ReservationExpired(_)
+            | MailboxError::ReconnectRejected(_)
+            | MailboxError::CapacityExceeded
+            | MailboxError::NamespaceLimit(_)
+            | MailboxError::IdSpaceExhausted
+            | MailboxError::InvalidCapacity(_)
+            | MailboxError::NotAllowed(_)
+            | MailboxError::ReadyNotExpected(_) => false,
+        }
+    }
+
+    /// ID of the mailbox concerned by the error (if any)
+    pub fn mailbox_id(&self) -> Option<MailboxId> {
+        match *self {
+            MailboxError::NotFound(id)
+            | MailboxError::Busy(id)
+            | MailboxError::Closing(id)
+            | MailboxError::ReservationExpired(id)
+            | MailboxError::ReconnectRejected(id)
+            | MailboxError::PeerIpRejected(id)
+            | MailboxError::Unauthorized(id)
+            | MailboxError::NotAllowed(id)
+            | MailboxError::ReadyNotExpected(id) => Some(id),
+            MailboxError::CapacityExceeded
+            | MailboxError::NamespaceLimit(_)
+            | MailboxError::IdSpaceExhausted
+            | MailboxError::InvalidCapacity(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn settings() -> MailboxSettings {
+        MailboxSettings {
+            max_prepairing_messages: 1000,
+            max_prepairing_bytes: 0,
+            close_policy: ClosePolicy::AnyPeer,
+            id_pool_size: 0,
+            reservation_timeout: Duration::from_secs(10),
+            max_pending_bytes: 0,
+            max_open_mailboxes: 0,
+            max_capacity: 2,
+            peer_ip_policy: PeerIpPolicy::Any,
+            require_connect_token: false,
+            mailbox_timeout: None,
+            unpaired_timeout: None,
+            reconnect_grace: Some(Duration::from_secs(10)),
+            restored_mailbox_ttl: Duration::from_secs(3600),
+            namespace_limits: HashMap::new(),
+        }
+    }
+
+    fn client(id: u64) -> ClientId {
+        ClientId::from_raw(id)
+    }
+
+    fn texts(messages: Vec<ws::Message>) -> Vec<String> {
+        messages.iter().map(|msg| msg.to_str().expect("text").to_owned()).collect()
+    }
+
+    fn delivered_texts(outcome: SendOutcome) -> Vec<String> {
+        match outcome {
+            SendOutcome::Deliver(targets) => texts(targets.into_iter().map(|(_, msg)| msg).collect()),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Creator attached to a new mailbox
+    fn new_mailbox(manager: &MailboxManager) -> MailboxId {
+        let (id, _) = manager.create_mailbox(false, false, None, None).unwrap();
+        manager.attach_client(id, client(1), None, false).unwrap();
+        id
+    }
+
+    #[test]
+    fn attach_takes_pending_messages() {
+        let manager = MailboxManager::new(settings());
+        let id = new_mailbox(&manager);
+        assert!(matches!(
+            manager.send_to_mailbox(id, client(1), ws::Message::text("a")),
+            SendOutcome::Enqueued
+        ));
+        let (peers, pending) = manager.attach_client(id, client(2), None, false).unwrap();
+        assert_eq!(peers, vec![client(1)]);
+        assert_eq!(texts(pending), vec!["a"]);
+        let outcome = manager.send_to_mailbox(id, client(1), ws::Message::text("b"));
+        assert_eq!(delivered_texts(outcome), vec!["b"]);
+    }
+
+    #[test]
+    fn reattach_takes_messages_enqueued_meanwhile() {
+        let manager = MailboxManager::new(settings());
+        let id = new_mailbox(&manager);
+        let _ = manager.attach_client(id, client(2), None, false).unwrap();
+        let token = manager.reconnect_token(id, client(2)).unwrap();
+        assert!(matches!(manager.close_mailbox(id, client(2), true), Departure::Reconnecting(_)));
+        assert!(matches!(
+            manager.send_to_mailbox(id, client(1), ws::Message::text("a")),
+            SendOutcome::Enqueued
+        ));
+        let (mailbox_id, peers, pending) = manager.reattach_client(id.raw(), None, client(3), &token).unwrap();
+        assert_eq!(mailbox_id, id);
+        assert_eq!(peers, vec![client(1)]);
+        assert_eq!(texts(pending), vec!["a"]);
+        let outcome = manager.send_to_mailbox(id, client(1), ws::Message::text("b"));
+        assert_eq!(delivered_texts(outcome), vec!["b"]);
+    }
+
+    /// Messages relayed while the peer is attaching are received exactly once and in order,
+    /// whichever way the relay and the attachment interleave
+    fn check_relay_interleaved_with_attach(reconnect: bool) {
+        const COUNT: usize = 500;
+        let manager = MailboxManager::new(settings());
+        let id = new_mailbox(&manager);
+        let reconnect_token = if reconnect {
+            let _ = manager.attach_client(id, client(2), None, false).unwrap();
+            let token = manager.reconnect_token(id, client(2)).unwrap();
+            assert!(matches!(manager.close_mailbox(id, client(2), true), Departure::Reconnecting(_)));
+            Some(token)
+        } else {
+            None
+        };
+        let sender = {
+            let manager = manager.clone();
+            thread::spawn(move || {
+                let mut delivered = Vec::new();
+                for i in 0..COUNT {
+                    let outcome = manager.send_to_mailbox(id, client(1), ws::Message::text(i.to_string()));
+                    delivered.extend(delivered_texts(outcome));
+                }
+                delivered
+            })
+        };
+        let pending = match reconnect_token {
+            Some(token) => manager.reattach_client(id.raw(), None, client(3), &token).unwrap().2,
+            None => manager.attach_client(id, client(3), None, false).unwrap().1,
+        };
+        let mut received = texts(pending);
+        received.extend(sender.join().unwrap());
+        let expected = (0..COUNT).map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn relay_interleaved_with_attach() {
+        check_relay_interleaved_with_attach(false);
+    }
+
+    #[test]
+    fn relay_interleaved_with_reattach() {
+        check_relay_interleaved_with_attach(true);
+    }
+
+    #[test]
+    fn confirm_takes_messages_enqueued_while_reserved() {
+        let manager = MailboxManager::new(settings());
+        let id = new_mailbox(&manager);
+        manager.reserve_slot(id, client(2), None).unwrap();
+        // nothing is delivered to a reserved slot until it is confirmed
+        assert!(matches!(
+            manager.send_to_mailbox(id, client(1), ws::Message::text("a")),
+            SendOutcome::Enqueued
+        ));
+        let (peers, pending) = manager.confirm_reservation(id, client(2), false).unwrap();
+        assert_eq!(peers, vec![client(1)]);
+        assert_eq!(texts(pending), vec!["a"]);
+        let outcome = manager.send_to_mailbox(id, client(1), ws::Message::text("b"));
+        assert_eq!(delivered_texts(outcome), vec!["b"]);
+    }
+
+    #[test]
+    fn reserved_slot_expires() {
+        let manager = MailboxManager::new(MailboxSettings {
+            reservation_timeout: Duration::from_millis(10),
+            ..settings()
+        });
+        let id = new_mailbox(&manager);
+        manager.reserve_slot(id, client(2), None).unwrap();
+        assert!(matches!(manager.find_mailbox(id.raw(), None, None), Err(MailboxError::Busy(_))));
+        thread::sleep(Duration::from_millis(20));
+        assert!(matches!(
+            manager.confirm_reservation(id, client(2), false),
+            Err(MailboxError::ReservationExpired(_))
+        ));
+        // the expired slot is free for another client
+        assert_eq!(manager.find_mailbox(id.raw(), None, None).unwrap(), id);
+        let _ = manager.attach_client(id, client(3), None, false).unwrap();
+    }
+
+    #[test]
+    fn relay_interleaved_with_confirm() {
+        const COUNT: usize = 500;
+        let manager = MailboxManager::new(settings());
+        let id = new_mailbox(&manager);
+        manager.reserve_slot(id, client(2), None).unwrap();
+        let sender = {
+            let manager = manager.clone();
+            thread::spawn(move || {
+                let mut delivered = Vec::new();
+                for i in 0..COUNT {
+                    let outcome = manager.send_to_mailbox(id, client(1), ws::Message::text(i.to_string()));
+                    delivered.extend(delivered_texts(outcome));
+                }
+                delivered
+            })
+        };
+        let mut received = texts(manager.confirm_reservation(id, client(2), false).unwrap().1);
+        received.extend(sender.join().unwrap());
+        let expected = (0..COUNT).map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn ready_group_mailbox_waits_for_attached_peers_only() {
+        let manager = MailboxManager::new(MailboxSettings {
+            max_capacity: 4,
+            ..settings()
+        });
+        let (id, _) = manager.create_mailbox(false, true, None, Some(4)).unwrap();
+        let _ = manager.attach_client(id, client(1), None, false).unwrap();
+        // a client alone in the mailbox has nobody to wait for, nor anybody to relay to
+        assert!(manager.set_ready(id, client(1)).unwrap().is_empty());
+        let _ = manager.attach_client(id, client(2), None, false).unwrap();
+        assert!(matches!(
+            manager.send_to_mailbox(id, client(1), ws::Message::text("a")),
+            SendOutcome::Enqueued
+        ));
+        let mut held = manager.set_ready(id, client(2)).unwrap();
+        held.sort_by_key(|(client_id, _)| client_id.raw());
+        let held = held
+            .into_iter()
+            .map(|(client_id, messages)| (client_id, texts(messages)))
+            .collect::<Vec<_>>();
+        assert_eq!(held, vec![(client(1), vec![]), (client(2), vec!["a".to_owned()])]);
+        // the two free slots do not hold the messages back
+        let outcome = manager.send_to_mailbox(id, client(1), ws::Message::text("b"));
+        assert_eq!(delivered_texts(outcome), vec!["b"]);
+        // a client joining later holds them again until it is ready too
+        let _ = manager.attach_client(id, client(3), None, false).unwrap();
+        assert!(matches!(
+            manager.send_to_mailbox(id, client(1), ws::Message::text("c")),
+            SendOutcome::Enqueued
+        ));
+        assert_eq!(manager.set_ready(id, client(3)).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn closing_mailbox_is_not_reported_busy() {
+        let manager = MailboxManager::new(settings());
+        let id = new_mailbox(&manager);
+        let _ = manager.attach_client(id, client(2), None, false).unwrap();
+        assert!(matches!(manager.find_mailbox(id.raw(), None, None), Err(MailboxError::Busy(_))));
+        // the mailbox is closing until the remaining peer is disconnected
+        assert!(matches!(manager.close_mailbox(id, client(2), false), Departure::Closing(_)));
+        assert!(matches!(manager.find_mailbox(id.raw(), None, None), Err(MailboxError::Closing(_))));
+        assert!(matches!(
+            manager.attach_client(id, client(3), None, false),
+            Err(MailboxError::Closing(_))
+        ));
+    }
+
+    #[test]
+    fn creator_close_policy() {
+        let manager = MailboxManager::new(MailboxSettings {
+            close_policy: ClosePolicy::Creator,
+            ..settings()
+        });
+        let id = new_mailbox(&manager);
+        let _ = manager.attach_client(id, client(2), None, false).unwrap();
+        // the joiner leaving frees its slot for a new joiner
+        assert!(matches!(
+            manager.close_mailbox(id, client(2), false),
+            Departure::PeerLeft(peers) if peers == vec![client(1)]
+        ));
+        let (peers, _) = manager.attach_client(id, client(3), None, false).unwrap();
+        assert_eq!(peers, vec![client(1)]);
+
+        // the creator leaving closes the mailbox for the joiner
+        match manager.close_mailbox(id, client(1), false) {
+            Departure::Closing(peers) => assert_eq!(peers.into_iter().map(|(peer, _)| peer).collect::<Vec<_>>(), vec![client(3)]),
+            _ => panic!("not closing"),
+        }
+        assert!(matches!(manager.close_mailbox(id, client(3), false), Departure::Nothing));
+        assert!(matches!(manager.find_mailbox(id.raw(), None, None), Err(MailboxError::NotFound(_))));
+    }
+
+    #[test]
+    fn average_peers_per_mailbox() {
+        let manager = MailboxManager::new(settings());
+        assert_eq!(manager.average_peers(), 0.0);
+        // one paired mailbox, one waiting for the joiner and one nobody is connected to
+        let paired_id = new_mailbox(&manager);
+        manager.attach_client(paired_id, client(2), None, false).unwrap();
+        let waiting_id = new_mailbox(&manager);
+        manager.create_mailbox(false, false, None, None).unwrap();
+        assert_eq!(manager.average_peers(), 1.0);
+        let _ = manager.close_mailbox(waiting_id, client(1), false);
+        assert_eq!(manager.average_peers(), 1.0);
+        let _ = manager.close_mailbox(paired_id, client(1), false);
+        assert_eq!(manager.average_peers(), 0.5);
+    }
+
+    #[test]
+    fn mailboxes_older_than_are_closed() {
+        let manager = MailboxManager::new(settings());
+        let young_id = new_mailbox(&manager);
+        let old_id = new_mailbox(&manager);
+        let (empty_id, _) = manager.create_mailbox(false, false, None, None).unwrap();
+        for id in [old_id, empty_id] {
+            let mut mailboxes = manager.mailboxes.lock();
+            let mailbox = mailboxes.get_mut(&id).unwrap();
+            mailbox.created_at = Instant::now() - Duration::from_secs(120);
+        }
+
+        let mut closed = manager.close_mailboxes_older_than(Duration::from_secs(60));
+        closed.sort_unstable_by_key(|(id, _)| id.raw());
+        let mut expected = vec![(old_id, vec![client(1)]), (empty_id, vec![])];
+        expected.sort_unstable_by_key(|(id, _)| id.raw());
+        assert_eq!(closed, expected);
+        // the mailbox without clients is destroyed right away, the other one is closed by its clients
+        assert!(matches!(
+            manager.find_mailbox(empty_id.raw(), None, None),
+            Err(MailboxError::NotFound(_))
+        ));
+        assert_eq!(manager.find_mailbox(young_id.raw(), None, None).unwrap(), young_id);
+        // closing mailboxes are not reported twice
+        assert!(manager.close_mailboxes_older_than(Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn unpaired_mailboxes_expire_sooner() {
+        let manager = MailboxManager::new(MailboxSettings {
+            mailbox_timeout: Some(Duration::from_secs(60)),
+            unpaired_timeout: Some(Duration::from_secs(10)),
+            ..settings()
+        });
+        let unpaired_id = new_mailbox(&manager);
+        let paired_id = new_mailbox(&manager);
+        let _ = manager.attach_client(paired_id, client(2), None, false).unwrap();
+        let set_idle = |idle_sec| {
+            let mut mailboxes = manager.mailboxes.lock();
+            for mailbox in mailboxes.values_mut() {
+                mailbox.last_activity = Instant::now() - Duration::from_secs(idle_sec);
+            }
+        };
+
+        set_idle(5);
+        assert!(manager.close_expired_mailboxes().is_empty());
+        set_idle(30);
+        assert_eq!(manager.close_expired_mailboxes(), vec![(unpaired_id, vec![client(1)])]);
+        set_idle(120);
+        assert_eq!(manager.close_expired_mailboxes(), vec![(paired_id, vec![client(1), client(2)])]);
+    }
+
+    /// Mailbox with a message waiting for the joining peer, saved and restored into a new manager
+    fn restored_mailbox(settings: MailboxSettings, creator_ip: Option<IpAddr>, age_sec: u64) -> (MailboxManager, MailboxId) {
+        let manager = MailboxManager::new(settings.clone());
+        let (id, _) = manager.create_mailbox(false, false, None, None).unwrap();
+        let _ = manager.attach_client(id, client(1), creator_ip, false).unwrap();
+        assert!(matches!(
+            manager.send_to_mailbox(id, client(1), ws::Message::text("a")),
+            SendOutcome::Enqueued
+        ));
+        let mut pending = manager.export_pending_messages();
+        pending[0].age_sec = age_sec;
+        let restored = MailboxManager::new(settings);
+        restored.import_pending_messages(pending);
+        (restored, id)
+    }
+
+    #[test]
+    fn restored_mailbox_delivers_its_messages() {
+        let (manager, id) = restored_mailbox(settings(), None, 0);
+        assert_eq!(manager.find_mailbox(id.raw(), None, None).unwrap(), id);
+        let (peers, pending) = manager.attach_client(id, client(2), None, false).unwrap();
+        assert!(peers.is_empty());
+        assert_eq!(texts(pending), vec!["a"]);
+    }
+
+    #[test]
+    fn restored_mailbox_checks_deny_list() {
+        let (manager, id) = restored_mailbox(settings(), None, 0);
+        manager.deny_id(id.raw());
+        assert!(matches!(
+            manager.attach_client(id, client(2), None, false),
+            Err(MailboxError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn peer_ip_policies() {
+        let creator_ip: IpAddr = "10.0.0.1".parse().unwrap();
+        for (policy, peer_ip, allowed) in [
+            (PeerIpPolicy::SameSubnet, "10.0.0.1", true),
+            (PeerIpPolicy::SameSubnet, "10.0.0.2", true),
+            (PeerIpPolicy::SameSubnet, "10.0.1.1", false),
+            (PeerIpPolicy::DifferentIp, "10.0.0.1", false),
+            (PeerIpPolicy::DifferentIp, "10.0.0.2", true),
+            (PeerIpPolicy::Any, "10.0.0.1", true),
+            (PeerIpPolicy::Any, "192.168.0.1", true),
+        ] {
+            let manager = MailboxManager::new(MailboxSettings {
+                peer_ip_policy: policy,
+                ..settings()
+            });
+            let (id, _) = manager.create_mailbox(false, false, None, None).unwrap();
+            let _ = manager.attach_client(id, client(1), Some(creator_ip), false).unwrap();
+            let attached = manager.attach_client(id, client(2), Some(peer_ip.parse().unwrap()), false);
+            match attached {
+                Ok(_) => assert!(allowed, "{:?} has allowed {}", policy, peer_ip),
+                Err(MailboxError::PeerIpRejected(rejected_id)) => {
+                    assert!(!allowed, "{:?} has rejected {}", policy, peer_ip);
+                    assert_eq!(rejected_id, id);
+                }
+                Err(err) => panic!("unexpected error {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn restored_mailbox_checks_peer_ip() {
+        let settings = MailboxSettings {
+            peer_ip_policy: PeerIpPolicy::SameSubnet,
+            ..settings()
+        };
+        let (manager, id) = restored_mailbox(settings, Some("10.0.0.1".parse().unwrap()), 0);
+        assert!(matches!(
+            manager.attach_client(id, client(2), Some("10.0.1.1".parse().unwrap()), false),
+            Err(MailboxError::PeerIpRejected(_))
+        ));
+        let _ = manager
+            .attach_client(id, client(2), Some("10.0.0.2".parse().unwrap()), false)
+            .unwrap();
+    }
+
+    #[test]
+    fn restored_mailbox_expires() {
+        let settings = MailboxSettings {
+            restored_mailbox_ttl: Duration::from_secs(60),
+            ..settings()
+        };
+        let (manager, young_id) = restored_mailbox(settings.clone(), None, 30);
+        let (old_manager, old_id) = restored_mailbox(settings, None, 90);
+        assert!(manager.close_expired_mailboxes().is_empty());
+        assert_eq!(old_manager.close_expired_mailboxes(), vec![(old_id, vec![])]);
+        // nobody is left to close the expired mailbox, so it is destroyed right away and not saved again
+        assert!(matches!(
+            old_manager.find_mailbox(old_id.raw(), None, None),
+            Err(MailboxError::NotFound(_))
+        ));
+        assert!(old_manager.export_pending_messages().is_empty());
+        assert_eq!(manager.export_pending_messages().len(), 1);
+        assert_eq!(manager.find_mailbox(young_id.raw(), None, None).unwrap(), young_id);
+    }
+
+    /// Both peers of many mailboxes close them at the same time, from two threads
+    fn check_racing_closes(connection_lost: bool) {
+        let manager = MailboxManager::new(MailboxSettings {
+            reconnect_grace: None,
+            ..settings()
+        });
+        let ids = (0..200)
+            .map(|_| {
+                let id = new_mailbox(&manager);
+                manager.attach_client(id, client(2), None, false).unwrap();
+                id
+            })
+            .collect::<Vec<_>>();
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let threads = [client(1), client(2)].map(|client_id| {
+            let (manager, ids, barrier) = (manager.clone(), ids.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                ids.into_iter()
+                    .map(|id| manager.close_mailbox(id, client_id, connection_lost))
+                    .collect::<Vec<_>>()
+            })
+        });
+        let [first, second] = threads.map(|thread| thread.join().expect("no panic"));
+        for (first, second) in first.iter().zip(&second) {
+            // whoever comes first disconnects the other peer, the last one destroys the mailbox
+            let closing = [first, second]
+                .iter()
+                .filter(|departure| matches!(departure, Departure::Closing(_)))
+                .count();
+            let nothing = [first, second]
+                .iter()
+                .filter(|departure| matches!(departure, Departure::Nothing))
+                .count();
+            assert_eq!((closing, nothing), (1, 1));
+        }
+        assert!(manager.snapshot().is_empty());
+        assert!(manager.ids.read().used_ids.is_empty());
+        // closing again does nothing
+        assert!(matches!(
+            manager.close_mailbox(ids[0], client(1), connection_lost),
+            Departure::Nothing
+        ));
+    }
+
+    #[test]
+    fn racing_closes_destroy_mailbox_once() {
+        check_racing_closes(false);
+    }
+
+    #[test]
+    fn racing_lost_connections_destroy_mailbox_once() {
+        check_racing_closes(true);
+    }
+
+    #[test]
+    fn concurrent_creates_get_unique_ids() {
+        let manager = MailboxManager::new(settings());
+        let threads = (0..8)
+            .map(|_| {
+                let manager = manager.clone();
+                thread::spawn(move || {
+                    (0..500)
+                        .map(|_| manager.create_mailbox(false, false, None, None).unwrap().0)
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let ids = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().expect("no panic"))
+            .collect::<HashSet<_>>();
+        assert_eq!(ids.len(), 8 * 500);
+        assert_eq!(manager.snapshot().len(), 8 * 500);
+        assert_eq!(manager.ids.read().used_ids, ids);
+    }
+
+    #[test]
+    fn id_pool_is_refilled_with_free_ids() {
+        let manager = MailboxManager::new(MailboxSettings {
+            id_pool_size: 10,
+            ..settings()
+        });
+        manager.refill_id_pool();
+        let pooled = manager.ids.read().pooled_ids.iter().copied().collect::<HashSet<_>>();
+        assert_eq!(pooled.len(), 10);
+
+        // the mailboxes take the pooled ids first, then fall back to generating them once the pool is depleted
+        let created = (0..15)
+            .map(|_| manager.create_mailbox(false, false, None, None).unwrap().0)
+            .collect::<HashSet<_>>();
+        assert_eq!(created.len(), 15);
+        assert!(pooled.is_subset(&created));
+        assert!(manager.ids.read().pooled_ids.is_empty());
+
+        manager.refill_id_pool();
+        let ids = manager.ids.read();
+        let refilled = ids.pooled_ids.iter().copied().collect::<HashSet<_>>();
+        assert_eq!(refilled.len(), 10);
+        assert!(refilled.is_disjoint(&created));
+    }
+
+    #[test]
+    fn id_pool_skips_denied_ids() {
+        let manager = MailboxManager::new(MailboxSettings {
+            id_pool_size: 1,
+            ..settings()
+        });
+        manager.refill_id_pool();
+        let pooled = manager.ids.read().pooled_ids[0];
+        manager.deny_id(pooled.raw());
+        let (id, _) = manager.create_mailbox(false, false, None, None).unwrap();
+        assert_ne!(id, pooled);
+    }
+
+    #[test]
+    fn prepairing_queue_drops_oldest_messages() {
+        for (max_messages, max_bytes) in [(2, 0), (1000, 2)] {
+            let manager = MailboxManager::new(MailboxSettings {
+                max_prepairing_messages: max_messages,
+                max_prepairing_bytes: max_bytes,
+                ..settings()
+            });
+            let id = new_mailbox(&manager);
+            for msg in ["a", "b"] {
+                assert!(matches!(
+                    manager.send_to_mailbox(id, client(1), ws::Message::text(msg)),
+                    SendOutcome::Enqueued
+                ));
+            }
+            assert!(matches!(
+                manager.send_to_mailbox(id, client(1), ws::Message::text("c")),
+                SendOutcome::EnqueuedDroppedOldest
+            ));
+            let (_, pending) = manager.attach_client(id, client(2), None, false).unwrap();
+            assert_eq!(texts(pending), vec!["b", "c"], "{} messages, {} bytes", max_messages, max_bytes);
+        }
+    }
+
+    #[test]
+    fn throughput_over_sliding_window() {
+        // 5 messages of 100 bytes a second for 20 seconds
+        let mut throughput = Throughput::default();
+        for second in 0..20 {
+            for _ in 0..5 {
+                throughput.record(second, 100);
+            }
+        }
+        assert_eq!(throughput.rates(19), (5.0, 500.0));
+        // the traffic stops, and the rates go down as the busy seconds leave the window
+        assert_eq!(throughput.rates(24), (2.5, 250.0));
+        assert_eq!(throughput.rates(40), (0.0, 0.0));
+
+        // a mailbox younger than the window is averaged over its lifetime
+        let mut throughput = Throughput::default();
+        for second in 0..2 {
+            for _ in 0..5 {
+                throughput.record(second, 100);
+            }
+        }
+        assert_eq!(throughput.rates(1), (5.0, 500.0));
+
+        let manager = MailboxManager::new(settings());
+        let id = new_mailbox(&manager);
+        for _ in 0..4 {
+            let _ = manager.send_to_mailbox(id, client(1), ws::Message::text("hello"));
+        }
+        let details = serde_json::to_value(manager.details(id.raw()).unwrap()).unwrap();
+        assert!(details["messages_per_sec"].as_f64().unwrap() >= 2.0, "{}", details);
+        assert!(details["bytes_per_sec"].as_f64().unwrap() >= 10.0, "{}", details);
+    }
+
+    #[test]
+    fn held_messages_are_bounded() {
+        for (max_messages, max_bytes) in [(2, 0), (1000, 2)] {
+            let manager = MailboxManager::new(MailboxSettings {
+                max_prepairing_messages: max_messages,
+                max_prepairing_bytes: max_bytes,
+                ..settings()
+            });
+            let (id, _) = manager.create_mailbox(true, false, None, None).unwrap();
+            let _ = manager.attach_client(id, client(1), None, false).unwrap();
+            let _ = manager.attach_client(id, client(2), None, false).unwrap();
+            // both peers are connected, but the messages are held until the creator approves the joining peer
+            for msg in ["a", "b"] {
+                assert!(matches!(
+                    manager.send_to_mailbox(id, client(1), ws::Message::text(msg)),
+                    SendOutcome::Enqueued
+                ));
+            }
+            assert!(matches!(
+                manager.send_to_mailbox(id, client(1), ws::Message::text("c")),
+                SendOutcome::EnqueuedDroppedOldest
+            ));
+            let released = manager.approve(id, client(1)).unwrap();
+            let (_, held) = released.into_iter().find(|(peer, _)| *peer == client(2)).unwrap();
+            assert_eq!(texts(held), vec!["b", "c"], "{} messages, {} bytes", max_messages, max_bytes);
+        }
+    }
+
+    #[test]
+    fn oldest_pending_messages_are_evicted_first() {
+        let manager = MailboxManager::new(MailboxSettings {
+            max_pending_bytes: 1_000_000,
+            ..settings()
+        });
+        let first = new_mailbox(&manager);
+        let (second, _) = manager.create_mailbox(false, false, None, None).unwrap();
+        manager.attach_client(second, client(3), None, false).unwrap();
+        let message = |i: usize| ws::Message::text(format!("{}{}", i, " ".repeat(99000 - i.to_string().len())));
+        // the messages pending in another manager do not count
+        let other = MailboxManager::new(settings());
+        let other_id = new_mailbox(&other);
+        for i in 0..20 {
+            assert!(matches!(
+                other.send_to_mailbox(other_id, client(1), message(i)),
+                SendOutcome::Enqueued
+            ));
+        }
+        for i in 0..11 {
+            let (id, sender) = if i % 2 == 0 { (first, client(1)) } else { (second, client(3)) };
+            assert!(matches!(manager.send_to_mailbox(id, sender, message(i)), SendOutcome::Enqueued));
+            if i < 10 {
+                assert!(manager.evict_pending_over_limit().is_empty());
+            }
+        }
+
+        // 11 messages of 99000 bytes are over the limit, evicting the 2 oldest brings them under 90% of it
+        let senders = manager.evict_pending_over_limit().into_iter().collect::<HashSet<_>>();
+        assert_eq!(senders, HashSet::from([client(1), client(3)]));
+        let pending = |id, joiner| {
+            let (_, pending) = manager.attach_client(id, joiner, None, false).unwrap();
+            texts(pending).iter().map(|text| text.trim_end().to_owned()).collect::<Vec<_>>()
+        };
+        assert_eq!(pending(first, client(2)), ["2", "4", "6", "8", "10"]);
+        assert_eq!(pending(second, client(4)), ["3", "5", "7", "9"]);
+    }
+
+    #[test]
+    fn ids_are_unpredictable() {
+        let manager = MailboxManager::new(settings());
+        let ids = (0..100)
+            .map(|_| manager.create_mailbox(false, false, None, None).unwrap().0.raw())
+            .collect::<Vec<_>>();
+        assert!(ids.iter().all(|&id| id < ID_SPACE_SIZE as u32));
+        // sequential ids would let anyone guess the ids of the mailboxes created around theirs
+        assert!(ids.windows(2).all(|pair| pair[0].abs_diff(pair[1]) > 1));
+    }
+
+    #[test]
+    fn group_mailbox_relays_to_all_peers() {
+        let manager = MailboxManager::new(MailboxSettings {
+            max_capacity: 4,
+            ..settings()
+        });
+        for capacity in [1, 5] {
+            assert!(matches!(
+                manager.create_mailbox(false, false, None, Some(capacity)),
+                Err(MailboxError::InvalidCapacity(4))
+            ));
+        }
+        let (id, _) = manager.create_mailbox(false, false, None, Some(3)).unwrap();
+        let _ = manager.attach_client(id, client(1), None, false).unwrap();
+        let (peers, _) = manager.attach_client(id, client(2), None, false).unwrap();
+        assert_eq!(peers, vec![client(1)]);
+        // delivered to the connected peer, and enqueued for the one which has not connected yet
+        let outcome = manager.send_to_mailbox(id, client(1), ws::Message::text("a"));
+        assert_eq!(delivered_texts(outcome), vec!["a"]);
+        let (mut peers, pending) = manager.attach_client(id, client(3), None, false).unwrap();
+        peers.sort_by_key(|client_id| client_id.raw());
+        assert_eq!(peers, vec![client(1), client(2)]);
+        assert_eq!(texts(pending), vec!["a"]);
+        assert!(matches!(
+            manager.attach_client(id, client(4), None, false),
+            Err(MailboxError::Busy(_))
+        ));
+
+        let outcome = manager.send_to_mailbox(id, client(2), ws::Message::text("b"));
+        let mut targets = match outcome {
+            SendOutcome::Deliver(targets) => targets.into_iter().map(|(client_id, _)| client_id.raw()).collect::<Vec<_>>(),
+            _ => panic!("not delivered"),
+        };
+        targets.sort_unstable();
+        assert_eq!(targets, vec![1, 3]);
+        // any peer leaving closes the mailbox for all the others
+        match manager.close_mailbox(id, client(3), false) {
+            Departure::Closing(peers) => assert_eq!(peers.len(), 2),
+            _ => panic!("not closing"),
+        }
+    }
+
+    #[test]
+    fn id_generation_fails_in_exhausted_space() {
+        const SPACE_SIZE: u32 = 1 << 20;
+        let mut ids = IdManager {
+            space_size: SPACE_SIZE as usize,
+            ..IdManager::default()
+        };
+        // with a single free id left, the bounded random attempts give up (they find it with a 2^-15 chance)
+        (1..SPACE_SIZE).for_each(|id| ids.reserve_id(MailboxId(id)));
+        assert_eq!(ids.create_id(), None);
+        // with none left, no attempts are made at all
+        ids.denied_ids.insert(MailboxId(0));
+        assert_eq!(ids.create_id(), None);
+
+        // the manager reports the exhausted id space, and recovers once ids are disposed
+        let manager = MailboxManager::new(settings());
+        *manager.ids.write() = ids;
+        assert!(matches!(
+            manager.create_mailbox(false, false, None, None),
+            Err(MailboxError::IdSpaceExhausted)
+        ));
+        let mut ids = manager.ids.write();
+        (1..SPACE_SIZE).for_each(|id| ids.dispose_id(MailboxId(id)));
+        drop(ids);
+        assert!(manager.create_mailbox(false, false, None, None).is_ok());
+    }
+
+    #[test]
+    fn denied_ids_are_never_issued() {
+        let manager = MailboxManager::new(MailboxSettings {
+            id_pool_size: 2,
+            ..settings()
+        });
+        manager.ids.write().space_size = 2;
+        // both ids are pooled, then one of them is denied
+        manager.refill_id_pool();
+        manager.deny_id(0);
+        let (id, _) = manager.create_mailbox(false, false, None, None).unwrap();
+        assert_eq!(id.raw(), 1);
+        assert!(matches!(
+            manager.create_mailbox(false, false, None, None),
+            Err(MailboxError::IdSpaceExhausted)
+        ));
+        assert!(manager.allow_id(0));
+        assert!(!manager.allow_id(0));
+        assert_eq!(manager.create_mailbox(false, false, None, None).unwrap().0.raw(), 0);
+    }
+
+    #[test]
+    fn denied_mailbox_refuses_connections() {
+        let manager = MailboxManager::new(settings());
+        let id = new_mailbox(&manager);
+        manager.deny_id(id.raw());
+        assert_eq!(manager.denied_ids(), vec![id.raw()]);
+        assert!(matches!(manager.find_mailbox(id.raw(), None, None), Err(MailboxError::NotFound(_))));
+        assert!(matches!(
+            manager.attach_client(id, client(2), None, false),
+            Err(MailboxError::NotFound(_))
+        ));
+        manager.allow_id(id.raw());
+        assert_eq!(manager.find_mailbox(id.raw(), None, None).unwrap(), id);
+    }
+
+    #[test]
+    fn creation_fails_at_the_open_mailbox_limit() {
+        let manager = MailboxManager::new(MailboxSettings {
+            max_open_mailboxes: 3,
+            ..settings()
+        });
+        let first_id = new_mailbox(&manager);
+        for _ in 1..3 {
+            assert!(manager.create_mailbox(false, false, None, None).is_ok());
+        }
+        assert!(matches!(
+            manager.create_mailbox(false, false, None, None),
+            Err(MailboxError::CapacityExceeded)
+        ));
+        // a destroyed mailbox frees its place
+        let _ = manager.close_mailbox(first_id, client(1), false);
+        assert!(manager.create_mailbox(false, false, None, None).is_ok());
+        assert!(matches!(
+            manager.create_mailbox(false, false, None, None),
+            Err(MailboxError::CapacityExceeded)
+        ));
+    }
+
+    #[test]
+    fn full_namespace_does_not_affect_another() {
+        let limits = NamespaceLimits {
+            max_open_mailboxes: 2,
+            ..NamespaceLimits::default()
+        };
+        let manager = MailboxManager::new(MailboxSettings {
+            namespace_limits: HashMap::from([("a".to_string(), limits), ("b".to_string(), limits)]),
+            ..settings()
+        });
+        let create = |ns: &str| manager.create_mailbox(false, false, Some(ns.to_string()), None);
+        for _ in 0..2 {
+            assert!(create("a").is_ok());
+        }
+        assert!(matches!(create("a"), Err(MailboxError::NamespaceLimit(ns)) if ns == "a"));
+        for _ in 0..2 {
+            assert!(create("b").is_ok());
+        }
+        assert!(matches!(create("b"), Err(MailboxError::NamespaceLimit(ns)) if ns == "b"));
+    }
+
+    #[test]
+    fn open_mailbox_limits_are_reported_separately() {
+        let limits = NamespaceLimits {
+            max_open_mailboxes: 1,
+            ..NamespaceLimits::default()
+        };
+        let manager = MailboxManager::new(MailboxSettings {
+            max_open_mailboxes: 3,
+            namespace_limits: HashMap::from([("app".to_string(), limits)]),
+            ..settings()
+        });
+        assert!(manager.create_mailbox(false, false, Some("app".to_string()), None).is_ok());
+        let err = manager.create_mailbox(false, false, Some("app".to_string()), None).unwrap_err();
+        assert!(matches!(&err, MailboxError::NamespaceLimit(ns) if ns == "app"));
+        assert!(!err.is_fatal());
+        // the namespace limit does not affect other mailboxes, the server-wide limit does
+        assert!(manager.create_mailbox(false, false, None, None).is_ok());
+        assert!(manager.create_mailbox(false, false, Some("other".to_string()), None).is_ok());
+        let err = manager.create_mailbox(false, false, None, None).unwrap_err();
+        assert!(matches!(err, MailboxError::CapacityExceeded));
+        assert!(!err.is_fatal());
+    }
 }
@@ -1,3 +1,7 @@
 pub(super) mod client;
 pub(super) mod connection;
+pub(super) mod ip_limit;
 pub(super) mod mailbox;
+pub(super) mod rate_limit;
+pub(super) mod subprotocol;
+pub(super) mod transform;
@@ -0,0 +1,61 @@
+//! Server-wide rate limiting
+
+use std::time::Instant;
+
+use parking_lot::Mutex;
+
+/// Token bucket allowing up to `rate` events per second on average, with bursts of up to `rate` events
+pub struct RateLimiter {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32) -> Self {
+        let rate = f64::from(rate);
+        RateLimiter {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate,
+                updated_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take a token if available, returns `false` if the rate limit is exceeded
+    pub fn try_acquire(&self) -> bool {
+        let mut bucket = self.bucket.lock();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn bursts_are_limited_and_tokens_refilled() {
+        let limiter = RateLimiter::new(10);
+        assert!((0..10).all(|_| limiter.try_acquire()));
+        assert!(!limiter.try_acquire());
+        // a token is refilled every 100ms
+        thread::sleep(Duration::from_millis(150));
+        assert!(limiter.try_acquire());
+    }
+}
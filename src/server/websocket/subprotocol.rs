@@ -0,0 +1,106 @@
+//! Negotiation of the protocol version with the `Sec-WebSocket-Protocol` header
+
+use crate::server::config::SubprotocolPolicy;
+
+/// Version of the mailbox protocol spoken over a websocket connection.
+/// With `safesync.v1` everything sent after the handshake is relayed as-is,
+/// `safesync.v2` adds the control messages handled by the server after the handshake.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ProtocolVersion {
+    V1,
+    V2,
+}
+
+impl ProtocolVersion {
+    /// Supported versions, oldest first
+    const ALL: [ProtocolVersion; 2] = [ProtocolVersion::V1, ProtocolVersion::V2];
+
+    /// Subprotocol name of this version, as sent in the `Sec-WebSocket-Protocol` header
+    pub fn name(self) -> &'static str {
+        match self {
+            ProtocolVersion::V1 => "safesync.v1",
+            ProtocolVersion::V2 => "safesync.v2",
+        }
+    }
+
+    /// Whether the control messages sent after the handshake are handled by the server instead of being relayed
+    pub fn control_messages(self) -> bool {
+        self == ProtocolVersion::V2
+    }
+
+    /// The first supported version in the comma-separated list of subprotocols offered by the client
+    /// (which lists them in its order of preference), `None` if none of them is supported
+    pub fn negotiate(offered: &str) -> Option<Self> {
+        offered
+            .split(',')
+            .map(str::trim)
+            .find_map(|name| Self::ALL.into_iter().find(|version| version.name() == name))
+    }
+}
+
+/// Outcome of the negotiation of the protocol version of a connection being upgraded
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Negotiation {
+    /// The client has offered this supported version, which is sent back in the header of the response
+    Selected(ProtocolVersion),
+    /// The client has offered none of the supported versions (or no header at all),
+    /// it speaks `safesync.v1` and is not sent the header
+    Fallback,
+    /// The client has offered none of the supported versions and the policy is strict, the upgrade is rejected
+    Rejected,
+}
+
+impl Negotiation {
+    /// Negotiate the version with the value of the `Sec-WebSocket-Protocol` header of the upgrade request (if sent)
+    pub fn new(offered: Option<&str>, policy: SubprotocolPolicy) -> Self {
+        match (offered.and_then(ProtocolVersion::negotiate), policy) {
+            (Some(version), _) => Negotiation::Selected(version),
+            (None, SubprotocolPolicy::Lenient) => Negotiation::Fallback,
+            (None, SubprotocolPolicy::Strict) => Negotiation::Rejected,
+        }
+    }
+
+    /// Version spoken by the connection, `None` if it is rejected
+    pub fn version(self) -> Option<ProtocolVersion> {
+        match self {
+            Negotiation::Selected(version) => Some(version),
+            Negotiation::Fallback => Some(ProtocolVersion::V1),
+            Negotiation::Rejected => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        use Negotiation::*;
+        use ProtocolVersion::*;
+        use SubprotocolPolicy::*;
+        let cases = [
+            // missing header
+            (None, Lenient, Fallback),
+            (None, Strict, Rejected),
+            // unknown versions only
+            (Some("safesync.v3"), Lenient, Fallback),
+            (Some("safesync.v3, chat"), Strict, Rejected),
+            (Some(""), Strict, Rejected),
+            // the first supported version in the order of preference of the client
+            (Some("safesync.v1"), Strict, Selected(V1)),
+            (Some("safesync.v2"), Lenient, Selected(V2)),
+            (Some("safesync.v2, safesync.v1"), Strict, Selected(V2)),
+            (Some("safesync.v1,safesync.v2"), Strict, Selected(V1)),
+            (Some("safesync.v3, safesync.v2 , safesync.v1"), Strict, Selected(V2)),
+            // names are case-sensitive
+            (Some("SafeSync.v2"), Lenient, Fallback),
+        ];
+        for (offered, policy, expected) in cases {
+            assert_eq!(Negotiation::new(offered, policy), expected, "{:?} {:?}", offered, policy);
+        }
+        assert_eq!(Fallback.version(), Some(V1));
+        assert_eq!(Selected(V2).version(), Some(V2));
+        assert_eq!(Rejected.version(), None);
+    }
+}
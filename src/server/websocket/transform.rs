@@ -0,0 +1,48 @@
+//! Relayed messages transformation
+
+use warp::ws;
+
+/// Inspects and optionally transforms every message before it is relayed to the other peer
+pub trait MessageTransformer: Send + Sync {
+    /// Returns the message to relay, or an error description (reported to the sender) if the message must not be relayed
+    fn transform(&self, msg: ws::Message) -> Result<ws::Message, String>;
+}
+
+/// Default transformer, relays all messages as-is
+pub struct PassThrough;
+
+impl MessageTransformer for PassThrough {
+    fn transform(&self, msg: ws::Message) -> Result<ws::Message, String> {
+        Ok(msg)
+    }
+}
+
+/// Transformers exercising the relaying in the tests
+#[cfg(test)]
+pub(in crate::server) mod testing {
+    use super::*;
+
+    /// Relays text messages in upper case, other messages as-is
+    pub struct Uppercase;
+
+    impl MessageTransformer for Uppercase {
+        fn transform(&self, msg: ws::Message) -> Result<ws::Message, String> {
+            match msg.to_str() {
+                Ok(text) => Ok(ws::Message::text(text.to_uppercase())),
+                Err(()) => Ok(msg),
+            }
+        }
+    }
+
+    /// Rejects the text messages containing the given word
+    pub struct RejectContaining(pub &'static str);
+
+    impl MessageTransformer for RejectContaining {
+        fn transform(&self, msg: ws::Message) -> Result<ws::Message, String> {
+            match msg.to_str() {
+                Ok(text) if text.contains(self.0) => Err(format!("the message contains {:?}", self.0)),
+                _ => Ok(msg),
+            }
+        }
+    }
+}